@@ -0,0 +1,5 @@
+-- Add down migration script here
+DROP TABLE webhook_dead_letter;
+DROP TABLE webhook_delivery;
+ALTER TABLE webhook_invocation DROP COLUMN error;
+ALTER TABLE webhook_invocation DROP COLUMN attempt;
@@ -0,0 +1,32 @@
+-- Add up migration script here
+ALTER TABLE webhook_invocation ADD COLUMN attempt INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE webhook_invocation ADD COLUMN error TEXT NULL;
+
+-- deliveries that failed at least once and are waiting to be retried
+CREATE TABLE IF NOT EXISTS webhook_delivery(
+    id INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    webhook_id INTEGER NOT NULL REFERENCES webhook(id) ON DELETE CASCADE,
+    scrape_id INTEGER REFERENCES scrape(id) ON DELETE SET NULL,
+    payload JSONB NOT NULL,
+    -- how many times a delivery has been attempted so far
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_response_code INTEGER NULL,
+    last_error TEXT NULL,
+    next_attempt_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW(),
+    created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON webhook_delivery (next_attempt_at);
+
+-- deliveries that ran out of attempts, these are only retried when replayed manually
+CREATE TABLE IF NOT EXISTS webhook_dead_letter(
+    id INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    webhook_id INTEGER NOT NULL REFERENCES webhook(id) ON DELETE CASCADE,
+    scrape_id INTEGER REFERENCES scrape(id) ON DELETE SET NULL,
+    payload JSONB NOT NULL,
+    attempts INTEGER NOT NULL,
+    last_response_code INTEGER NULL,
+    last_error TEXT NULL,
+    created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+    failed_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW()
+);
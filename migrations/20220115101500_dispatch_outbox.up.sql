@@ -0,0 +1,14 @@
+-- Add up migration script here
+-- payloads are written in the same transaction as the media they belong to and
+-- get drained to webhooks and AMQP separately
+CREATE TABLE IF NOT EXISTS dispatch_outbox(
+    id INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    scrape_id INTEGER NOT NULL REFERENCES scrape(id) ON DELETE CASCADE,
+    provider_name TEXT NOT NULL,
+    provider_destination TEXT NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW(),
+    dispatched_at TIMESTAMP WITHOUT TIME ZONE NULL
+);
+
+CREATE INDEX ON dispatch_outbox (id) WHERE dispatched_at IS NULL;
@@ -0,0 +1,7 @@
+-- Add down migration script here
+ALTER TABLE webhook_dead_letter DROP COLUMN delivery_id;
+ALTER TABLE webhook_delivery DROP COLUMN delivery_id;
+ALTER TABLE webhook_invocation DROP COLUMN delivery_id;
+ALTER TABLE webhook DROP COLUMN previous_secret_expires_at;
+ALTER TABLE webhook DROP COLUMN previous_secret;
+ALTER TABLE webhook DROP COLUMN secret;
@@ -0,0 +1,12 @@
+-- Add up migration script here
+-- webhooks without a secret are delivered unsigned
+ALTER TABLE webhook ADD COLUMN secret TEXT NULL;
+-- the secret that was replaced by the last rotation, deliveries are signed
+-- with both secrets until it expires
+ALTER TABLE webhook ADD COLUMN previous_secret TEXT NULL;
+ALTER TABLE webhook ADD COLUMN previous_secret_expires_at TIMESTAMP WITHOUT TIME ZONE NULL;
+
+-- the same delivery id is sent on every attempt of a delivery
+ALTER TABLE webhook_invocation ADD COLUMN delivery_id TEXT NULL;
+ALTER TABLE webhook_delivery ADD COLUMN delivery_id TEXT NOT NULL DEFAULT '';
+ALTER TABLE webhook_dead_letter ADD COLUMN delivery_id TEXT NOT NULL DEFAULT '';
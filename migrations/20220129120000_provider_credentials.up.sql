@@ -0,0 +1,11 @@
+-- Add up migration script here
+-- credentials are encrypted with the CREDENTIALS_KEY environment variable and
+-- never stored in plaintext
+CREATE TABLE IF NOT EXISTS provider_credential(
+    id INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    provider_name TEXT NOT NULL UNIQUE,
+    nonce BYTEA NOT NULL,
+    ciphertext BYTEA NOT NULL,
+    created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW()
+);
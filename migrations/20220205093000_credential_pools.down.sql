@@ -0,0 +1,7 @@
+-- Add down migration script here
+ALTER TABLE scrape_request DROP COLUMN account;
+
+DELETE FROM provider_credential;
+ALTER TABLE provider_credential DROP CONSTRAINT provider_credential_provider_name_account_key;
+ALTER TABLE provider_credential DROP COLUMN account;
+ALTER TABLE provider_credential ADD UNIQUE (provider_name);
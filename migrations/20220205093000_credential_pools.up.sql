@@ -0,0 +1,9 @@
+-- Add up migration script here
+-- credentials stored before pools existed can't be attributed to an account
+DELETE FROM provider_credential;
+ALTER TABLE provider_credential ADD COLUMN account TEXT NOT NULL;
+ALTER TABLE provider_credential DROP CONSTRAINT provider_credential_provider_name_key;
+ALTER TABLE provider_credential ADD UNIQUE (provider_name, account);
+
+-- the account that served the request for providers that use credentials
+ALTER TABLE scrape_request ADD COLUMN account TEXT NULL;
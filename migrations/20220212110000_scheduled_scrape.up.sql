@@ -0,0 +1,20 @@
+-- Add up migration script here
+-- the day's plan of scrapes, persisted so a restart picks up where the last process left off
+CREATE TABLE IF NOT EXISTS scheduled_scrape(
+    id INTEGER PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    provider_resource_id INTEGER NOT NULL REFERENCES provider_resource(id) ON DELETE CASCADE,
+    -- the priority of the resource at the time the scrape was planned
+    priority DECIMAL NOT NULL,
+    scheduled_for TIMESTAMP WITHOUT TIME ZONE NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending'
+        CHECK (status IN ('pending', 'running', 'completed', 'failed')),
+    started_at TIMESTAMP WITHOUT TIME ZONE NULL,
+    finished_at TIMESTAMP WITHOUT TIME ZONE NULL,
+    -- the scrape that was recorded once it completed
+    scrape_id INTEGER NULL REFERENCES scrape(id) ON DELETE SET NULL,
+    error TEXT NULL,
+    created_at TIMESTAMP WITHOUT TIME ZONE NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX ON scheduled_scrape (status, scheduled_for);
+CREATE INDEX ON scheduled_scrape (provider_resource_id);
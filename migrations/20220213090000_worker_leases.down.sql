@@ -0,0 +1,4 @@
+-- Add down migration script here
+DROP TABLE IF EXISTS provider_rate_limit;
+ALTER TABLE scheduled_scrape DROP COLUMN IF EXISTS lease_expires_at;
+ALTER TABLE scheduled_scrape DROP COLUMN IF EXISTS leased_by;
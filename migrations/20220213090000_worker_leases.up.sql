@@ -0,0 +1,11 @@
+-- Add up migration script here
+-- workers lease the scrapes they run and keep extending the lease while they're alive,
+-- scrapes whose lease ran out are picked up by another worker
+ALTER TABLE scheduled_scrape ADD COLUMN IF NOT EXISTS leased_by TEXT NULL;
+ALTER TABLE scheduled_scrape ADD COLUMN IF NOT EXISTS lease_expires_at TIMESTAMP WITHOUT TIME ZONE NULL;
+
+-- the earliest time the next request to a provider can be made by any worker
+CREATE TABLE IF NOT EXISTS provider_rate_limit(
+    provider_name TEXT PRIMARY KEY,
+    next_request_at TIMESTAMP WITHOUT TIME ZONE NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE provider_resource DROP COLUMN IF EXISTS priority_strategy;
@@ -0,0 +1,3 @@
+-- Add up migration script here
+-- the strategy deciding how the priority of a resource changes, the provider's default when null
+ALTER TABLE provider_resource ADD COLUMN IF NOT EXISTS priority_strategy TEXT NULL;
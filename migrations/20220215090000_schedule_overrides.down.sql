@@ -0,0 +1,7 @@
+-- Add down migration script here
+ALTER TABLE provider_resource DROP CONSTRAINT IF EXISTS provider_resource_priority_bounds;
+ALTER TABLE provider_resource DROP COLUMN IF EXISTS fixed_interval_minutes;
+ALTER TABLE provider_resource DROP COLUMN IF EXISTS min_priority;
+ALTER TABLE provider_resource DROP COLUMN IF EXISTS max_priority;
+ALTER TABLE provider_resource DROP COLUMN IF EXISTS blackout_windows;
+ALTER TABLE provider_resource DROP COLUMN IF EXISTS paused_until;
@@ -0,0 +1,11 @@
+-- Add up migration script here
+-- scrapes the resource this often no matter what its priority is
+ALTER TABLE provider_resource ADD COLUMN IF NOT EXISTS fixed_interval_minutes INTEGER NULL CHECK (fixed_interval_minutes > 0);
+-- bounds the priority of the resource can move between
+ALTER TABLE provider_resource ADD COLUMN IF NOT EXISTS min_priority DECIMAL NULL;
+ALTER TABLE provider_resource ADD COLUMN IF NOT EXISTS max_priority DECIMAL NULL;
+ALTER TABLE provider_resource ADD CONSTRAINT provider_resource_priority_bounds CHECK (min_priority <= max_priority);
+-- comma separated UTC times of day the resource is never scraped at, like '02:00-04:30,23:00-00:15'
+ALTER TABLE provider_resource ADD COLUMN IF NOT EXISTS blackout_windows TEXT NULL;
+-- the resource isn't scraped and doesn't earn tokens until then
+ALTER TABLE provider_resource ADD COLUMN IF NOT EXISTS paused_until TIMESTAMP WITHOUT TIME ZONE NULL;
@@ -0,0 +1,8 @@
+-- Add up migration script here
+-- requests made to every provider each (UTC) day, counted against their daily budgets
+CREATE TABLE IF NOT EXISTS provider_request_usage(
+  provider_name TEXT NOT NULL,
+  day DATE NOT NULL,
+  requests INTEGER NOT NULL DEFAULT 0,
+  PRIMARY KEY (provider_name, day)
+);
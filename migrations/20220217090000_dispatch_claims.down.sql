@@ -0,0 +1,2 @@
+-- Add down migration script here
+ALTER TABLE dispatch_outbox DROP COLUMN IF EXISTS claimed_until;
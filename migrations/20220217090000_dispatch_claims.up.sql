@@ -0,0 +1,4 @@
+-- Add up migration script here
+-- outbox entries are claimed by the worker dispatching them so workers sharing a database
+-- don't all send the same payload, claims that run out are picked up by someone else
+ALTER TABLE dispatch_outbox ADD COLUMN IF NOT EXISTS claimed_until TIMESTAMP WITHOUT TIME ZONE NULL;
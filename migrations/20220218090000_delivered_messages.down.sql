@@ -0,0 +1,3 @@
+-- Add down migration script here
+ALTER TABLE webhook_dead_letter DROP COLUMN IF EXISTS delivered_messages;
+ALTER TABLE webhook_delivery DROP COLUMN IF EXISTS delivered_messages;
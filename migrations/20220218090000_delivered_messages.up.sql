@@ -0,0 +1,5 @@
+-- Add up migration script here
+-- payloads that don't fit in a single discord message are sent as several, the ones that
+-- already went through are skipped when the delivery is retried
+ALTER TABLE webhook_delivery ADD COLUMN IF NOT EXISTS delivered_messages INT NOT NULL DEFAULT 0;
+ALTER TABLE webhook_dead_letter ADD COLUMN IF NOT EXISTS delivered_messages INT NOT NULL DEFAULT 0;
@@ -7,12 +7,14 @@ use serde_json::json;
 use std::convert::Infallible;
 use std::sync::Arc;
 use crate::scraper::ProviderMap;
+use crate::scraper::credentials::CredentialStore;
 
 pub mod v1;
 
 pub struct Context {
     pub db: Arc<Database>,
-    pub providers: Arc<ProviderMap>
+    pub providers: Arc<ProviderMap>,
+    pub credentials: Option<Arc<CredentialStore>>,
 }
 
 pub enum AppError {
@@ -1,4 +1,6 @@
 pub mod stats;
 pub mod providers;
+pub mod scrape;
+pub mod webhooks;
 
 pub use stats::*;
@@ -4,10 +4,10 @@ use axum::extract::Extension;
 use axum::Json;
 use log::{debug, error, info};
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Map, Value};
+use serde_json::{Map, Value};
 
 use crate::api::{AppError, Context};
-use crate::scraper::{CanonicalUrlResolution, ProviderFailure, WorkableDomain};
+use crate::scraper::{provider_for_url, CanonicalUrlResolution, ProviderFailure, WorkableDomain};
 
 #[derive(Deserialize)]
 pub struct ProviderAdd {
@@ -39,11 +39,7 @@ pub async fn v1_add_provider(
     Extension(state): Extension<Arc<Context>>,
     Json(input): Json<ProviderAdd>,
 ) -> Result<Json<ProviderAddResponse>, AppError> {
-    let result = state
-        .providers
-        .values()
-        .find_map(|p| p.match_domain(&input.url).map(|res| (p, res)));
-    let (provider, domain) = match result {
+    let (provider, domain) = match provider_for_url(&state.providers, &input.url) {
         Some((provider, domain)) => (provider, domain),
         None => {
             debug!("Url {} was not valid", input.url);
@@ -75,6 +71,7 @@ pub async fn v1_add_provider(
             return Ok(Json(ProviderAddResponse::InvalidUrl { url: input.url }));
         }
         Err(other) => {
+            error!("{:?}", other);
             return Ok(Json(ProviderAddResponse::InternalError));
         }
     };
@@ -84,26 +81,28 @@ pub async fn v1_add_provider(
         provider.id()
     );
     let provider_name = provider.id().to_string();
-    if let Err(sqlx::Error::Database(err)) = sqlx::query!(
+    // resources that were deleted are enabled again and queued like new ones
+    let inserted = sqlx::query!(
         "INSERT INTO provider_resource (destination, name, default_name, official, url) VALUES
-            ($1, $2, $3, $4, $5)",
+            ($1, $2, $3, $4, $5)
+        ON CONFLICT (destination, name) DO UPDATE
+        SET enabled = True, tokens = GREATEST(provider_resource.tokens, 1), last_queue = NULL
+        WHERE NOT provider_resource.enabled
+        RETURNING id",
         destination,
         provider_name,
         input.name,
         input.official,
         input.url
     )
-    .execute(&*state.db)
-    .await
-    {
-        // the most disgusting way of checking for primary key conflicts
-        if err.code().map(|code| code == "23505").unwrap_or(false) {
-            return Ok(Json(ProviderAddResponse::ProviderExists {
-                destination,
-                provider: provider_name,
-            }));
-        }
-    };
+    .fetch_optional(&*state.db)
+    .await?;
+    if inserted.is_none() {
+        return Ok(Json(ProviderAddResponse::ProviderExists {
+            destination,
+            provider: provider_name,
+        }));
+    }
     // there's a conflict
     // TODO: decouple this kiyomi-specific thing out?
     if input.add_to_amqp.unwrap_or(false) {
@@ -140,13 +139,23 @@ pub async fn v1_delete_provider(
     Json(input): Json<ProviderDelete>,
 ) -> Result<Json<ProviderDeleteResponse>, AppError> {
     let result = sqlx::query!(
-        "UPDATE provider_resource SET enabled = False WHERE name = $1 and destination = $2 RETURNING *",
+        "UPDATE provider_resource SET enabled = False, last_queue = NULL
+        WHERE name = $1 and destination = $2 RETURNING id",
         input.name,
         input.destination,
     )
     .fetch_optional(&*state.db)
     .await?;
-    return Ok(Json(ProviderDeleteResponse {
+    if let Some(resource) = &result {
+        // scrapes that were queued would otherwise keep the resource from being planned again
+        sqlx::query!(
+            "DELETE FROM scheduled_scrape WHERE provider_resource_id = $1 AND status = 'pending'",
+            resource.id
+        )
+        .execute(&*state.db)
+        .await?;
+    }
+    Ok(Json(ProviderDeleteResponse {
         modified: result.is_some(),
-    }));
+    }))
 }
@@ -0,0 +1,275 @@
+use std::convert::Infallible;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::body::{boxed, BoxBody, StreamBody};
+use axum::extract::{Extension, Path, Query};
+use axum::http::{header, HeaderMap, HeaderValue, Response, StatusCode};
+use axum::response::IntoResponse;
+use axum::Json;
+use chrono::NaiveDateTime;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+use crate::api::{AppError, Context};
+use crate::db::process_scrape;
+use crate::dispatcher::dispatcher::DispatchablePayload;
+use crate::models::PendingProvider;
+use crate::scheduler::queue::{
+    complete_scheduled_scrape, fail_scheduled_scrape, lease_resource_now, release_scheduled_scrape,
+    renew_leases, worker_id, HEARTBEAT_INTERVAL_SECONDS,
+};
+use crate::scheduler::Priority;
+use crate::scraper::scraper::{scrape_input, scrape_with_progress, ScrapeRequest, ScraperStep};
+use crate::scraper::{AllProviders, ProviderPost, ScopedProvider};
+
+#[derive(Deserialize)]
+pub struct ScrapeOptions {
+    /// scrapes without saving anything or dispatching webhooks
+    dry_run: Option<bool>,
+}
+
+#[derive(Serialize)]
+pub enum ScrapeNowResponse {
+    NotFound,
+    UnknownProvider {
+        provider: String,
+    },
+    /// a worker is already scraping the resource
+    Busy,
+}
+
+/// A line of the response, written as soon as the scrape gets to it
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ScrapeEvent<'a> {
+    Started {
+        provider: String,
+        destination: String,
+        dry_run: bool,
+    },
+    Request {
+        date: NaiveDateTime,
+        response_code: u16,
+        /// milliseconds
+        response_delay: u128,
+        posts: &'a [ProviderPost],
+    },
+    RequestError {
+        date: NaiveDateTime,
+        error: String,
+    },
+    Finished {
+        requests: usize,
+        /// only set when the scrape was saved
+        scrape_id: Option<i32>,
+    },
+    Failed {
+        error: String,
+    },
+}
+
+fn send(tx: &UnboundedSender<String>, event: &ScrapeEvent) {
+    match serde_json::to_string(event) {
+        // the caller going away doesn't stop the scrape
+        Ok(line) => drop(tx.send(line + "\n")),
+        Err(err) => error!("Could not serialize a scrape event: {:?}", err),
+    }
+}
+
+fn send_request(tx: &UnboundedSender<String>, request: &ScrapeRequest) {
+    let event = match &request.step {
+        ScraperStep::Data(result) => ScrapeEvent::Request {
+            date: request.date,
+            response_code: result.response_code.as_u16(),
+            response_delay: result.response_delay.as_millis(),
+            posts: &result.posts,
+        },
+        ScraperStep::Error(err) => ScrapeEvent::RequestError {
+            date: request.date,
+            error: format!("{:?}", err),
+        },
+    };
+    send(tx, &event);
+}
+
+/// Runs the scrape and keeps its lease alive until it's over
+async fn run_scrape(
+    state: Arc<Context>,
+    pending: PendingProvider,
+    lease: String,
+    id: i32,
+    dry_run: bool,
+    tx: UnboundedSender<String>,
+) {
+    let heartbeat = {
+        let db = Arc::clone(&state.db);
+        let lease = lease.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Duration::from_secs(HEARTBEAT_INTERVAL_SECONDS)).await;
+                if let Err(err) = renew_leases(&db, &lease).await {
+                    error!("{:?}", err);
+                }
+            }
+        })
+    };
+    let result = scrape_and_save(&state, &pending, dry_run, &tx).await;
+    heartbeat.abort();
+    let db = &*state.db;
+    let recorded = match result {
+        Ok((requests, scrape_id)) => {
+            send(
+                &tx,
+                &ScrapeEvent::Finished {
+                    requests,
+                    scrape_id,
+                },
+            );
+            match scrape_id {
+                Some(scrape_id) => complete_scheduled_scrape(db, &lease, id, scrape_id).await,
+                None => release_scheduled_scrape(db, &lease, id).await,
+            }
+        }
+        Err(err) => {
+            error!("{:?}", err);
+            send(
+                &tx,
+                &ScrapeEvent::Failed {
+                    error: err.to_string(),
+                },
+            );
+            if dry_run {
+                release_scheduled_scrape(db, &lease, id).await
+            } else {
+                fail_scheduled_scrape(db, &lease, id, &err.to_string()).await
+            }
+        }
+    };
+    if let Err(err) = recorded {
+        error!("Could not update scheduled scrape {}", id);
+        error!("{:?}", err);
+    }
+}
+
+/// Returns how many requests were made and the id of the scrape if it was saved
+async fn scrape_and_save(
+    state: &Context,
+    pending: &PendingProvider,
+    dry_run: bool,
+    tx: &UnboundedSender<String>,
+) -> anyhow::Result<(usize, Option<i32>)> {
+    let provider = state
+        .providers
+        .get(&pending.provider.name)
+        .ok_or_else(|| anyhow::anyhow!("{} is not enabled", pending.provider.name))?;
+    let input = scrape_input(&state.db, pending).await?;
+    let result = scrape_with_progress(
+        &pending.provider,
+        &**provider,
+        &input,
+        state.credentials.as_deref(),
+        &|request| send_request(tx, request),
+    )
+    .await?;
+    let requests = result.requests.len();
+    if dry_run {
+        return Ok((requests, None));
+    }
+    // the payload is dispatched by the outbox once the scrape is committed
+    let payload = if result.discovered_new_images() {
+        Some(DispatchablePayload::new(&**provider, &result, None))
+    } else {
+        None
+    };
+    let processed = process_scrape(&state.db, &result, pending, payload.as_ref()).await?;
+    Ok((requests, Some(processed.scrape_id)))
+}
+
+fn rejected(status: StatusCode, response: ScrapeNowResponse) -> Response<BoxBody> {
+    (status, Json(response)).into_response().map(boxed)
+}
+
+/// Scrapes a resource right away and streams every request back as a line of JSON as soon as
+/// it's done. The scrape holds a lease like any other so it never runs next to a worker's
+pub async fn v1_scrape_provider(
+    Extension(state): Extension<Arc<Context>>,
+    Path(id): Path<i32>,
+    Query(options): Query<ScrapeOptions>,
+) -> Result<Response<BoxBody>, AppError> {
+    let dry_run = options.dry_run.unwrap_or(false);
+    let row = sqlx::query!(
+        "SELECT id, name, destination, official, priority, last_scrape, default_name
+        FROM provider_resource WHERE id = $1",
+        id
+    )
+    .fetch_optional(&*state.db)
+    .await?;
+    let row = match row {
+        Some(row) => row,
+        None => return Ok(rejected(StatusCode::NOT_FOUND, ScrapeNowResponse::NotFound)),
+    };
+    let name = match AllProviders::from_str(&row.name) {
+        Ok(name) => name,
+        Err(_) => {
+            return Ok(rejected(
+                StatusCode::BAD_REQUEST,
+                ScrapeNowResponse::UnknownProvider { provider: row.name },
+            ))
+        }
+    };
+    let lease = worker_id();
+    let scheduled_id = match lease_resource_now(&state.db, &lease, id).await? {
+        Some(scheduled_id) => scheduled_id,
+        None => return Ok(rejected(StatusCode::CONFLICT, ScrapeNowResponse::Busy)),
+    };
+    let pending = PendingProvider {
+        id: row.id,
+        default_name: row.default_name,
+        priority: Priority {
+            level: row.priority,
+        },
+        provider: ScopedProvider {
+            name,
+            destination: row.destination,
+            official: row.official,
+        },
+        scheduled_for: chrono::Utc::now().naive_utc(),
+        last_scrape: row.last_scrape,
+    };
+    info!(
+        "Scraping {} on demand{}",
+        pending,
+        if dry_run { " as a dry run" } else { "" }
+    );
+
+    let (tx, rx) = unbounded_channel();
+    send(
+        &tx,
+        &ScrapeEvent::Started {
+            provider: pending.provider.name.to_string(),
+            destination: pending.provider.destination.clone(),
+            dry_run,
+        },
+    );
+    tokio::spawn(run_scrape(
+        Arc::clone(&state),
+        pending,
+        lease,
+        scheduled_id,
+        dry_run,
+        tx,
+    ));
+    let lines = futures::stream::unfold(rx, |mut rx| async move {
+        let line = rx.recv().await?;
+        Some((Ok::<_, Infallible>(line), rx))
+    });
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/x-ndjson"),
+    );
+    Ok((headers, StreamBody::new(lines)).into_response().map(boxed))
+}
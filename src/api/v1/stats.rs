@@ -2,13 +2,14 @@ use std::sync::Arc;
 
 use axum::extract::Extension;
 use axum::Json;
-use chrono::{DateTime, Duration, NaiveDateTime, Utc};
+use chrono::{Duration, NaiveDateTime, Utc};
 use num_traits::ToPrimitive;
 use serde::Serialize;
 use sqlx::types::BigDecimal;
 
 use crate::api::{AppError, Context};
-use crate::models::ScrapeHistory;
+use crate::scheduler::budget::{budget_usage, configured_budget};
+use crate::scheduler::overrides::Blackouts;
 
 struct ScheduledProvider {
     id: i32,
@@ -21,6 +22,60 @@ struct ScheduledProvider {
     last_queue: Option<NaiveDateTime>,
     metadata: Option<serde_json::Value>,
     official: bool,
+    last_scrape: Option<NaiveDateTime>,
+    fixed_interval_minutes: Option<i32>,
+    min_priority: Option<BigDecimal>,
+    max_priority: Option<BigDecimal>,
+    blackout_windows: Option<String>,
+    paused_until: Option<NaiveDateTime>,
+}
+
+/// What a resource was told to do differently from the rest
+#[derive(Serialize)]
+pub struct ScheduleOverrides {
+    fixed_interval_minutes: Option<i32>,
+    min_priority: Option<f32>,
+    max_priority: Option<f32>,
+    blackout_windows: Vec<String>,
+    paused_until: Option<NaiveDateTime>,
+}
+
+impl ScheduledProvider {
+    fn overrides(&self) -> ScheduleOverrides {
+        ScheduleOverrides {
+            fixed_interval_minutes: self.fixed_interval_minutes,
+            min_priority: self.min_priority.as_ref().and_then(|p| p.to_f32()),
+            max_priority: self.max_priority.as_ref().and_then(|p| p.to_f32()),
+            blackout_windows: Blackouts::from_column(self.blackout_windows.as_deref())
+                .windows()
+                .iter()
+                .map(|window| window.to_string())
+                .collect(),
+            paused_until: self.paused_until,
+        }
+    }
+
+    /// Days until a resource that wasn't queued recently is expected to be scraped again
+    fn wait_days(&self, now: NaiveDateTime) -> i16 {
+        let wait_days = match self.fixed_interval_minutes {
+            Some(minutes) => self
+                .last_scrape
+                .map(|last_scrape| {
+                    (last_scrape + Duration::minutes(minutes as i64) - now).num_days()
+                })
+                .unwrap_or(0)
+                .max(0) as i16,
+            None => ((1f32 / (self.priority.clone() + self.tokens.clone()))
+                .to_f32()
+                .unwrap_or(0f32))
+            .floor() as i16,
+        };
+        let paused_days = self
+            .paused_until
+            .map(|until| (until - now).num_days().max(0) as i16)
+            .unwrap_or(0);
+        wait_days.max(paused_days)
+    }
 }
 
 #[derive(Serialize)]
@@ -33,6 +88,7 @@ pub struct ScheduledProviderRun {
     metadata: Option<serde_json::Value>,
     name: String,
     official: bool,
+    overrides: ScheduleOverrides,
 }
 
 struct PreviousScrapeRow {
@@ -76,47 +132,46 @@ pub async fn v1_scheduled_scrapes(
 ) -> Result<Json<Vec<ScheduledProviderRun>>, AppError> {
     let rows = sqlx::query_as!(
         ScheduledProvider,
-        "SELECT pr.id, pr.official, pr.priority, pr.name, pr.destination, pr.url, pr.tokens, pr.last_queue, pr.default_name, (
+        "SELECT pr.id, pr.official, pr.priority, pr.name, pr.destination, pr.url, pr.tokens, pr.last_queue, pr.default_name,
+            pr.last_scrape, pr.fixed_interval_minutes, pr.min_priority, pr.max_priority, pr.blackout_windows, pr.paused_until, (
             SELECT metadata FROM amqp_source where provider_destination = pr.destination and provider_name = pr.name
         ) as metadata FROM provider_resource pr"
     )
         .fetch_all(&*state.db)
         .await?;
+    let now = Utc::now().naive_utc();
     let (today, later): (Vec<ScheduledProvider>, Vec<ScheduledProvider>) =
         rows.into_iter().partition(|e| {
-            let now = Utc::now().naive_utc();
+            let paused = e.paused_until.map(|until| until > now).unwrap_or(false);
             // anything that was queued in the last 24 hours is already being scraped
             // it's not SUPER accurate since it's possible but
             // we only need a general idea, not precision
-            e.last_queue
-                .map(|last_queue| {
-                    let yesterday = now - Duration::hours(24);
-                    last_queue > yesterday
-                })
-                .unwrap_or(false)
+            !paused
+                && e.last_queue
+                    .map(|last_queue| {
+                        let yesterday = now - Duration::hours(24);
+                        last_queue > yesterday
+                    })
+                    .unwrap_or(false)
         });
     let labeled = later
         .into_iter()
-        .map(|row| {
-            let wait_days = ((1f32 / (row.priority + row.tokens))
-                .to_f32()
-                .unwrap_or(0f32))
-            .floor() as i16;
-            ScheduledProviderRun {
-                destination: row.destination,
-                provider: row.name,
-                id: row.id,
-                url: row.url,
-                official: row.official,
-                wait_days,
-                metadata: row.metadata,
-                name: row.default_name.unwrap_or_default(),
-            }
+        .map(|row| ScheduledProviderRun {
+            wait_days: row.wait_days(now),
+            overrides: row.overrides(),
+            destination: row.destination,
+            provider: row.name,
+            id: row.id,
+            url: row.url,
+            official: row.official,
+            metadata: row.metadata,
+            name: row.default_name.unwrap_or_default(),
         })
         .collect::<Vec<_>>();
     let mut scheduled = today
         .into_iter()
         .map(|t| ScheduledProviderRun {
+            overrides: t.overrides(),
             destination: t.destination,
             provider: t.name,
             official: t.official,
@@ -195,9 +250,21 @@ pub struct ProviderStat {
     scrape_count: i64,
 }
 
+/// How much of its daily request budget a provider used today
+#[derive(Serialize)]
+pub struct ProviderBudgetStat {
+    provider: String,
+    /// unlimited when missing
+    budget: Option<u32>,
+    used_today: i64,
+    queued_today: i64,
+    requests_per_scrape: f32,
+}
+
 #[derive(Serialize)]
 pub struct ProviderStatsResponse {
     stats: Vec<ProviderStat>,
+    budgets: Vec<ProviderBudgetStat>,
 }
 
 pub async fn v1_provider_stats(
@@ -238,6 +305,9 @@ pub async fn v1_provider_stats(
     )
     .fetch_all(&*state.db)
     .await?;
+    let usage = budget_usage(&state.db).await?;
+    let mut providers = state.providers.keys().collect::<Vec<_>>();
+    providers.sort_by_key(|provider| provider.to_string());
     let data = ProviderStatsResponse {
         stats: stats
             .iter()
@@ -257,6 +327,19 @@ pub async fn v1_provider_stats(
                 scrape_count: stat.scrape_count.unwrap_or(0),
             })
             .collect::<Vec<_>>(),
+        budgets: providers
+            .into_iter()
+            .map(|provider| {
+                let usage = usage.get(provider).cloned().unwrap_or_default();
+                ProviderBudgetStat {
+                    provider: provider.to_string(),
+                    budget: configured_budget(*provider),
+                    used_today: usage.used,
+                    queued_today: usage.queued,
+                    requests_per_scrape: usage.requests_per_scrape,
+                }
+            })
+            .collect::<Vec<_>>(),
     };
     Ok(Json(data))
 }
@@ -0,0 +1,170 @@
+use std::sync::Arc;
+
+use axum::extract::{Extension, Path};
+use axum::http::StatusCode;
+use axum::Json;
+use chrono::{Duration, NaiveDateTime, Utc};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::api::{AppError, Context};
+use crate::dispatcher::signature::generate_secret;
+
+#[derive(Serialize)]
+pub struct DeadLetter {
+    id: i32,
+    webhook_id: i32,
+    destination: String,
+    scrape_id: Option<i32>,
+    delivery_id: String,
+    attempts: i32,
+    last_response_code: Option<i32>,
+    last_error: Option<String>,
+    created_at: NaiveDateTime,
+    failed_at: NaiveDateTime,
+    payload: serde_json::Value,
+}
+
+#[derive(Serialize)]
+pub struct DeadLetterResponse {
+    dead_letters: Vec<DeadLetter>,
+}
+
+pub async fn v1_dead_letters(
+    Extension(state): Extension<Arc<Context>>,
+) -> Result<Json<DeadLetterResponse>, AppError> {
+    let dead_letters = sqlx::query_as!(
+        DeadLetter,
+        "SELECT dl.id,
+            dl.webhook_id,
+            w.destination,
+            dl.scrape_id,
+            dl.delivery_id,
+            dl.attempts,
+            dl.last_response_code,
+            dl.last_error,
+            dl.created_at,
+            dl.failed_at,
+            dl.payload
+        FROM webhook_dead_letter dl
+        INNER JOIN webhook w on w.id = dl.webhook_id
+        ORDER BY dl.failed_at desc
+        LIMIT 100"
+    )
+    .fetch_all(&*state.db)
+    .await?;
+    Ok(Json(DeadLetterResponse { dead_letters }))
+}
+
+#[derive(Serialize)]
+pub struct ReplayResponse {
+    replayed: bool,
+}
+
+/// Moves a dead letter back into the retry queue with a fresh set of attempts
+pub async fn v1_replay_dead_letter(
+    Extension(state): Extension<Arc<Context>>,
+    Path(id): Path<i32>,
+) -> Result<Json<ReplayResponse>, AppError> {
+    let mut tx = state.db.begin().await?;
+    let replayed = sqlx::query!(
+        "INSERT INTO webhook_delivery (webhook_id, scrape_id, delivery_id, payload, delivered_messages, created_at)
+        SELECT webhook_id, scrape_id, delivery_id, payload, delivered_messages, created_at FROM webhook_dead_letter WHERE id = $1
+        RETURNING id",
+        id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    if replayed.is_some() {
+        sqlx::query!("DELETE FROM webhook_dead_letter WHERE id = $1", id)
+            .execute(&mut tx)
+            .await?;
+        info!("Replaying dead letter {}", id);
+    }
+    tx.commit().await?;
+    Ok(Json(ReplayResponse {
+        replayed: replayed.is_some(),
+    }))
+}
+
+/// Both secrets are used for signing for this long after a rotation unless specified
+const DEFAULT_ROTATION_OVERLAP_HOURS: i64 = 24;
+
+/// Old secrets are never trusted for longer than a week
+const MAX_ROTATION_OVERLAP_HOURS: i64 = 24 * 7;
+
+#[derive(Deserialize)]
+pub struct SecretRotation {
+    overlap_hours: Option<i64>,
+}
+
+#[derive(Serialize)]
+pub enum SecretRotationResponse {
+    WebhookNotFound,
+    InvalidOverlap {
+        max_hours: i64,
+    },
+    Success {
+        secret: String,
+        previous_secret_expires_at: Option<NaiveDateTime>,
+    },
+}
+
+/// Generates a new signing secret for a webhook. The old secret keeps getting
+/// used next to the new one until the overlap window is over
+pub async fn v1_rotate_webhook_secret(
+    Extension(state): Extension<Arc<Context>>,
+    Path(id): Path<i32>,
+    input: Option<Json<SecretRotation>>,
+) -> Result<(StatusCode, Json<SecretRotationResponse>), AppError> {
+    let overlap_hours = input
+        .and_then(|Json(input)| input.overlap_hours)
+        .unwrap_or(DEFAULT_ROTATION_OVERLAP_HOURS);
+    let expires_at = Some(overlap_hours)
+        .filter(|hours| (0..=MAX_ROTATION_OVERLAP_HOURS).contains(hours))
+        .and_then(|hours| {
+            Utc::now()
+                .naive_utc()
+                .checked_add_signed(Duration::hours(hours))
+        });
+    let expires_at = match expires_at {
+        Some(expires_at) => expires_at,
+        None => {
+            return Ok((
+                StatusCode::BAD_REQUEST,
+                Json(SecretRotationResponse::InvalidOverlap {
+                    max_hours: MAX_ROTATION_OVERLAP_HOURS,
+                }),
+            ))
+        }
+    };
+    let secret = generate_secret();
+    let result = sqlx::query!(
+        "UPDATE webhook
+        SET
+            previous_secret = secret,
+            previous_secret_expires_at = CASE WHEN secret IS NULL THEN NULL ELSE $3::TIMESTAMP END,
+            secret = $2,
+            updated_at = NOW()
+        WHERE id = $1
+        RETURNING previous_secret_expires_at",
+        id,
+        secret,
+        expires_at
+    )
+    .fetch_optional(&*state.db)
+    .await?;
+    Ok((
+        StatusCode::OK,
+        Json(match result {
+            None => SecretRotationResponse::WebhookNotFound,
+            Some(row) => {
+                info!("Rotated the signing secret of webhook {}", id);
+                SecretRotationResponse::Success {
+                    secret,
+                    previous_secret_expires_at: row.previous_secret_expires_at,
+                }
+            }
+        }),
+    ))
+}
@@ -0,0 +1,147 @@
+//! Replays the scrape history in the database with different scheduling parameters and
+//! compares the result with what actually happened. See the README for the options.
+use std::env;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail};
+use chrono::{Duration, Utc};
+use jiu::db::connect;
+use jiu::dotenv;
+use jiu::scheduler::simulation::{compare, load_history, Outcome, SimulationParams};
+use jiu::scheduler::strategy::StrategyName;
+
+const USAGE: &str = "Usage: simulate [options]
+
+Options:
+    --days <n>                      how many days of history to replay [default: 30]
+    --strategy <name>               use this strategy for every resource (curve, ewma, fixed_rate)
+    --min-priority <n>              the lowest priority a resource can drop to
+    --max-priority <n>              the highest priority a resource can climb to
+    --max-result-contribution <n>   images a scrape can find before more stop counting
+    --token-period-hours <n>        hours a priority of 1 takes to earn a token [default: 24]
+    --starved-after-days <n>        posts waiting longer than this are starved [default: 7]";
+
+struct Args {
+    days: i64,
+    params: SimulationParams,
+}
+
+fn value<T: FromStr>(flag: &str, value: Option<String>) -> anyhow::Result<T> {
+    let value = value.ok_or_else(|| anyhow!("{} needs a value", flag))?;
+    value
+        .parse()
+        .map_err(|_| anyhow!("{} is not a valid value for {}", value, flag))
+}
+
+fn parse_args() -> anyhow::Result<Args> {
+    let mut days = 30;
+    let mut params = SimulationParams::default();
+    let mut args = env::args().skip(1);
+    while let Some(flag) = args.next() {
+        match flag.as_str() {
+            "--days" => days = value(&flag, args.next())?,
+            "--strategy" => params.strategy = Some(value(&flag, args.next())?),
+            "--min-priority" => params.limits.min_priority = value(&flag, args.next())?,
+            "--max-priority" => params.limits.max_priority = value(&flag, args.next())?,
+            "--max-result-contribution" => {
+                params.limits.max_result_contribution = value(&flag, args.next())?
+            }
+            "--token-period-hours" => {
+                params.token_period = Duration::hours(value(&flag, args.next())?)
+            }
+            "--starved-after-days" => {
+                params.starved_after = Duration::days(value(&flag, args.next())?)
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                std::process::exit(0);
+            }
+            _ => bail!("Unknown option {}\n\n{}", flag, USAGE),
+        }
+    }
+    if params.limits.min_priority > params.limits.max_priority {
+        bail!("--min-priority can't be higher than --max-priority");
+    }
+    if params.limits.max_result_contribution == 0 || params.token_period <= Duration::zero() {
+        bail!("--max-result-contribution and --token-period-hours must be above 0");
+    }
+    Ok(Args { days, params })
+}
+
+fn hours(duration: Option<Duration>) -> String {
+    match duration {
+        Some(duration) => format!("{:.1}", duration.num_minutes() as f32 / 60.0),
+        None => "-".to_owned(),
+    }
+}
+
+fn print_comparison(actual: &Outcome, simulated: &Outcome) {
+    let rows = [
+        (
+            "scrapes",
+            actual.scrapes.to_string(),
+            simulated.scrapes.to_string(),
+        ),
+        (
+            "requests",
+            format!("{:.0}", actual.requests),
+            format!("{:.0}", simulated.requests),
+        ),
+        (
+            "posts discovered",
+            actual.discovered.to_string(),
+            simulated.discovered.to_string(),
+        ),
+        (
+            "posts undiscovered",
+            actual.undiscovered.to_string(),
+            simulated.undiscovered.to_string(),
+        ),
+        (
+            "mean latency (hours)",
+            hours(actual.mean_latency()),
+            hours(simulated.mean_latency()),
+        ),
+        (
+            "max latency (hours)",
+            hours(Some(actual.max_latency())),
+            hours(Some(simulated.max_latency())),
+        ),
+        (
+            "resources starved",
+            actual.starved_resources.to_string(),
+            simulated.starved_resources.to_string(),
+        ),
+    ];
+    println!("{:<24}{:>12}{:>12}", "", "actual", "simulated");
+    for (name, actual, simulated) in rows.iter() {
+        println!("{:<24}{:>12}{:>12}", name, actual, simulated);
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    env_logger::init();
+    dotenv().ok();
+    let Args { days, params } = parse_args()?;
+    let strategy = params
+        .strategy
+        .map(|strategy: StrategyName| strategy.to_string())
+        .unwrap_or_else(|| "configured".to_owned());
+
+    let db = connect().await?;
+    let end = Utc::now().naive_utc();
+    let start = end - Duration::days(days);
+    let resources = load_history(&db, start, end).await?;
+    println!(
+        "Replaying {} resources over the last {} days with {} strategies, priorities between {} and {}\n",
+        resources.len(),
+        days,
+        strategy,
+        params.limits.min_priority,
+        params.limits.max_priority
+    );
+    let (actual, simulated) = compare(&resources, end, &params);
+    print_comparison(&actual, &simulated);
+    Ok(())
+}
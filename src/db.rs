@@ -1,14 +1,19 @@
 use std::collections::HashSet;
 use std::env;
 use std::iter::FromIterator;
+use std::time::Duration;
 
 use anyhow::bail;
+use chrono::Utc;
 use itertools::Itertools;
-use log::error;
+use log::{debug, error};
 use sqlx::postgres::PgPoolOptions;
-use sqlx::{Error, Pool, Postgres};
+use sqlx::{Error, Pool, Postgres, Transaction};
 
-use crate::dispatcher::dispatcher::WebhookInteraction;
+use crate::dispatcher::dispatcher::{
+    response_error, response_status, DispatchablePayload, WebhookInteraction, WebhookResponse,
+};
+use crate::dispatcher::retry::retry_delay;
 use crate::models::{
     AMQPDestination, DatabaseWebhook, PendingProvider, ScrapeRequestMedia, ScrapeRequestWithMedia,
 };
@@ -19,10 +24,24 @@ use crate::scraper::{ProviderFailure, ScopedProvider};
 pub type Database = Pool<Postgres>;
 
 pub async fn connect() -> Result<Database, Error> {
-    Ok(PgPoolOptions::new()
+    PgPoolOptions::new()
         .max_connections(5)
         .connect(&env::var("DATABASE_URL").expect("No DATABASE_URL env"))
-        .await?)
+        .await
+}
+
+/// Tests write to the database they run against, so they only run when `TEST_DATABASE_URL`
+/// points them at one that isn't used for anything else
+#[cfg(test)]
+pub async fn test_database() -> Option<Database> {
+    let url = env::var("TEST_DATABASE_URL").ok()?;
+    Some(
+        PgPoolOptions::new()
+            .max_connections(5)
+            .connect(&url)
+            .await
+            .unwrap(),
+    )
 }
 
 // Grab the latest N images from a relevant provider destination
@@ -40,7 +59,7 @@ pub async fn latest_media_ids_from_provider(
     .map(|e| e.unique_identifier)
     .fetch_all(db)
     .await?;
-    Ok(HashSet::from_iter(out.into_iter()))
+    Ok(HashSet::from_iter(out))
 }
 
 pub async fn amqp_metadata(
@@ -80,14 +99,16 @@ pub async fn webhooks_for_provider(
 
 #[derive(Debug)]
 pub struct ProcessedScrape {
-    scrape_id: i32,
+    pub scrape_id: i32,
 }
 
-/// Adds scrapes to the db. Reverses the scrape list as a side effect
+/// Adds scrapes to the db. The payload, if there is one, is written to the dispatch outbox
+/// in the same transaction so nothing gets dispatched for a scrape that was never committed
 pub async fn process_scrape<'a>(
     db: &Database,
-    scrape: &mut Scrape<'a>,
+    scrape: &Scrape<'a>,
     pending: &PendingProvider,
+    payload: Option<&DispatchablePayload>,
 ) -> anyhow::Result<ProcessedScrape> {
     let mut tx = db.begin().await?;
     let out = sqlx::query!(
@@ -103,28 +124,30 @@ pub async fn process_scrape<'a>(
         "UPDATE provider_resource
         SET
             last_scrape = NOW(),
-            tokens = tokens - 1
+            -- resources with a fixed interval aren't held back by tokens and shouldn't owe any,
+            -- the rest still go into debt when they're scraped early
+            tokens = CASE
+                WHEN fixed_interval_minutes IS NOT NULL THEN GREATEST(tokens - 1, 0)
+                ELSE tokens - 1
+            END
         WHERE name = $1 AND destination = $2
         RETURNING *",
         scrape.provider.name.to_string(),
         scrape.provider.destination,
     )
-    .fetch_one(db)
+    .fetch_one(&mut tx)
     .await?;
     let scrape_id = out.id;
-    let requests = &mut scrape.requests;
-    // we specifically need to reverse this list of requests/images
-    // to make sure that the images that were first scraped get inserted
+    // we specifically need to go through this list of requests/images
+    // in reverse to make sure that the images that were first scraped get inserted
     // last with the highest id
-    requests.reverse();
-
-    for (i, request) in requests.iter().enumerate() {
+    for (i, request) in scrape.requests.iter().rev().enumerate() {
         match &request.step {
             ScraperStep::Data(provider_result) => {
                 let response_code = provider_result.response_code.as_u16();
                 let scrape_request_row = sqlx::query!(
-                    "INSERT INTO scrape_request (scrape_id, response_code, response_delay, scraped_at, page)
-                    VALUES ($1, $2, $3, $4, $5)
+                    "INSERT INTO scrape_request (scrape_id, response_code, response_delay, scraped_at, page, account)
+                    VALUES ($1, $2, $3, $4, $5, $6)
                     RETURNING id",
                     scrape_id,
                     response_code as u32,
@@ -132,7 +155,8 @@ pub async fn process_scrape<'a>(
                     provider_result.response_delay.as_millis() as u32,
                     request.date,
                     // pages are 1-indexed
-                    (i as i32) + 1
+                    (i as i32) + 1,
+                    scrape.account
                 ).fetch_one(&mut tx).await?;
                 // we're not persisting post data, but that's ok
                 let mut posts = provider_result.posts.clone();
@@ -215,60 +239,111 @@ pub async fn process_scrape<'a>(
             ScraperStep::Error(ProviderFailure::Url) => {
                 println!(
                     "Could not formal url properly for {}: {}",
-                    scrape.provider.name.to_string(),
+                    scrape.provider.name,
                     scrape.provider.destination
                 );
             }
             _ => {}
         }
     }
+    if let Some(payload) = payload {
+        sqlx::query!(
+            "INSERT INTO dispatch_outbox (scrape_id, provider_name, provider_destination, payload)
+            VALUES ($1, $2, $3, $4)",
+            scrape_id,
+            scrape.provider.name.to_string(),
+            scrape.provider.destination,
+            serde_json::to_value(payload)?
+        )
+        .execute(&mut tx)
+        .await?;
+    }
     tx.commit().await?;
     Ok(ProcessedScrape { scrape_id: out.id })
 }
 
+/// Records a single attempt at delivering a webhook
+pub async fn record_webhook_invocation(
+    tx: &mut Transaction<'_, Postgres>,
+    scrape_id: Option<i32>,
+    webhook_id: i32,
+    delivery_id: &str,
+    attempt: i32,
+    response: &WebhookResponse,
+    response_time: Duration,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "INSERT INTO webhook_invocation (
+            scrape_id,
+            webhook_id,
+            delivery_id,
+            response_code,
+            response_delay,
+            attempt,
+            error
+        ) VALUES ($1, $2, $3, $4, $5, $6, $7)",
+        scrape_id,
+        webhook_id,
+        delivery_id,
+        response_status(response).map(|code| code.as_u16() as i32),
+        response_time.as_millis() as i32,
+        attempt,
+        response_error(response)
+    )
+    .execute(tx)
+    .await?;
+    Ok(())
+}
+
 pub async fn submit_webhook_responses(
     db: &Database,
-    processed_scrape: ProcessedScrape,
+    scrape_id: i32,
     interactions: Vec<WebhookInteraction>,
 ) -> anyhow::Result<()> {
     let mut tx = db.begin().await?;
-    // can't commit the invocation if we don't have a response status
     for interaction in interactions {
-        let response_time = interaction.response_time.as_millis() as i32;
-        let response = interaction.response;
-        let status = match response {
-            Ok(res) => Some(res.status()),
-            Err(HttpError::UnexpectedBody(err)) | Err(HttpError::FailStatus(err)) => Some(err.code),
-            Err(HttpError::ReqwestError(err)) => {
-                let out = err.status();
-                if out.is_none() {
-                    println!("Received a response without a status code");
-                    eprintln!("{:?}", err);
-                }
-                out
-            }
-        };
-        if let Some(code) = status {
-            sqlx::query!(
-                "INSERT INTO webhook_invocation (
-                    scrape_id,
-                    webhook_id,
-                    response_code,
-                    response_delay
-                ) VALUES ($1, $2, $3, $4) RETURNING *",
-                processed_scrape.scrape_id,
-                interaction.webhook.id,
-                code.as_u16() as i32,
-                response_time
-            )
-            .fetch_one(&mut tx)
-            .await?;
-        } else {
-            println!(
-                "Failed to persist webhook response from {}",
-                interaction.webhook.destination
-            )
+        let response = &interaction.response;
+        record_webhook_invocation(
+            &mut tx,
+            Some(scrape_id),
+            interaction.webhook.id,
+            &interaction.delivery_id,
+            1,
+            response,
+            interaction.response_time,
+        )
+        .await?;
+        if response.is_ok() {
+            continue;
         }
+        debug!(
+            "Queueing a retry for the webhook delivery to {}",
+            interaction.webhook.destination
+        );
+        let next_attempt_at = Utc::now().naive_utc() + chrono::Duration::from_std(retry_delay(1))?;
+        sqlx::query!(
+            "INSERT INTO webhook_delivery (
+                webhook_id,
+                scrape_id,
+                delivery_id,
+                payload,
+                attempts,
+                last_response_code,
+                last_error,
+                next_attempt_at,
+                delivered_messages
+            ) VALUES ($1, $2, $3, $4, 1, $5, $6, $7, $8)",
+            interaction.webhook.id,
+            scrape_id,
+            interaction.delivery_id,
+            serde_json::to_value(&interaction.payload)?,
+            response_status(response).map(|code| code.as_u16() as i32),
+            response_error(response),
+            next_attempt_at,
+            interaction.delivered_messages
+        )
+        .execute(&mut tx)
+        .await?;
     }
     tx.commit().await?;
     Ok(())
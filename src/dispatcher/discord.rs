@@ -1,22 +1,417 @@
-use serde::Serialize;
+use std::time::{Duration, Instant};
 
-#[derive(Debug, Serialize)]
+use chrono::{DateTime, Utc};
+use log::{debug, warn};
+use reqwest::header::RETRY_AFTER;
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+
+use crate::dispatcher::dispatcher::{DispatchablePayload, WebhookResponse};
+use crate::request::{request_default_headers, HttpError, ResponseErrorContext};
+use crate::scraper::{ProviderMediaType, ProviderPost};
+
+/// https://discord.com/developers/docs/resources/channel#embed-object-embed-limits
+const MAX_EMBEDS_PER_MESSAGE: usize = 10;
+const MAX_EMBED_CHARACTERS_PER_MESSAGE: usize = 6000;
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+const MAX_AUTHOR_NAME_LENGTH: usize = 256;
+const MAX_FOOTER_LENGTH: usize = 2048;
+
+/// How many times a single message is retried after getting rate limited
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+
+/// Anything that asks us to wait longer than this is treated as a failed
+/// delivery and left to the retry queue
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(30);
+
+/// Rate limits are reported as at most a day long no matter what Discord asked for
+const MAX_REPORTED_RETRY_AFTER_SECONDS: f32 = 60.0 * 60.0 * 24.0;
+
+#[derive(Debug, Clone, Serialize)]
 pub struct DiscordImage {
     pub url: String,
 }
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscordEmbedAuthor {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon_url: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscordEmbedFooter {
+    pub text: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
 pub struct DiscordEmbed {
-    pub image: DiscordImage,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub description: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub author: Option<DiscordEmbedAuthor>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<DiscordImage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub footer: Option<DiscordEmbedFooter>,
+}
+
+impl DiscordEmbed {
+    /// The number of characters that count towards the per-message embed limit
+    fn character_count(&self) -> usize {
+        let count = |text: &Option<String>| text.as_ref().map_or(0, |t| t.chars().count());
+        count(&self.description)
+            + self.author.as_ref().map_or(0, |a| a.name.chars().count())
+            + self.footer.as_ref().map_or(0, |f| f.text.chars().count())
+    }
 }
 
 #[derive(Debug, Serialize)]
-pub struct DiscordPayload<'a> {
-    pub username: &'a str,
-    pub avatar_url: &'a str,
-    pub content: String, // Vec<DiscordEmbed>,
+pub struct DiscordPayload {
+    pub embeds: Vec<DiscordEmbed>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiscordRateLimit {
+    /// seconds until the next request can be made
+    retry_after: f32,
 }
 
 pub fn is_discord_webhook_url(url: &str) -> bool {
     url.starts_with("https://discord.com/api/webhooks")
 }
+
+fn truncate(text: &str, max_length: usize) -> String {
+    if text.chars().count() <= max_length {
+        return text.to_owned();
+    }
+    let mut out = text.chars().take(max_length - 1).collect::<String>();
+    out.push('…');
+    out
+}
+
+/// The embeds of a single post. Discord shows embeds that share the same url as
+/// a single gallery, so every additional image gets its own embed with the post url
+fn post_embeds(post: &ProviderPost, footer: &str) -> Vec<DiscordEmbed> {
+    let mut description = post.body.clone().unwrap_or_default();
+    for video in post
+        .images
+        .iter()
+        .filter(|media| matches!(media._type, ProviderMediaType::Video))
+    {
+        description.push_str(&format!("\n[Video]({})", video.media_url));
+    }
+    let description = description.trim();
+    let mut images = post
+        .images
+        .iter()
+        .filter(|media| matches!(media._type, ProviderMediaType::Image))
+        .map(|media| DiscordImage {
+            url: media.media_url.clone(),
+        });
+    let header = DiscordEmbed {
+        url: post.url.clone(),
+        description: if description.is_empty() {
+            None
+        } else {
+            Some(truncate(description, MAX_DESCRIPTION_LENGTH))
+        },
+        timestamp: post
+            .post_date
+            .map(|date| DateTime::<Utc>::from_utc(date, Utc).to_rfc3339()),
+        author: Some(DiscordEmbedAuthor {
+            name: truncate(&post.account.name, MAX_AUTHOR_NAME_LENGTH),
+            icon_url: post.account.avatar_url.clone(),
+        }),
+        image: images.next(),
+        footer: Some(DiscordEmbedFooter {
+            text: truncate(footer, MAX_FOOTER_LENGTH),
+        }),
+    };
+    let mut embeds = vec![header];
+    embeds.extend(images.map(|image| DiscordEmbed {
+        url: post.url.clone(),
+        image: Some(image),
+        ..DiscordEmbed::default()
+    }));
+    embeds
+}
+
+/// Renders a payload into as many messages as it takes to stay within Discord's limits
+pub fn render_discord_messages(payload: &DispatchablePayload) -> Vec<DiscordPayload> {
+    let footer = payload.provider._type.to_string();
+    let mut messages: Vec<DiscordPayload> = vec![];
+    let mut embeds: Vec<DiscordEmbed> = vec![];
+    let mut characters = 0;
+    for embed in payload
+        .posts
+        .iter()
+        .flat_map(|post| post_embeds(post, &footer))
+    {
+        let embed_characters = embed.character_count();
+        let is_full = embeds.len() >= MAX_EMBEDS_PER_MESSAGE
+            || characters + embed_characters > MAX_EMBED_CHARACTERS_PER_MESSAGE;
+        if is_full && !embeds.is_empty() {
+            messages.push(DiscordPayload {
+                embeds: std::mem::take(&mut embeds),
+            });
+            characters = 0;
+        }
+        characters += embed_characters;
+        embeds.push(embed);
+    }
+    if !embeds.is_empty() {
+        messages.push(DiscordPayload { embeds });
+    }
+    messages
+}
+
+async fn rate_limit_delay(response: Response) -> Duration {
+    let header = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<f32>().ok());
+    let body = response
+        .json::<DiscordRateLimit>()
+        .await
+        .ok()
+        .map(|limit| limit.retry_after);
+    let seconds = body
+        .or(header)
+        .filter(|seconds| seconds.is_finite())
+        .unwrap_or(1f32);
+    // anything this long is given up on either way, it just can't overflow the duration
+    Duration::from_secs_f32(seconds.clamp(0f32, MAX_REPORTED_RETRY_AFTER_SECONDS))
+}
+
+async fn send_message(
+    client: &Client,
+    destination: &str,
+    message: &DiscordPayload,
+) -> Result<Response, HttpError> {
+    let mut rate_limits = 0;
+    loop {
+        let response = client
+            .post(destination)
+            .headers(request_default_headers())
+            .json(message)
+            .send()
+            .await?;
+        let status = response.status();
+        if status == StatusCode::TOO_MANY_REQUESTS && rate_limits < MAX_RATE_LIMIT_RETRIES {
+            let delay = rate_limit_delay(response).await;
+            if delay <= MAX_RETRY_AFTER {
+                debug!("Discord rate limited a webhook, retrying in {:?}", delay);
+                rate_limits += 1;
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            warn!("Discord asked to wait {:?} for a webhook, giving up", delay);
+            return Err(HttpError::FailStatus(ResponseErrorContext {
+                body: String::new(),
+                code: status,
+                message: Some(format!("Rate limited for {:?}", delay)),
+                retry_after: Some(delay),
+            }));
+        }
+        if status.is_success() {
+            return Ok(response);
+        }
+        return Err(HttpError::FailStatus(ResponseErrorContext {
+            body: response.text().await.unwrap_or_default(),
+            code: status,
+            message: None,
+            retry_after: None,
+        }));
+    }
+}
+
+/// Sends a payload to a Discord webhook as one or more messages of embeds, skipping the
+/// `delivered` messages that went through on an earlier attempt. Delivery stops at the first
+/// message that fails and `delivered` is moved past every message that didn't, so a retry
+/// only sends what's left. A payload with nothing left to send has no response to show for it
+pub async fn deliver_discord_webhook(
+    client: &Client,
+    destination: &str,
+    payload: &DispatchablePayload,
+    delivered: &mut i32,
+) -> (WebhookResponse, Duration) {
+    let instant = Instant::now();
+    let mut last_response = None;
+    let messages = render_discord_messages(payload);
+    for message in messages.iter().skip(*delivered as usize) {
+        match send_message(client, destination, message).await {
+            Ok(response) => {
+                last_response = Some(response);
+                *delivered += 1;
+            }
+            Err(err) => return (Err(err), instant.elapsed()),
+        }
+    }
+    (Ok(last_response), instant.elapsed())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::http;
+    use reqwest::header::RETRY_AFTER;
+    use reqwest::{Client, Response};
+
+    use crate::dispatcher::dispatcher::{DispatchablePayload, DispatchablePayloadProviderInfo};
+    use crate::scraper::fixtures::FixtureServer;
+    use crate::scraper::{
+        AllProviders, ProviderAccount, ProviderMedia, ProviderMediaType, ProviderPost,
+    };
+
+    use super::{
+        deliver_discord_webhook, rate_limit_delay, render_discord_messages, MAX_EMBEDS_PER_MESSAGE,
+        MAX_EMBED_CHARACTERS_PER_MESSAGE, MAX_RETRY_AFTER,
+    };
+
+    fn post(id: usize, image_count: usize, body: &str) -> ProviderPost {
+        ProviderPost {
+            account: ProviderAccount {
+                name: "드림캐쳐 Dreamcatcher".to_owned(),
+                avatar_url: Some("https://pbs.twimg.com/profile_images/1/a.jpg".to_owned()),
+            },
+            unique_identifier: id.to_string(),
+            images: (0..image_count)
+                .map(|i| ProviderMedia {
+                    _type: ProviderMediaType::Image,
+                    media_url: format!("https://pbs.twimg.com/media/{}-{}.jpg", id, i),
+                    reference_url: None,
+                    unique_identifier: format!("{}-{}", id, i),
+                    metadata: None,
+                })
+                .collect(),
+            body: Some(body.to_owned()),
+            url: Some(format!("https://twitter.com/hf_dreamcatcher/status/{}", id)),
+            post_date: None,
+            metadata: None,
+        }
+    }
+
+    fn payload(posts: Vec<ProviderPost>) -> DispatchablePayload {
+        DispatchablePayload {
+            provider: DispatchablePayloadProviderInfo {
+                _type: AllProviders::TwitterTimeline,
+                id: "729935154290925570".to_owned(),
+                ephemeral: false,
+                official: true,
+            },
+            posts,
+            metadata: None,
+        }
+    }
+
+    #[test]
+    fn images_of_a_post_are_grouped_by_url() {
+        let messages = render_discord_messages(&payload(vec![post(1, 4, "hello")]));
+        assert_eq!(messages.len(), 1);
+        let embeds = &messages[0].embeds;
+        assert_eq!(embeds.len(), 4);
+        assert!(embeds[0].author.is_some());
+        assert_eq!(embeds[0].description.as_deref(), Some("hello"));
+        assert!(embeds
+            .iter()
+            .all(|e| e.url == embeds[0].url && e.image.is_some()));
+        assert!(embeds[1..].iter().all(|e| e.author.is_none()));
+    }
+
+    #[test]
+    fn messages_are_split_on_embed_count() {
+        let posts = (0..5).map(|i| post(i, 4, "")).collect::<Vec<_>>();
+        let messages = render_discord_messages(&payload(posts));
+        assert_eq!(messages.len(), 2);
+        assert!(messages
+            .iter()
+            .all(|m| m.embeds.len() <= MAX_EMBEDS_PER_MESSAGE));
+        assert_eq!(messages.iter().map(|m| m.embeds.len()).sum::<usize>(), 20);
+    }
+
+    #[test]
+    fn messages_are_split_on_character_count() {
+        let body = "a".repeat(5000);
+        let posts = (0..3).map(|i| post(i, 1, &body)).collect::<Vec<_>>();
+        let messages = render_discord_messages(&payload(posts));
+        assert_eq!(messages.len(), 3);
+        for message in messages {
+            let characters = message
+                .embeds
+                .iter()
+                .map(|e| e.character_count())
+                .sum::<usize>();
+            assert!(characters <= MAX_EMBED_CHARACTERS_PER_MESSAGE);
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_skip_messages_that_were_delivered() {
+        let server = FixtureServer::start("discord/second_message_fails").await;
+        let destination = server
+            .upstream()
+            .url("https://discord.com/api/webhooks/1/token");
+        let posts = (0..7).map(|i| post(i, 4, "")).collect::<Vec<_>>();
+        let payload = payload(posts);
+        assert_eq!(render_discord_messages(&payload).len(), 3);
+        let client = server.provider_input().client;
+        let mut delivered = 0;
+        let (response, _) =
+            deliver_discord_webhook(&client, &destination, &payload, &mut delivered).await;
+        assert!(response.is_err());
+        assert_eq!(delivered, 1);
+        // the fixture only has the two messages that are left for the retry
+        let (response, _) =
+            deliver_discord_webhook(&client, &destination, &payload, &mut delivered).await;
+        assert!(response.unwrap().is_some());
+        assert_eq!(delivered, 3);
+        server.finish();
+    }
+
+    #[tokio::test]
+    async fn payloads_without_posts_are_delivered_without_requests() {
+        let mut delivered = 0;
+        let (response, _) = deliver_discord_webhook(
+            &Client::new(),
+            "http://127.0.0.1:9/api/webhooks/1/token",
+            &payload(vec![]),
+            &mut delivered,
+        )
+        .await;
+        assert!(response.unwrap().is_none());
+        assert_eq!(delivered, 0);
+    }
+
+    #[tokio::test]
+    async fn unusable_rate_limits_dont_panic() {
+        let response = |retry_after: &str| {
+            Response::from(
+                http::Response::builder()
+                    .status(429)
+                    .header(RETRY_AFTER, retry_after)
+                    .body("")
+                    .unwrap(),
+            )
+        };
+        assert_eq!(
+            rate_limit_delay(response("NaN")).await,
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            rate_limit_delay(response("inf")).await,
+            Duration::from_secs(1)
+        );
+        assert_eq!(
+            rate_limit_delay(response("-5")).await,
+            Duration::from_secs(0)
+        );
+        assert!(rate_limit_delay(response("1e30")).await > MAX_RETRY_AFTER);
+    }
+}
@@ -1,17 +1,23 @@
 use futures::{stream, StreamExt};
 use tokio::sync::Mutex;
 // use parking_lot::Mutex;
-use reqwest::{Client, Response};
-use serde::Serialize;
+use chrono::{NaiveDateTime, Utc};
+use reqwest::header::CONTENT_TYPE;
+use reqwest::{Client, Response, StatusCode};
+use serde::{Deserialize, Serialize};
 use std::{
-    sync::{Arc, RwLock},
-    time::Instant,
+    sync::Arc,
+    time::{Duration, Instant},
 };
 
 use crate::{
-    dispatcher::{webhook_type, WebhookDestination},
+    dispatcher::{
+        discord::deliver_discord_webhook,
+        signature::{generate_delivery_id, signature_header, DELIVERY_HEADER, SIGNATURE_HEADER},
+        webhook_type, WebhookDestination,
+    },
     models::DatabaseWebhook,
-    request::{request_default_headers, HttpError},
+    request::{request_default_headers, HttpError, ResponseErrorContext},
     scraper::{
         scraper::{Scrape, ScraperStep},
         AllProviders, ProviderPost,
@@ -24,14 +30,84 @@ pub struct WebhookDispatch {
     pub webhook: DatabaseWebhook,
 }
 
+/// How a single delivery gets identified and signed
+#[derive(Debug, Clone)]
+pub struct WebhookSigning {
+    pub delivery_id: String,
+    /// every secret the payload is signed with, deliveries are unsigned if this is empty
+    pub secrets: Vec<String>,
+}
+
+impl WebhookSigning {
+    pub fn new(
+        delivery_id: String,
+        secret: Option<String>,
+        previous_secret: Option<String>,
+        previous_secret_expires_at: Option<NaiveDateTime>,
+    ) -> Self {
+        let now = Utc::now().naive_utc();
+        // the previous secret is only used during the overlap window of a rotation
+        let previous_secret = previous_secret.filter(|_| {
+            previous_secret_expires_at
+                .map(|expiry| expiry > now)
+                .unwrap_or(false)
+        });
+        Self {
+            delivery_id,
+            secrets: secret.into_iter().chain(previous_secret).collect(),
+        }
+    }
+}
+
+impl DatabaseWebhook {
+    /// Signing information for a brand new delivery to this webhook
+    pub fn new_signing(&self) -> WebhookSigning {
+        WebhookSigning::new(
+            generate_delivery_id(),
+            self.secret.clone(),
+            self.previous_secret.clone(),
+            self.previous_secret_expires_at,
+        )
+    }
+}
+
+/// What a destination responded to a delivery with. Deliveries that had nothing left to
+/// send succeed without a response
+pub type WebhookResponse = Result<Option<Response>, HttpError>;
+
 #[derive(Debug)]
 pub struct WebhookInteraction {
     pub webhook: DatabaseWebhook,
-    pub response: Result<Response, HttpError>,
+    pub delivery_id: String,
+    /// kept around so failed deliveries can be retried
+    pub payload: DispatchablePayload,
+    /// how many of the messages the payload was split into made it to the destination
+    pub delivered_messages: i32,
+    pub response: WebhookResponse,
     pub response_time: std::time::Duration,
 }
 
-#[derive(Debug, Serialize, Clone)]
+/// The status code of a webhook response if the destination responded at all
+pub fn response_status(response: &WebhookResponse) -> Option<StatusCode> {
+    match response {
+        Ok(res) => res.as_ref().map(|res| res.status()),
+        Err(HttpError::UnexpectedBody(err)) | Err(HttpError::FailStatus(err)) => Some(err.code),
+        Err(HttpError::ReqwestError(err)) => err.status(),
+    }
+}
+
+/// A human readable reason for why a webhook delivery failed
+pub fn response_error(response: &WebhookResponse) -> Option<String> {
+    match response {
+        Ok(_) => None,
+        Err(HttpError::UnexpectedBody(err)) | Err(HttpError::FailStatus(err)) => {
+            Some(err.message.clone().unwrap_or_else(|| err.body.clone()))
+        }
+        Err(HttpError::ReqwestError(err)) => Some(err.to_string()),
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DispatchablePayloadProviderInfo {
     #[serde(rename = "type")]
     pub _type: AllProviders,
@@ -40,7 +116,7 @@ pub struct DispatchablePayloadProviderInfo {
     pub official: bool,
 }
 
-#[derive(Debug, Serialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct DispatchablePayload {
     pub provider: DispatchablePayloadProviderInfo,
     pub posts: Vec<ProviderPost>,
@@ -73,16 +149,93 @@ impl DispatchablePayload {
             metadata,
         }
     }
+    /// The same payload addressed to a destination with its own metadata
+    pub fn with_metadata(&self, metadata: Option<serde_json::Value>) -> Self {
+        DispatchablePayload {
+            metadata,
+            ..self.clone()
+        }
+    }
+}
+
+pub const WEBHOOK_DISPATCH_CONCURRENCY_LIMIT: usize = 8;
+
+/// Webhooks that take longer than this to respond are treated as failed deliveries
+const WEBHOOK_TIMEOUT_SECONDS: u64 = 15;
+
+pub fn webhook_client() -> Client {
+    Client::builder()
+        .timeout(Duration::from_secs(WEBHOOK_TIMEOUT_SECONDS))
+        .build()
+        .expect("Could not build the webhook client")
+}
+
+/// Send a single payload to a webhook. Anything other than a 2xx response is
+/// considered a failure so it can be picked up by the retry queue. `delivered` keeps
+/// track of the messages of the payload that already went through for destinations that
+/// get it in more than one
+pub async fn deliver_webhook(
+    client: &Client,
+    destination: &str,
+    payload: &DispatchablePayload,
+    signing: &WebhookSigning,
+    delivered: &mut i32,
+) -> (WebhookResponse, Duration) {
+    match webhook_type(destination) {
+        WebhookDestination::Discord => {
+            deliver_discord_webhook(client, destination, payload, delivered).await
+        }
+        WebhookDestination::Custom => {
+            deliver_custom_webhook(client, destination, payload, signing).await
+        }
+    }
 }
 
-const WEBHOOK_DISPATCH_CONCURRENCY_LIMIT: usize = 8;
+async fn deliver_custom_webhook(
+    client: &Client,
+    destination: &str,
+    payload: &DispatchablePayload,
+    signing: &WebhookSigning,
+) -> (WebhookResponse, Duration) {
+    // the signature has to be computed over the exact bytes that are sent
+    let body = serde_json::to_vec(payload).expect("Could not serialize a webhook payload");
+    let mut builder = client
+        .post(destination)
+        .headers(request_default_headers())
+        .header(CONTENT_TYPE, "application/json")
+        .header(DELIVERY_HEADER, &signing.delivery_id);
+    if !signing.secrets.is_empty() {
+        let timestamp = Utc::now().timestamp();
+        builder = builder.header(
+            SIGNATURE_HEADER,
+            signature_header(&signing.secrets, timestamp, &body),
+        );
+    }
+    let instant = Instant::now();
+    let result = builder.body(body).send().await;
+    let response_time = instant.elapsed();
+    let response = match result {
+        Err(err) => Err(HttpError::ReqwestError(err)),
+        Ok(res) if res.status().is_success() => Ok(Some(res)),
+        Ok(res) => {
+            let code = res.status();
+            Err(HttpError::FailStatus(ResponseErrorContext {
+                body: res.text().await.unwrap_or_default(),
+                code,
+                message: None,
+                retry_after: None,
+            }))
+        }
+    };
+    (response, response_time)
+}
 
-pub async fn dispatch_webhooks<'a>(
+pub async fn dispatch_webhooks(
     // provider: &dyn Provider,
     // scrape: &Scrape<'a>,
     dispatch: Vec<(DatabaseWebhook, DispatchablePayload)>,
 ) -> Vec<WebhookInteraction> {
-    let client = &Client::new();
+    let client = &webhook_client();
     // request results are not guaranteed to be in order
     let mut results: Vec<WebhookInteraction> = vec![];
 
@@ -90,23 +243,24 @@ pub async fn dispatch_webhooks<'a>(
     let iter = |(wh, payload): (DatabaseWebhook, DispatchablePayload)| {
         let f = results_lock.lock();
         async move {
-            let builder = client
-                .post(&wh.destination)
-                .headers(request_default_headers());
-            let instant = Instant::now();
-            if let WebhookDestination::Custom = webhook_type(&wh.destination) {
-                let response = builder
-                    .json(&payload)
-                    .send()
-                    .await
-                    .map_err(HttpError::ReqwestError);
-                let response_time = instant.elapsed();
-                f.await.push(WebhookInteraction {
-                    webhook: wh,
-                    response,
-                    response_time,
-                });
-            }
+            let signing = wh.new_signing();
+            let mut delivered_messages = 0;
+            let (response, response_time) = deliver_webhook(
+                client,
+                &wh.destination,
+                &payload,
+                &signing,
+                &mut delivered_messages,
+            )
+            .await;
+            f.await.push(WebhookInteraction {
+                webhook: wh,
+                delivery_id: signing.delivery_id,
+                payload,
+                delivered_messages,
+                response,
+                response_time,
+            });
         }
     };
 
@@ -1,11 +1,13 @@
 use self::discord::is_discord_webhook_url;
 
 pub mod amqp;
-mod discord;
+pub mod discord;
 pub mod dispatcher;
+pub mod outbox;
+pub mod retry;
+pub mod signature;
 
 pub enum WebhookDestination {
-    #[deprecated]
     Discord,
     Custom,
 }
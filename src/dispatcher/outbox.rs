@@ -0,0 +1,241 @@
+use std::env;
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info, trace, warn};
+use sqlx::{Postgres, Transaction};
+
+use crate::db::{amqp_metadata, submit_webhook_responses, webhooks_for_provider, Database};
+use crate::dispatcher::amqp::AMQPDispatcher;
+use crate::dispatcher::dispatcher::{dispatch_webhooks, DispatchablePayload};
+use crate::scraper::ScopedProvider;
+use crate::shutdown::Shutdown;
+
+/// How often the outbox is checked for payloads that haven't been dispatched yet
+const OUTBOX_POLL_INTERVAL_SECONDS: u64 = if cfg!(debug_assertions) { 1 } else { 5 };
+
+const OUTBOX_BATCH_SIZE: i64 = 20;
+
+/// How long a worker has to dispatch the entries it claimed before they're handed to another
+/// one. Discord rate limits can keep a batch busy for a while
+const OUTBOX_CLAIM_SECONDS: f64 = 600.0;
+
+struct OutboxEntry {
+    id: i32,
+    scrape_id: i32,
+    payload: serde_json::Value,
+}
+
+/// Sends a committed payload to every webhook and AMQP source that's listening
+/// to its provider. The entry is only marked as dispatched after everything has been
+/// attempted, so a crash in between means it gets sent again (at-least-once)
+async fn dispatch_entry(
+    db: &Database,
+    amqp: &Option<AMQPDispatcher>,
+    entry: OutboxEntry,
+) -> anyhow::Result<()> {
+    let payload = serde_json::from_value::<DispatchablePayload>(entry.payload)?;
+    let sp = ScopedProvider {
+        name: payload.provider._type,
+        destination: payload.provider.id.clone(),
+        official: payload.provider.official,
+    };
+    // we don't really care about the interactions in amqp since we have full
+    // control of that environment anyways
+    if let Some(amqp) = amqp {
+        if let Ok(Some(amqp_d)) = amqp_metadata(db, &sp).await {
+            trace!("Publishing AMQP message for {}", sp);
+            amqp.publish(&payload.with_metadata(amqp_d.metadata)).await;
+        }
+    }
+    let dispatch = webhooks_for_provider(db, &sp)
+        .await?
+        .into_iter()
+        .map(|wh| {
+            let payload = payload.with_metadata(wh.metadata.clone());
+            (wh, payload)
+        })
+        .collect::<Vec<_>>();
+    if !dispatch.is_empty() {
+        let interactions = dispatch_webhooks(dispatch).await;
+        submit_webhook_responses(db, entry.scrape_id, interactions).await?;
+    }
+    sqlx::query!(
+        "UPDATE dispatch_outbox SET dispatched_at = NOW() WHERE id = $1",
+        entry.id
+    )
+    .execute(db)
+    .await?;
+    debug!("Dispatched outbox entry {} for {}", entry.id, sp);
+    Ok(())
+}
+
+/// Claims entries that haven't been dispatched yet. Entries other workers are in the middle of
+/// claiming are skipped instead of waited on, so every entry is only sent out by one of them
+async fn claim_outbox_entries(
+    tx: &mut Transaction<'_, Postgres>,
+    limit: i64,
+) -> anyhow::Result<Vec<OutboxEntry>> {
+    let mut entries = sqlx::query_as!(
+        OutboxEntry,
+        "UPDATE dispatch_outbox
+        SET claimed_until = NOW() + $2 * interval '1 second'
+        WHERE id IN (
+            SELECT id FROM dispatch_outbox
+            WHERE dispatched_at IS NULL AND (claimed_until IS NULL OR claimed_until < NOW())
+            ORDER BY id
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING id, scrape_id, payload",
+        limit,
+        OUTBOX_CLAIM_SECONDS
+    )
+    .fetch_all(tx)
+    .await?;
+    entries.sort_by_key(|entry| entry.id);
+    Ok(entries)
+}
+
+/// Returns how many entries were dispatched
+async fn drain_outbox(db: &Database, amqp: &Option<AMQPDispatcher>) -> anyhow::Result<usize> {
+    let mut tx = db.begin().await?;
+    let entries = claim_outbox_entries(&mut tx, OUTBOX_BATCH_SIZE).await?;
+    tx.commit().await?;
+    let mut dispatched = 0;
+    for entry in entries {
+        let id = entry.id;
+        match dispatch_entry(db, amqp, entry).await {
+            Ok(()) => dispatched += 1,
+            Err(err) => {
+                error!("Failed to dispatch outbox entry {}", id);
+                error!("{:?}", err);
+                // entries that fail are picked up again on the next poll
+                let released = sqlx::query!(
+                    "UPDATE dispatch_outbox SET claimed_until = NULL WHERE id = $1",
+                    id
+                )
+                .execute(db)
+                .await;
+                if let Err(err) = released {
+                    error!("{:?}", err);
+                }
+            }
+        }
+    }
+    Ok(dispatched)
+}
+
+async fn drain_until_stopped(
+    db: &Database,
+    amqp: &Option<AMQPDispatcher>,
+    stop: impl Future<Output = ()>,
+) {
+    tokio::pin!(stop);
+    loop {
+        if let Err(err) = drain_outbox(db, amqp).await {
+            error!("{:?}", err);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(OUTBOX_POLL_INTERVAL_SECONDS)) => {}
+            _ = &mut stop => break,
+        }
+    }
+    info!("Flushing the dispatch outbox");
+    loop {
+        match drain_outbox(db, amqp).await {
+            // a full batch means there could be more, entries that failed are left for the
+            // next process instead of being retried until they work
+            Ok(dispatched) if dispatched == OUTBOX_BATCH_SIZE as usize => {}
+            Ok(_) => break,
+            Err(err) => {
+                error!("{:?}", err);
+                break;
+            }
+        }
+    }
+}
+
+/// Drains payloads of committed scrapes to webhooks and AMQP until `stop` resolves, then
+/// sends out whatever is left over before returning. Nothing is sent out past the shutdown
+/// deadline, entries that were cut off stay in the outbox and are picked up by another
+/// process once their claim runs out
+pub async fn dispatch_outbox_loop(
+    db: Arc<Database>,
+    stop: impl Future<Output = ()>,
+    shutdown: Shutdown,
+) {
+    let amqp = match env::var("AMQP_URL") {
+        Ok(url) => Some(
+            AMQPDispatcher::from_connection_string(&url)
+                .await
+                .expect("Could not connect to AMQP"),
+        ),
+        Err(_) => None,
+    };
+    info!("Starting the dispatch outbox");
+    tokio::select! {
+        _ = drain_until_stopped(&db, &amqp, stop) => {}
+        _ = shutdown.deadline() => warn!("Leaving undispatched entries in the outbox"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::db::test_database;
+
+    use super::claim_outbox_entries;
+
+    #[tokio::test]
+    async fn concurrent_drainers_claim_different_entries() {
+        // needs a database to claim from
+        let db = match test_database().await {
+            Some(db) => db,
+            None => return,
+        };
+        let scrape = sqlx::query!("INSERT INTO scrape DEFAULT VALUES RETURNING id")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        let mut inserted = HashSet::new();
+        for _ in 0..10 {
+            let row = sqlx::query!(
+                "INSERT INTO dispatch_outbox (scrape_id, provider_name, provider_destination, payload)
+                VALUES ($1, 'test', 'test', '{}') RETURNING id",
+                scrape.id
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            inserted.insert(row.id);
+        }
+        // claims are rolled back at the end so entries the test didn't insert are left alone
+        let (mut first_tx, mut second_tx) = (db.begin().await.unwrap(), db.begin().await.unwrap());
+        let (first, second) = tokio::join!(
+            claim_outbox_entries(&mut first_tx, 1000),
+            claim_outbox_entries(&mut second_tx, 1000)
+        );
+        let claimed = |entries: Vec<super::OutboxEntry>| {
+            entries
+                .into_iter()
+                .map(|entry| entry.id)
+                .filter(|id| inserted.contains(id))
+                .collect::<HashSet<_>>()
+        };
+        let (first, second) = (claimed(first.unwrap()), claimed(second.unwrap()));
+        let third = claimed(claim_outbox_entries(&mut first_tx, 1000).await.unwrap());
+        first_tx.rollback().await.unwrap();
+        second_tx.rollback().await.unwrap();
+        sqlx::query!("DELETE FROM scrape WHERE id = $1", scrape.id)
+            .execute(&db)
+            .await
+            .unwrap();
+        assert!(first.is_disjoint(&second));
+        assert_eq!(first.len() + second.len(), inserted.len());
+        // claimed entries aren't handed out again until their claim runs out
+        assert!(third.is_empty());
+    }
+}
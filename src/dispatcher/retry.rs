@@ -0,0 +1,333 @@
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use futures::{stream, StreamExt};
+use log::{debug, error, info, warn};
+use rand::Rng;
+use reqwest::Client;
+use sqlx::{Postgres, Transaction};
+
+use crate::db::{record_webhook_invocation, Database};
+use crate::dispatcher::dispatcher::{
+    deliver_webhook, response_error, response_status, webhook_client, DispatchablePayload,
+    WebhookSigning, WEBHOOK_DISPATCH_CONCURRENCY_LIMIT,
+};
+use crate::shutdown::Shutdown;
+
+/// How often the retry queue is checked for deliveries that are due
+const RETRY_POLL_INTERVAL_SECONDS: u64 = if cfg!(debug_assertions) { 5 } else { 30 };
+
+/// The maximum number of deliveries that are retried in a single poll
+const RETRY_BATCH_SIZE: i64 = 50;
+
+const BASE_RETRY_DELAY_SECONDS: u64 = 30;
+
+/// Retries are never pushed back further than 6 hours
+const MAX_RETRY_DELAY_SECONDS: u64 = 60 * 60 * 6;
+
+const DEFAULT_MAX_DELIVERY_ATTEMPTS: i32 = 8;
+
+/// Claimed deliveries are pushed back this far while they're being retried, so other workers
+/// don't pick them up too. Deliveries of a worker that died are retried once it runs out
+const RETRY_CLAIM_SECONDS: f64 = 600.0;
+
+/// The number of attempts a webhook gets before it's moved to the dead letter table.
+/// Can be overridden with the `WEBHOOK_MAX_ATTEMPTS` environment variable
+pub fn max_delivery_attempts() -> i32 {
+    env::var("WEBHOOK_MAX_ATTEMPTS")
+        .ok()
+        .and_then(|attempts| attempts.parse::<i32>().ok())
+        .filter(|attempts| *attempts > 0)
+        .unwrap_or(DEFAULT_MAX_DELIVERY_ATTEMPTS)
+}
+
+/// Exponential backoff for the delivery following the given number of failed attempts.
+/// Half of the delay is random so deliveries that failed together don't all get retried
+/// at the same time
+pub fn retry_delay(failed_attempts: i32) -> Duration {
+    let exponent = failed_attempts.clamp(1, 16) as u32 - 1;
+    let ceiling = BASE_RETRY_DELAY_SECONDS
+        .saturating_mul(2u64.pow(exponent))
+        .min(MAX_RETRY_DELAY_SECONDS);
+    let jitter = rand::thread_rng().gen_range(0..=ceiling / 2);
+    Duration::from_secs(ceiling - ceiling / 2 + jitter)
+}
+
+struct PendingDelivery {
+    id: i32,
+    webhook_id: i32,
+    scrape_id: Option<i32>,
+    delivery_id: String,
+    payload: serde_json::Value,
+    attempts: i32,
+    delivered_messages: i32,
+    destination: String,
+    secret: Option<String>,
+    previous_secret: Option<String>,
+    previous_secret_expires_at: Option<NaiveDateTime>,
+}
+
+async fn retry_delivery(
+    db: &Database,
+    client: &Client,
+    delivery: PendingDelivery,
+    max_attempts: i32,
+) -> anyhow::Result<()> {
+    // secrets are looked up again on every attempt in case they were rotated in the meantime
+    let signing = WebhookSigning::new(
+        delivery.delivery_id.clone(),
+        delivery.secret.clone(),
+        delivery.previous_secret.clone(),
+        delivery.previous_secret_expires_at,
+    );
+    let payload = serde_json::from_value::<DispatchablePayload>(delivery.payload)?;
+    let mut delivered_messages = delivery.delivered_messages;
+    let (response, response_time) = deliver_webhook(
+        client,
+        &delivery.destination,
+        &payload,
+        &signing,
+        &mut delivered_messages,
+    )
+    .await;
+    let attempt = delivery.attempts + 1;
+    let mut tx = db.begin().await?;
+    record_webhook_invocation(
+        &mut tx,
+        delivery.scrape_id,
+        delivery.webhook_id,
+        &delivery.delivery_id,
+        attempt,
+        &response,
+        response_time,
+    )
+    .await?;
+    let last_response_code = response_status(&response).map(|code| code.as_u16() as i32);
+    let last_error = response_error(&response);
+    if response.is_ok() {
+        debug!(
+            "Delivered webhook to {} after {} attempts",
+            delivery.destination, attempt
+        );
+        sqlx::query!("DELETE FROM webhook_delivery WHERE id = $1", delivery.id)
+            .execute(&mut tx)
+            .await?;
+    } else if attempt >= max_attempts {
+        warn!(
+            "Giving up on delivering webhook to {} after {} attempts",
+            delivery.destination, attempt
+        );
+        sqlx::query!(
+            "INSERT INTO webhook_dead_letter (
+                webhook_id,
+                scrape_id,
+                delivery_id,
+                payload,
+                attempts,
+                last_response_code,
+                last_error,
+                delivered_messages,
+                created_at
+            ) SELECT webhook_id, scrape_id, delivery_id, payload, $2, $3, $4, $5, created_at
+            FROM webhook_delivery WHERE id = $1",
+            delivery.id,
+            attempt,
+            last_response_code,
+            last_error,
+            delivered_messages
+        )
+        .execute(&mut tx)
+        .await?;
+        sqlx::query!("DELETE FROM webhook_delivery WHERE id = $1", delivery.id)
+            .execute(&mut tx)
+            .await?;
+    } else {
+        let next_attempt_at =
+            Utc::now().naive_utc() + chrono::Duration::from_std(retry_delay(attempt))?;
+        sqlx::query!(
+            "UPDATE webhook_delivery
+            SET
+                attempts = $2,
+                last_response_code = $3,
+                last_error = $4,
+                next_attempt_at = $5,
+                delivered_messages = $6
+            WHERE id = $1",
+            delivery.id,
+            attempt,
+            last_response_code,
+            last_error,
+            next_attempt_at,
+            delivered_messages
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+/// Claims deliveries that are due by pushing their next attempt back. Deliveries other workers
+/// are in the middle of claiming are skipped instead of waited on
+async fn claim_due_deliveries(
+    tx: &mut Transaction<'_, Postgres>,
+    limit: i64,
+) -> anyhow::Result<Vec<PendingDelivery>> {
+    let due = sqlx::query_as!(
+        PendingDelivery,
+        "UPDATE webhook_delivery d
+        SET next_attempt_at = NOW() + $2 * interval '1 second'
+        FROM webhook w
+        WHERE w.id = d.webhook_id AND d.id IN (
+            SELECT id FROM webhook_delivery
+            WHERE next_attempt_at <= NOW()
+            ORDER BY next_attempt_at
+            LIMIT $1
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING d.id,
+            d.webhook_id,
+            d.scrape_id,
+            d.delivery_id,
+            d.payload,
+            d.attempts,
+            d.delivered_messages,
+            w.destination,
+            w.secret,
+            w.previous_secret,
+            w.previous_secret_expires_at",
+        limit,
+        RETRY_CLAIM_SECONDS
+    )
+    .fetch_all(tx)
+    .await?;
+    Ok(due)
+}
+
+async fn retry_due_deliveries(db: &Database, client: &Client) -> anyhow::Result<()> {
+    let mut tx = db.begin().await?;
+    let due = claim_due_deliveries(&mut tx, RETRY_BATCH_SIZE).await?;
+    tx.commit().await?;
+    if due.is_empty() {
+        return Ok(());
+    }
+    info!("Retrying {} failed webhook deliveries", due.len());
+    let max_attempts = max_delivery_attempts();
+    stream::iter(due)
+        .for_each_concurrent(WEBHOOK_DISPATCH_CONCURRENCY_LIMIT, |delivery| async move {
+            if let Err(err) = retry_delivery(db, client, delivery, max_attempts).await {
+                error!("{:?}", err);
+            }
+        })
+        .await;
+    Ok(())
+}
+
+async fn retry_until_stopped(db: &Database, client: &Client, shutdown: &Shutdown) {
+    while !shutdown.is_triggered() {
+        if let Err(err) = retry_due_deliveries(db, client).await {
+            error!("{:?}", err);
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(RETRY_POLL_INTERVAL_SECONDS)) => {}
+            _ = shutdown.triggered() => {}
+        }
+    }
+}
+
+/// Periodically redelivers webhooks that failed in the past until they either
+/// succeed or run out of attempts. Retries that are due when the process shuts down are
+/// left for the next one, and retries still running at the shutdown deadline are cut off
+/// and picked up again once their claim runs out
+pub async fn webhook_retry_loop(db: Arc<Database>, shutdown: Shutdown) {
+    let client = webhook_client();
+    tokio::select! {
+        _ = retry_until_stopped(&db, &client, &shutdown) => {}
+        _ = shutdown.deadline() => warn!("Cutting off webhook retries that didn't finish in time"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::time::Duration;
+
+    use crate::db::test_database;
+
+    use super::{
+        claim_due_deliveries, retry_delay, BASE_RETRY_DELAY_SECONDS, MAX_RETRY_DELAY_SECONDS,
+    };
+
+    #[test]
+    fn retry_delay_grows_exponentially() {
+        for _ in 0..50 {
+            let first = retry_delay(1);
+            assert!(first >= Duration::from_secs(BASE_RETRY_DELAY_SECONDS / 2));
+            assert!(first <= Duration::from_secs(BASE_RETRY_DELAY_SECONDS));
+
+            let fourth = retry_delay(4);
+            assert!(fourth >= Duration::from_secs(BASE_RETRY_DELAY_SECONDS * 4));
+            assert!(fourth <= Duration::from_secs(BASE_RETRY_DELAY_SECONDS * 8));
+        }
+    }
+
+    #[test]
+    fn retry_delay_is_capped() {
+        for attempts in [20, 100, i32::MAX] {
+            assert!(retry_delay(attempts) <= Duration::from_secs(MAX_RETRY_DELAY_SECONDS));
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_retriers_claim_different_deliveries() {
+        // needs a database to claim from
+        let db = match test_database().await {
+            Some(db) => db,
+            None => return,
+        };
+        let webhook = sqlx::query!(
+            "INSERT INTO webhook (destination) VALUES ('https://retry-claims.example.com/' || gen_random_uuid())
+            RETURNING id"
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let mut inserted = HashSet::new();
+        for _ in 0..10 {
+            let row = sqlx::query!(
+                "INSERT INTO webhook_delivery (webhook_id, payload) VALUES ($1, '{}') RETURNING id",
+                webhook.id
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            inserted.insert(row.id);
+        }
+        let claimed = |deliveries: Vec<super::PendingDelivery>| {
+            deliveries
+                .into_iter()
+                .map(|delivery| delivery.id)
+                .filter(|id| inserted.contains(id))
+                .collect::<HashSet<_>>()
+        };
+        // claims are rolled back at the end so deliveries the test didn't insert are left alone
+        let (mut first_tx, mut second_tx) = (db.begin().await.unwrap(), db.begin().await.unwrap());
+        let (first, second) = tokio::join!(
+            claim_due_deliveries(&mut first_tx, 1000),
+            claim_due_deliveries(&mut second_tx, 1000)
+        );
+        let (first, second) = (claimed(first.unwrap()), claimed(second.unwrap()));
+        let third = claimed(claim_due_deliveries(&mut first_tx, 1000).await.unwrap());
+        first_tx.rollback().await.unwrap();
+        second_tx.rollback().await.unwrap();
+        sqlx::query!("DELETE FROM webhook WHERE id = $1", webhook.id)
+            .execute(&db)
+            .await
+            .unwrap();
+        assert!(first.is_disjoint(&second));
+        assert_eq!(first.len() + second.len(), inserted.len());
+        assert!(third.is_empty());
+    }
+}
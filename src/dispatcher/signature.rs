@@ -0,0 +1,191 @@
+//! Webhook signatures so receivers can verify that a delivery came from Jiu.
+//!
+//! Every signed delivery carries a `X-Jiu-Signature` header that looks like
+//! `t=1642870800,v1=5257a869e7ecebeda32affa62cdca3fa51cad7e77a0e56ff536d0ce8e108d8bd`
+//! where `v1` is the hex encoded HMAC-SHA256 of `{t}.{body}` using the webhook's secret.
+//! While a secret is being rotated the header contains one `v1` entry per active secret.
+use std::time::Duration;
+
+use hmac::{Hmac, Mac, NewMac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::Sha256;
+use thiserror::Error;
+
+pub const SIGNATURE_HEADER: &str = "x-jiu-signature";
+pub const DELIVERY_HEADER: &str = "x-jiu-delivery";
+
+/// Signatures older than this are rejected by default to prevent replays
+pub const DEFAULT_SIGNATURE_TOLERANCE: Duration = Duration::from_secs(60 * 5);
+
+const SIGNATURE_SCHEME: &str = "v1";
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum SignatureError {
+    #[error("Signature header is malformed")]
+    MalformedHeader,
+    #[error("Signature timestamp is outside of the tolerance window")]
+    ExpiredTimestamp,
+    #[error("No signature matched any of the given secrets")]
+    NoMatchingSignature,
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    OsRng.fill_bytes(&mut buf);
+    hex::encode(buf)
+}
+
+/// A new random secret for signing webhook payloads
+pub fn generate_secret() -> String {
+    random_hex(32)
+}
+
+/// A unique id for a delivery that stays the same across retries so receivers
+/// can deduplicate
+pub fn generate_delivery_id() -> String {
+    random_hex(16)
+}
+
+fn mac(secret: &str, timestamp: i64, body: &[u8]) -> HmacSha256 {
+    // HMAC accepts keys of any length
+    let mut mac = HmacSha256::new_varkey(secret.as_bytes()).unwrap();
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    mac
+}
+
+/// The hex encoded signature of a payload for a single secret
+pub fn sign(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    hex::encode(mac(secret, timestamp, body).finalize().into_bytes())
+}
+
+/// The value of the signature header for a payload signed with every active secret
+pub fn signature_header<S: AsRef<str>>(secrets: &[S], timestamp: i64, body: &[u8]) -> String {
+    let mut header = format!("t={}", timestamp);
+    for secret in secrets {
+        header.push_str(&format!(
+            ",{}={}",
+            SIGNATURE_SCHEME,
+            sign(secret.as_ref(), timestamp, body)
+        ));
+    }
+    header
+}
+
+/// Verify the signature header of a delivery against the raw request body.
+/// Any of the receiver's secrets can match, which allows accepting both the old
+/// and new secret while one is being rotated.
+/// `now` is the current unix timestamp in seconds.
+pub fn verify_signature<S: AsRef<str>>(
+    header: &str,
+    body: &[u8],
+    secrets: &[S],
+    tolerance: Duration,
+    now: i64,
+) -> Result<(), SignatureError> {
+    let mut timestamp: Option<i64> = None;
+    let mut signatures: Vec<Vec<u8>> = vec![];
+    for part in header.split(',') {
+        let (key, value) = part
+            .trim()
+            .split_once('=')
+            .ok_or(SignatureError::MalformedHeader)?;
+        match key {
+            "t" => {
+                timestamp = Some(
+                    value
+                        .parse::<i64>()
+                        .map_err(|_| SignatureError::MalformedHeader)?,
+                )
+            }
+            SIGNATURE_SCHEME => {
+                signatures.push(hex::decode(value).map_err(|_| SignatureError::MalformedHeader)?)
+            }
+            // unknown schemes are ignored so new ones can be added later
+            _ => {}
+        }
+    }
+    let timestamp = timestamp.ok_or(SignatureError::MalformedHeader)?;
+    if signatures.is_empty() {
+        return Err(SignatureError::MalformedHeader);
+    }
+    if (now - timestamp).unsigned_abs() > tolerance.as_secs() {
+        return Err(SignatureError::ExpiredTimestamp);
+    }
+    let matched = secrets.iter().any(|secret| {
+        signatures.iter().any(|signature| {
+            // constant time comparison
+            mac(secret.as_ref(), timestamp, body)
+                .verify(signature)
+                .is_ok()
+        })
+    });
+    if matched {
+        Ok(())
+    } else {
+        Err(SignatureError::NoMatchingSignature)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        signature_header, verify_signature, SignatureError, DEFAULT_SIGNATURE_TOLERANCE,
+    };
+
+    const BODY: &[u8] = br#"{"provider":{"type":"TwitterTimeline"}}"#;
+    const NOW: i64 = 1642870800;
+
+    #[test]
+    fn signatures_round_trip() {
+        let header = signature_header(&["secret"], NOW, BODY);
+        assert_eq!(
+            verify_signature(&header, BODY, &["secret"], DEFAULT_SIGNATURE_TOLERANCE, NOW),
+            Ok(())
+        );
+        assert_eq!(
+            verify_signature(&header, b"{}", &["secret"], DEFAULT_SIGNATURE_TOLERANCE, NOW),
+            Err(SignatureError::NoMatchingSignature)
+        );
+        assert_eq!(
+            verify_signature(&header, BODY, &["other"], DEFAULT_SIGNATURE_TOLERANCE, NOW),
+            Err(SignatureError::NoMatchingSignature)
+        );
+    }
+
+    #[test]
+    fn rotated_secrets_are_accepted_during_overlap() {
+        let header = signature_header(&["new", "old"], NOW, BODY);
+        for secrets in [["new"], ["old"]] {
+            assert_eq!(
+                verify_signature(&header, BODY, &secrets, DEFAULT_SIGNATURE_TOLERANCE, NOW),
+                Ok(())
+            );
+        }
+        let header = signature_header(&["new"], NOW, BODY);
+        assert_eq!(
+            verify_signature(&header, BODY, &["old", "new"], DEFAULT_SIGNATURE_TOLERANCE, NOW),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn stale_and_malformed_signatures_are_rejected() {
+        let header = signature_header(&["secret"], NOW, BODY);
+        let later = NOW + DEFAULT_SIGNATURE_TOLERANCE.as_secs() as i64 + 1;
+        assert_eq!(
+            verify_signature(&header, BODY, &["secret"], DEFAULT_SIGNATURE_TOLERANCE, later),
+            Err(SignatureError::ExpiredTimestamp)
+        );
+        for header in ["", "t=abc,v1=00", "v1=00", "t=1642870800", "t=1642870800,v1=zz"] {
+            assert_eq!(
+                verify_signature(header, BODY, &["secret"], DEFAULT_SIGNATURE_TOLERANCE, NOW),
+                Err(SignatureError::MalformedHeader)
+            );
+        }
+    }
+}
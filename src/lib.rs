@@ -1,10 +1,14 @@
+#![allow(clippy::module_inception)]
+
 pub mod db;
 pub mod dispatcher;
 pub mod models;
 pub mod request;
+pub mod roles;
 pub mod scheduler;
 pub mod scraper;
 pub mod server;
+pub mod shutdown;
 pub mod api;
 pub use dotenv::dotenv;
 pub use std::env;
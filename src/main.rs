@@ -1,85 +1,69 @@
-use std::env;
-use std::{error::Error, sync::Arc, time::Duration};
+use std::collections::HashMap;
+use std::{sync::Arc, time::Duration};
 
 use dotenv::dotenv;
-use futures::future::join_all;
-use log::{debug, error, info, trace};
+use futures::future;
+use log::{debug, error, info, trace, warn};
+use parking_lot::Mutex;
 use reqwest::Client;
 use sqlx::{Pool, Postgres};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 
-use jiu::dispatcher::amqp::AMQPDispatcher;
-use jiu::dispatcher::dispatcher::{dispatch_webhooks, DispatchablePayload};
+use jiu::dispatcher::dispatcher::DispatchablePayload;
+use jiu::dispatcher::outbox::dispatch_outbox_loop;
+use jiu::dispatcher::retry::webhook_retry_loop;
+use jiu::roles::{Role, Roles};
 use jiu::server::run_server;
+use jiu::shutdown::Shutdown;
 use jiu::{
     db::*,
     models::PendingProvider,
-    scheduler::*,
-    scraper::{get_provider_map, scraper::scrape, Provider, ProviderMap, ScrapeRequestInput},
+    scheduler::{queue::*, *},
+    scraper::{
+        credentials::CredentialStore,
+        get_provider_map,
+        refresh::token_refresh_loop,
+        scraper::{scrape, scrape_input},
+        Provider, ProviderMap,
+    },
 };
 
+/// How long scrapes that were cut off at the shutdown deadline get to be put back in the queue
+const SHUTDOWN_GRACE_SECONDS: u64 = 5;
+
 struct Context {
     db: Arc<Pool<Postgres>>,
-    amqp: Arc<Option<AMQPDispatcher>>,
-    client: Arc<Client>,
     provider_map: Arc<ProviderMap>,
+    credential_store: Option<Arc<CredentialStore>>,
+    /// the id this process holds scrape leases under
+    worker: String,
 }
 
 async fn iter(
     ctx: Arc<Context>,
     pending: &PendingProvider,
     provider: &dyn Provider,
-) -> anyhow::Result<()> {
+) -> anyhow::Result<ProcessedScrape> {
     let sp = pending.provider.clone();
-    let latest_data = latest_media_ids_from_provider(&ctx.db, &sp).await?;
-    // there must be at least ONE data found if the scrape isn't the first ever one
-    let is_first_scrape = latest_data.is_empty();
-    if is_first_scrape {
-        trace!(
-            "Scraping {}: {} for the first time ever",
-            sp.name.to_string(),
-            sp.destination
-        )
-    }
-    let step = ScrapeRequestInput {
-        latest_data,
-        is_first_scrape,
-        default_name: pending.default_name.clone(),
-        last_scrape: pending.last_scrape,
-    };
-    let mut result = scrape(&sp, &*provider, &step).await?;
+    let step = scrape_input(&ctx.db, pending).await?;
+    let result = scrape(&sp, provider, &step, ctx.credential_store.as_deref()).await?;
 
-    let webhooks = webhooks_for_provider(&ctx.db, &sp).await?;
-    let webhook_interactions = if result.discovered_new_images() {
-        let dispatch = webhooks
-            .into_iter()
-            .map(|wh| {
-                let payload = DispatchablePayload::new(&*provider, &result, wh.metadata.clone());
-                (wh, payload)
-            })
-            .collect::<Vec<_>>();
-        // we don't really care about the interactions in amqp since we have full
-        // control of that environment anyways
-        if let Some(amqp) = &*ctx.amqp {
-            if let Ok(Some(amqp_d)) = amqp_metadata(&*ctx.db, &sp).await {
-                let payload = DispatchablePayload::new(&*provider, &result, amqp_d.metadata);
-                trace!("Publishing AMQP message for {}", &provider.id().to_string());
-                amqp.publish(&payload).await;
-            }
-        }
-        Some(dispatch_webhooks(dispatch).await)
+    // the payload is dispatched by the outbox once the scrape is committed
+    let payload = if result.discovered_new_images() {
+        Some(DispatchablePayload::new(provider, &result, None))
     } else {
         None
     };
-    // process scraping MUST come after dispatcher dispatching since it mutates the array by reversing it
-    let processed_scrape = process_scrape(&ctx.db, &mut result, pending).await?;
-    if webhook_interactions.is_some() {
-        submit_webhook_responses(&ctx.db, processed_scrape, webhook_interactions.unwrap()).await?
-    }
-    Ok(())
+    process_scrape(&ctx.db, &result, pending, payload.as_ref()).await
 }
 
+/// Plans scrapes for resources that earned them and writes them to the queue for workers to pick up
 async fn job_loop(ctx: Arc<Context>) {
     let arc_db = Arc::clone(&ctx.db);
+    if let Err(err) = accrue_tokens(&arc_db).await {
+        error!("{:?}", err);
+    }
     trace!("Getting pending scrapes");
     let pendings = match pending_scrapes(&arc_db).await {
         Err(error) => {
@@ -94,94 +78,242 @@ async fn job_loop(ctx: Arc<Context>) {
         // Could end up spamming a provider if it's stuck at a high value
         error!("{:?}", err);
     };
-    trace!("Preparing to scrape {} pending providers", pendings.len());
+    if pendings.is_empty() {
+        return;
+    }
+    trace!("Queueing {} pending providers", pendings.len());
+    if let Err(err) = enqueue_scrapes(&arc_db, &pendings).await {
+        error!("{:?}", err);
+    }
+}
 
-    let this_scrape = pendings.iter().map(Arc::new).map(|pending| async {
-        let pp = pending;
-        let sleep_time = pp.scrape_date;
-        tokio::time::sleep(sleep_time).await;
-        if let Err(err) = run(Arc::clone(&ctx), &pp, &ctx.provider_map).await {
+async fn run_scheduled(ctx: Arc<Context>, claimed: ClaimedScrape) {
+    let pp = &claimed.pending;
+    // blackout windows can change after a scrape is planned, and busy workers fall behind
+    let now = chrono::Utc::now().naive_utc();
+    if claimed.blackouts.contains(now) {
+        let until = claimed.blackouts.next_allowed(now);
+        info!("Postponing {} until {} for its blackout window", pp, until);
+        if let Err(err) = postpone_scheduled_scrape(&ctx.db, &ctx.worker, claimed.id, until).await {
+            error!("Could not postpone scheduled scrape {}", claimed.id);
             error!("{:?}", err);
-            return;
         }
-        debug!("Finished scraping {}", pp.provider.name.to_string());
-    });
-    join_all(this_scrape).await;
+        return;
+    }
+    let result = match run(Arc::clone(&ctx), pp, &ctx.provider_map).await {
+        Ok(processed) => {
+            complete_scheduled_scrape(&ctx.db, &ctx.worker, claimed.id, processed.scrape_id).await
+        }
+        Err(err) => {
+            error!("{:?}", err);
+            fail_scheduled_scrape(&ctx.db, &ctx.worker, claimed.id, &err.to_string()).await
+        }
+    };
+    if let Err(err) = result {
+        error!("Could not update scheduled scrape {}", claimed.id);
+        error!("{:?}", err);
+    }
+    debug!("Finished scraping {}", pp.provider.name.to_string());
+}
+
+/// Keeps the leases of running scrapes alive for as long as this process is
+async fn heartbeat_loop(ctx: Arc<Context>) {
+    loop {
+        if let Err(err) = renew_leases(&ctx.db, &ctx.worker).await {
+            error!("Could not renew scrape leases");
+            error!("{:?}", err);
+        }
+        tokio::time::sleep(Duration::from_secs(HEARTBEAT_INTERVAL_SECONDS)).await;
+    }
+}
+
+/// Picks up scrapes from the queue as they become due. Once the process is shutting down
+/// nothing new is claimed and the scrapes that are still running are given until the
+/// deadline to finish, the ones that don't are stopped and put back in the queue
+async fn worker_loop(ctx: Arc<Context>, shutdown: Shutdown) {
+    info!("Starting worker {}", ctx.worker);
+    let heartbeat = tokio::spawn(heartbeat_loop(Arc::clone(&ctx)));
+    let running: Arc<Mutex<HashMap<i32, JoinHandle<()>>>> = Arc::default();
+    // every scrape holds on to a sender, so the receiver only closes once all of them are done
+    let (in_flight, mut drained) = mpsc::channel::<()>(1);
+    while !shutdown.is_triggered() {
+        if let Err(err) = expire_leases(&ctx.db).await {
+            error!("{:?}", err);
+        }
+        match claim_due_scrapes(&ctx.db, &ctx.worker, WORKER_BATCH_SIZE).await {
+            Ok(claimed) => {
+                for scrape in claimed {
+                    let id = scrape.id;
+                    let ctx = Arc::clone(&ctx);
+                    let done = Arc::clone(&running);
+                    let in_flight = in_flight.clone();
+                    let mut running = running.lock();
+                    running.insert(
+                        id,
+                        tokio::spawn(async move {
+                            run_scheduled(ctx, scrape).await;
+                            done.lock().remove(&id);
+                            drop(in_flight);
+                        }),
+                    );
+                }
+            }
+            Err(err) => {
+                error!("Could not claim scheduled scrapes");
+                error!("{:?}", err);
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(WORKER_POLL_INTERVAL_SECONDS)) => {}
+            _ = shutdown.triggered() => {}
+        }
+    }
+    drop(in_flight);
+    let count = running.lock().len();
+    if count > 0 {
+        info!("Waiting for {} running scrapes to finish", count);
+    }
+    let finished = tokio::select! {
+        _ = drained.recv() => true,
+        _ = shutdown.deadline() => false,
+    };
+    if !finished {
+        let unfinished = running.lock().drain().collect::<Vec<_>>();
+        warn!(
+            "Putting {} scrapes that didn't finish in time back in the queue",
+            unfinished.len()
+        );
+        let now = chrono::Utc::now().naive_utc();
+        for (id, handle) in unfinished {
+            // anything the scrape was in the middle of saving is rolled back with it
+            handle.abort();
+            if let Err(err) = postpone_scheduled_scrape(&ctx.db, &ctx.worker, id, now).await {
+                error!("Could not requeue scheduled scrape {}", id);
+                error!("{:?}", err);
+            }
+        }
+    }
+    heartbeat.abort();
+}
+
+async fn planner_loop(ctx: Arc<Context>, shutdown: Shutdown) {
+    info!("Planning scrapes every {}ms", PLANNER_INTERVAL_MILLISECONDS);
+    while !shutdown.is_triggered() {
+        job_loop(Arc::clone(&ctx)).await;
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_millis(PLANNER_INTERVAL_MILLISECONDS)) => {}
+            _ = shutdown.triggered() => {}
+        }
+    }
 }
 
 async fn run(
     ctx: Arc<Context>,
     pp: &PendingProvider,
     provider_map: &ProviderMap,
-) -> Result<(), Box<dyn Error + Send>> {
+) -> anyhow::Result<ProcessedScrape> {
     let provider = provider_map.get(&pp.provider.name).unwrap_or_else(|| {
         panic!(
             "Tried to get a provider that doesn't exist {}",
             &pp.provider,
         )
     });
-    if let Err(err) = iter(Arc::clone(&ctx), pp, &**provider).await {
-        eprintln!("{:?}", err);
-    }
-    Ok(())
+    iter(Arc::clone(&ctx), pp, &**provider).await
 }
 
 async fn setup() -> anyhow::Result<()> {
-    info!("Starting JiU");
+    let roles = Roles::from_env();
+    info!("Starting JiU as {}", roles);
     let client = Arc::new(Client::new());
+    let credential_store = CredentialStore::from_env(Arc::new(connect().await?)).map(Arc::new);
     let provider_map = Arc::new(
-        get_provider_map(&Arc::clone(&client))
+        get_provider_map(&Arc::clone(&client), credential_store.as_deref())
             .await
             .expect("Could not successfully initialize a provider map"),
     );
-    let pm = Arc::clone(&provider_map);
-    tokio::spawn(async move {
-        match connect().await {
-            Ok(db) => run_server(Arc::new(db), pm, 8080).await,
-            Err(err) => {
-                error!("{:?}", err)
-            }
+    if roles.has(Role::Server) || roles.has(Role::Worker) {
+        // workers and scrapes asked for through the API make requests to the same providers
+        // as other processes
+        let db = Arc::new(connect().await?);
+        for provider in provider_map.values() {
+            provider.limiter().share(Arc::clone(&db), provider.id());
         }
-    });
-    loop {
-        let client = Arc::clone(&client);
-        let provider_map = Arc::clone(&provider_map);
-        info!("Starting job loop {}", SCHEDULER_END_MILLISECONDS);
-        let data = match env::var("NO_WORKER") {
-            Ok(_) => {
-                info!("Not starting worker because 'NO_WORKER' environment was set");
-                tokio::task::spawn(async {})
+    }
+    let shutdown = Shutdown::listen();
+    let mut running = Vec::new();
+    if roles.has(Role::Server) {
+        let pm = Arc::clone(&provider_map);
+        let store = credential_store.clone();
+        let shutdown = shutdown.clone();
+        running.push(tokio::spawn(async move {
+            match connect().await {
+                Ok(db) => run_server(Arc::new(db), pm, store, 8080, shutdown).await,
+                Err(err) => {
+                    error!("{:?}", err)
+                }
             }
-            _ => tokio::task::spawn(async move {
-                let db = match connect().await {
-                    Err(err) => {
-                        error!("{:?}", err);
-                        return;
-                    }
-                    Ok(db) => db,
-                };
-                let db = Arc::new(db);
-                let amqp = Arc::new(match env::var("AMQP_URL") {
-                    Ok(a) => Some(AMQPDispatcher::from_connection_string(&a).await.unwrap()),
-                    Err(_) => None,
-                });
-                let ctx = Arc::new(Context {
-                    db: Arc::clone(&db),
-                    amqp: Arc::clone(&amqp),
-                    client: Arc::clone(&client),
-                    provider_map,
-                });
-                info!("Starting requests for the day...");
-                job_loop(ctx).await;
-                info!("Requests finished for the day...");
-            }),
-        };
-        let delay = tokio::time::sleep(Duration::from_millis(SCHEDULER_END_MILLISECONDS));
-        if let (_, Err(join_err)) = tokio::join!(delay, data) {
-            println!("{:?}", join_err)
-        }
-        info!("Finished job loop");
+        }));
     }
+    if roles.has(Role::Worker) {
+        tokio::spawn(token_refresh_loop(
+            Arc::clone(&provider_map),
+            credential_store.clone(),
+        ));
+        let ctx = Arc::new(Context {
+            db: Arc::new(connect().await?),
+            provider_map: Arc::clone(&provider_map),
+            credential_store: credential_store.clone(),
+            worker: worker_id(),
+        });
+        let worker = tokio::spawn(worker_loop(ctx, shutdown.clone()));
+        let shutdown = shutdown.clone();
+        running.push(tokio::spawn(async move {
+            match connect().await {
+                Ok(db) => {
+                    let db = Arc::new(db);
+                    // payloads of the scrapes that were running when the process started
+                    // shutting down are sent out once they're all done
+                    let scrapes_done = async {
+                        drop(worker.await);
+                    };
+                    tokio::join!(
+                        dispatch_outbox_loop(Arc::clone(&db), scrapes_done, shutdown.clone()),
+                        webhook_retry_loop(db, shutdown)
+                    );
+                }
+                Err(err) => {
+                    error!("{:?}", err)
+                }
+            }
+        }));
+    }
+    if roles.has(Role::Planner) {
+        let ctx = Arc::new(Context {
+            db: Arc::new(connect().await?),
+            provider_map,
+            credential_store,
+            worker: worker_id(),
+        });
+        running.push(tokio::spawn(planner_loop(ctx, shutdown.clone())));
+    } else {
+        info!("Not planning scrapes because this process isn't a planner");
+    }
+    shutdown.triggered().await;
+    info!(
+        "Giving running work {}s to finish",
+        shutdown.timeout().as_secs()
+    );
+    // everything stops by the deadline on its own, scrapes that were cut off just need a
+    // moment to be put back in the queue
+    let stopped = async {
+        shutdown.deadline().await;
+        tokio::time::sleep(Duration::from_secs(SHUTDOWN_GRACE_SECONDS)).await;
+    };
+    tokio::select! {
+        _ = future::join_all(running) => {}
+        _ = stopped => warn!("Exiting without waiting for work that didn't stop in time"),
+    }
+    Ok(())
 }
 
 #[tokio::main]
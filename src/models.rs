@@ -2,7 +2,6 @@ use crate::{scheduler::Priority, scraper::ScopedProvider};
 use chrono::NaiveDateTime;
 use serde::Serialize;
 use std::fmt::Display;
-use std::time::Duration;
 
 #[derive(Debug)]
 pub struct AMQPDestination {
@@ -16,6 +15,9 @@ pub struct DatabaseWebhook {
     pub destination: String,
     pub created_at: NaiveDateTime,
     pub updated_at: NaiveDateTime,
+    pub secret: Option<String>,
+    pub previous_secret: Option<String>,
+    pub previous_secret_expires_at: Option<NaiveDateTime>,
     pub metadata: Option<serde_json::Value>,
 }
 
@@ -54,11 +56,12 @@ pub struct ScrapeHistory {
 
 pub struct PendingProvider {
     pub id: i32,
-    /// the name that is used if a more relevant name for posts cannot be found 
+    /// the name that is used if a more relevant name for posts cannot be found
     pub default_name: Option<String>,
     pub priority: Priority,
     pub provider: ScopedProvider,
-    pub scrape_date: Duration,
+    /// when the scrape is planned to happen
+    pub scheduled_for: NaiveDateTime,
     pub last_scrape: Option<NaiveDateTime>,
 }
 
@@ -1,9 +1,11 @@
 use std::env;
 use std::iter::FromIterator;
+use std::time::Duration;
 
+use chrono::{DateTime, Utc};
 use log::error;
 use reqwest;
-use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue, RETRY_AFTER};
 use reqwest::{Response, StatusCode};
 use serde::de::DeserializeOwned;
 use thiserror::Error;
@@ -13,6 +15,48 @@ pub struct ResponseErrorContext {
     pub body: String,
     pub code: StatusCode,
     pub message: Option<String>,
+    /// how long the server asked us to wait before trying again
+    pub retry_after: Option<Duration>,
+}
+
+/// Reads the `Retry-After` header which is either a number of seconds or an HTTP date
+pub fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
+
+/// Where provider requests are sent. Requests go straight to the provider unless they are
+/// redirected somewhere like a fixture server, which gets the original host as the first
+/// segment of the path: `https://api.twitter.com/2/...` becomes `{redirect}/api.twitter.com/2/...`
+#[derive(Debug, Clone, Default)]
+pub struct Upstream {
+    redirect: Option<String>,
+}
+
+impl Upstream {
+    pub fn redirect(to: &str) -> Self {
+        Self {
+            redirect: Some(to.trim_end_matches('/').to_owned()),
+        }
+    }
+
+    pub fn url(&self, url: &str) -> String {
+        match &self.redirect {
+            None => url.to_owned(),
+            Some(redirect) => {
+                let without_scheme = url.split_once("://").map_or(url, |(_, rest)| rest);
+                format!("{}/{}", redirect, without_scheme)
+            }
+        }
+    }
 }
 
 /// Wrapper for providing actual useful information about
@@ -28,11 +72,10 @@ pub enum HttpError {
     ReqwestError(#[from] reqwest::Error),
 }
 
-pub async fn parse_successful_response<T: DeserializeOwned>(
-    response: Response,
-) -> Result<T, HttpError> {
+/// The body of a response, failing with the body as context if it wasn't successful
+pub async fn successful_response_text(response: Response) -> Result<String, HttpError> {
     let response_code = response.status();
-    let url = response.url().clone();
+    let retry_after = retry_after(response.headers());
     let response_body = response.text().await?;
     if !response_code.is_success() {
         // sadly shitty reqwest doesn't give us the response body as
@@ -41,8 +84,18 @@ pub async fn parse_successful_response<T: DeserializeOwned>(
             body: response_body,
             code: response_code,
             message: None,
+            retry_after,
         }));
     }
+    Ok(response_body)
+}
+
+pub async fn parse_successful_response<T: DeserializeOwned>(
+    response: Response,
+) -> Result<T, HttpError> {
+    let response_code = response.status();
+    let url = response.url().clone();
+    let response_body = successful_response_text(response).await?;
     serde_json::from_str::<T>(&response_body).map_err(|error| {
         error!("{:?}", error);
         error!("Failed to parse response from {}", url);
@@ -50,6 +103,7 @@ pub async fn parse_successful_response<T: DeserializeOwned>(
             body: response_body,
             code: response_code,
             message: Some(error.to_string()),
+            retry_after: None,
         })
     })
 }
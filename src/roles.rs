@@ -0,0 +1,91 @@
+//! What a Jiu process is responsible for. Roles are picked with the `ROLES` environment
+//! variable as a comma separated list like `server,worker`, and every role is taken on
+//! when it isn't set. Any number of servers and workers can share a database, the
+//! planner should usually only be running in one place.
+use std::collections::HashSet;
+use std::env;
+use std::str::FromStr;
+
+use log::warn;
+use strum::IntoEnumIterator;
+use strum_macros::{Display, EnumIter, EnumString};
+
+#[derive(Display, Debug, Hash, Copy, Clone, EnumString, EnumIter, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+pub enum Role {
+    /// Serves the HTTP API
+    Server,
+    /// Runs queued scrapes, dispatches webhooks and keeps provider tokens fresh
+    Worker,
+    /// Plans scrapes as resources earn them and updates priorities
+    Planner,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Roles(HashSet<Role>);
+
+impl Roles {
+    pub fn all() -> Self {
+        Self(Role::iter().collect())
+    }
+
+    /// Parses a comma separated list of roles, ignoring the ones it doesn't know about
+    pub fn parse(input: &str) -> Self {
+        let roles = input
+            .split(',')
+            .map(str::trim)
+            .filter(|role| !role.is_empty())
+            .filter_map(|role| match Role::from_str(role) {
+                Ok(role) => Some(role),
+                Err(_) => {
+                    warn!("Ignoring unknown role {}", role);
+                    None
+                }
+            })
+            .collect();
+        Self(roles)
+    }
+
+    /// `NO_WORKER` predates roles and is still treated as running only the server
+    pub fn from_env() -> Self {
+        match env::var("ROLES") {
+            Ok(roles) => Self::parse(&roles),
+            Err(_) if env::var("NO_WORKER").is_ok() => Self::parse("server"),
+            Err(_) => Self::all(),
+        }
+    }
+
+    pub fn has(&self, role: Role) -> bool {
+        self.0.contains(&role)
+    }
+}
+
+impl std::fmt::Display for Roles {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = Role::iter()
+            .filter(|role| self.has(*role))
+            .map(|role| role.to_string())
+            .collect::<Vec<_>>();
+        f.write_str(&names.join(","))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Role, Roles};
+
+    #[test]
+    fn roles_are_parsed() {
+        let roles = Roles::parse(" worker, planner ,,");
+        assert!(roles.has(Role::Worker));
+        assert!(roles.has(Role::Planner));
+        assert!(!roles.has(Role::Server));
+        assert_eq!(roles.to_string(), "worker,planner");
+    }
+
+    #[test]
+    fn unknown_roles_are_ignored() {
+        assert_eq!(Roles::parse("server,scraper"), Roles::parse("server"));
+        assert_eq!(Roles::all().to_string(), "server,worker,planner");
+    }
+}
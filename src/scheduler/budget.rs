@@ -0,0 +1,249 @@
+//! Caps the number of requests made to a provider in a (UTC) day. Tokens only limit how
+//! often each resource is scraped, but every scrape can go through several pages, so
+//! providers with lots of resources can end up making far more requests than expected.
+//!
+//! Budgets are set with `REQUEST_BUDGET_<PROVIDER>`. The planner leaves out the lowest
+//! priority scrapes that wouldn't fit in what's left of the day's budget, and scrapes stop
+//! paginating once it runs out.
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::str::FromStr;
+
+use chrono::{Duration, NaiveDateTime};
+use log::{info, warn};
+
+use crate::db::Database;
+use crate::models::PendingProvider;
+use crate::scheduler::env_name;
+use crate::scraper::AllProviders;
+
+/// The environment variable that sets a provider's budget,
+/// `REQUEST_BUDGET_TWITTER_TIMELINE` for `twitter.timeline`
+pub fn budget_variable(provider: AllProviders) -> String {
+    env_name(provider, "REQUEST_BUDGET")
+}
+
+/// The number of requests a provider can make every day, if it's limited
+pub fn configured_budget(provider: AllProviders) -> Option<u32> {
+    let variable = budget_variable(provider);
+    let value = env::var(&variable).ok()?;
+    let budget = value.trim().parse::<u32>().ok();
+    if budget.is_none() {
+        warn!("Ignoring invalid request budget {} in {}", value, variable);
+    }
+    budget
+}
+
+/// Counts a request towards the provider's usage of the day. Returns false without counting
+/// anything when the request would go over the budget
+pub async fn spend_request(
+    db: &Database,
+    provider: AllProviders,
+    budget: Option<u32>,
+) -> anyhow::Result<bool> {
+    if budget == Some(0) {
+        return Ok(false);
+    }
+    let row = sqlx::query!(
+        "INSERT INTO provider_request_usage AS u (provider_name, day, requests)
+        VALUES ($1, (NOW() AT TIME ZONE 'UTC')::date, 1)
+        ON CONFLICT (provider_name, day) DO UPDATE
+        SET requests = u.requests + 1
+        WHERE $2::int IS NULL OR u.requests < $2
+        RETURNING requests",
+        provider.to_string(),
+        budget.map(|budget| budget as i32)
+    )
+    .fetch_optional(db)
+    .await?;
+    Ok(row.is_some())
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BudgetUsage {
+    /// requests made today
+    pub used: i64,
+    /// scrapes waiting in the queue for later today
+    pub queued: i64,
+    /// how many pages scrapes of the provider usually go through
+    pub requests_per_scrape: f32,
+}
+
+impl BudgetUsage {
+    /// How many more scrapes fit in what's left of the budget
+    pub fn allowance(&self, budget: u32) -> usize {
+        let per_scrape = self.requests_per_scrape.max(1f32);
+        let remaining = budget as f32 - self.used as f32 - self.queued as f32 * per_scrape;
+        (remaining / per_scrape).floor().max(0f32) as usize
+    }
+}
+
+/// Today's usage of every provider that has enabled resources
+pub async fn budget_usage(db: &Database) -> anyhow::Result<HashMap<AllProviders, BudgetUsage>> {
+    let rows = sqlx::query!(
+        r#"SELECT
+            names.name as "name!",
+            COALESCE((
+                SELECT u.requests FROM provider_request_usage u
+                WHERE u.provider_name = names.name AND u.day = (NOW() AT TIME ZONE 'UTC')::date
+            ), 0)::bigint as "used!",
+            (
+                SELECT COUNT(*) FROM scheduled_scrape ss
+                JOIN provider_resource queued ON queued.id = ss.provider_resource_id
+                WHERE queued.name = names.name AND ss.status = 'pending'
+                AND ss.scheduled_for < date_trunc('day', NOW() AT TIME ZONE 'UTC') + interval '1 day'
+            ) as "queued!",
+            (
+                SELECT COUNT(sr.id)::float8 / GREATEST(COUNT(DISTINCT s.id), 1)
+                FROM scrape s
+                JOIN scrape_request sr ON sr.scrape_id = s.id
+                WHERE s.provider_name = names.name AND s.scraped_at > NOW() - interval '7 days'
+            ) as "requests_per_scrape!"
+        FROM (SELECT DISTINCT name FROM provider_resource WHERE enabled) names"#
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let provider = AllProviders::from_str(&row.name).ok()?;
+            let usage = BudgetUsage {
+                used: row.used,
+                queued: row.queued,
+                requests_per_scrape: row.requests_per_scrape as f32,
+            };
+            Some((provider, usage))
+        })
+        .collect())
+}
+
+/// When the budget day `now` falls in runs out, budgets start over at midnight UTC
+pub fn budget_day_end(now: NaiveDateTime) -> NaiveDateTime {
+    (now.date() + Duration::days(1)).and_hms(0, 0, 0)
+}
+
+/// Leaves out the scrapes of every provider that don't fit in its allowance, starting from
+/// the lowest priorities. Resources with a fixed interval are kept over everything else.
+/// Allowances are what's left of the current budget day, so scrapes planned for after
+/// `day_end` don't count against them
+pub fn plan_within_budget(
+    planned: Vec<PendingProvider>,
+    allowances: &HashMap<AllProviders, usize>,
+    pinned: &HashSet<i32>,
+    day_end: NaiveDateTime,
+) -> Vec<PendingProvider> {
+    let mut dropped = HashSet::new();
+    for (provider, allowance) in allowances {
+        let mut candidates = planned
+            .iter()
+            .enumerate()
+            .filter(|(_, pending)| {
+                pending.provider.name == *provider && pending.scheduled_for < day_end
+            })
+            .collect::<Vec<_>>();
+        if candidates.len() <= *allowance {
+            continue;
+        }
+        candidates.sort_by(|(_, a), (_, b)| {
+            pinned
+                .contains(&b.id)
+                .cmp(&pinned.contains(&a.id))
+                .then_with(|| b.priority.level.cmp(&a.priority.level))
+        });
+        info!(
+            "Leaving out {} scrapes of {} to stay within its request budget",
+            candidates.len() - allowance,
+            provider
+        );
+        dropped.extend(candidates[*allowance..].iter().map(|(index, _)| *index));
+    }
+    planned
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| !dropped.contains(index))
+        .map(|(_, pending)| pending)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use chrono::NaiveDateTime;
+
+    use crate::{
+        models::PendingProvider,
+        scheduler::Priority,
+        scraper::{AllProviders, ScopedProvider},
+    };
+
+    use super::{budget_day_end, plan_within_budget, BudgetUsage};
+
+    fn pending(id: i32, name: AllProviders, priority: f32) -> PendingProvider {
+        PendingProvider {
+            id,
+            default_name: None,
+            priority: Priority::unchecked_clamp(priority),
+            provider: ScopedProvider {
+                name,
+                destination: id.to_string(),
+                official: false,
+            },
+            scheduled_for: NaiveDateTime::from_timestamp(0, 0),
+            last_scrape: None,
+        }
+    }
+
+    #[test]
+    fn allowances_account_for_pagination() {
+        let usage = BudgetUsage {
+            used: 40,
+            queued: 10,
+            requests_per_scrape: 2.0,
+        };
+        // 100 - 40 used - 20 for the queued scrapes leaves room for 20 more scrapes
+        assert_eq!(usage.allowance(100), 20);
+        assert_eq!(usage.allowance(50), 0);
+    }
+
+    #[test]
+    fn lowest_priorities_are_dropped_first() {
+        let planned = vec![
+            pending(1, AllProviders::TwitterTimeline, 0.5),
+            pending(2, AllProviders::TwitterTimeline, 1.5),
+            pending(3, AllProviders::PinterestBoardFeed, 0.1),
+            pending(4, AllProviders::TwitterTimeline, 0.1),
+            pending(5, AllProviders::TwitterTimeline, 1.0),
+        ];
+        let allowances = HashMap::from([(AllProviders::TwitterTimeline, 2)]);
+        let pinned = HashSet::from([4]);
+        let day_end = budget_day_end(NaiveDateTime::from_timestamp(0, 0));
+        let kept = plan_within_budget(planned, &allowances, &pinned, day_end)
+            .into_iter()
+            .map(|pending| pending.id)
+            .collect::<Vec<_>>();
+        assert_eq!(kept, vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn scrapes_after_the_budget_day_are_left_alone() {
+        let day_end = budget_day_end(NaiveDateTime::from_timestamp(0, 0));
+        assert_eq!(day_end, NaiveDateTime::from_timestamp(60 * 60 * 24, 0));
+        let tomorrow = |mut pending: PendingProvider| {
+            pending.scheduled_for = day_end;
+            pending
+        };
+        let planned = vec![
+            pending(1, AllProviders::TwitterTimeline, 0.5),
+            tomorrow(pending(2, AllProviders::TwitterTimeline, 1.5)),
+            pending(3, AllProviders::TwitterTimeline, 1.0),
+            tomorrow(pending(4, AllProviders::TwitterTimeline, 0.1)),
+        ];
+        let allowances = HashMap::from([(AllProviders::TwitterTimeline, 1)]);
+        let kept = plan_within_budget(planned, &allowances, &HashSet::new(), day_end)
+            .into_iter()
+            .map(|pending| pending.id)
+            .collect::<Vec<_>>();
+        assert_eq!(kept, vec![2, 3, 4]);
+    }
+}
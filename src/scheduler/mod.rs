@@ -4,6 +4,21 @@ pub mod scheduler;
 pub use scheduler::*;
 pub mod rate_limiter;
 pub use rate_limiter::*;
+pub mod budget;
+pub mod overrides;
+pub mod posting;
+pub mod queue;
+pub mod simulation;
+pub mod strategy;
+
+use crate::scraper::AllProviders;
 
 const MIN_PRIORITY: f32 = 0.07;
 const MAX_PRIORITY: f32 = 1.75;
+
+/// The environment variable that configures something for a single provider,
+/// `RATE_LIMIT_TWITTER_TIMELINE` for `twitter.timeline` with the `RATE_LIMIT` prefix
+pub fn env_name(provider: AllProviders, prefix: &str) -> String {
+    let name = provider.to_string().to_uppercase().replace('.', "_");
+    format!("{}_{}", prefix, name)
+}
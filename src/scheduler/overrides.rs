@@ -0,0 +1,168 @@
+//! Settings on `provider_resource` that take precedence over what the scheduler would do on
+//! its own, for resources that need a guaranteed cadence or should stay away from some
+//! hours of the day.
+use std::fmt::{self, Display};
+
+use chrono::{Duration, NaiveDateTime, NaiveTime};
+use log::warn;
+use sqlx::types::BigDecimal;
+
+use crate::scheduler::Priority;
+
+/// A time of day range a resource is never scraped in, in UTC. Windows that end before
+/// they start go over midnight
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct BlackoutWindow {
+    pub start: NaiveTime,
+    pub end: NaiveTime,
+}
+
+impl BlackoutWindow {
+    fn parse(input: &str) -> Option<Self> {
+        let (start, end) = input.split_once('-')?;
+        let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?;
+        let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?;
+        Some(Self { start, end })
+    }
+
+    /// When the window containing `at` ends, if `at` is in it
+    fn end_after(&self, at: NaiveDateTime) -> Option<NaiveDateTime> {
+        let time = at.time();
+        let date = at.date();
+        if self.start <= self.end {
+            (self.start <= time && time < self.end).then(|| date.and_time(self.end))
+        } else if time >= self.start {
+            Some((date + Duration::days(1)).and_time(self.end))
+        } else if time < self.end {
+            Some(date.and_time(self.end))
+        } else {
+            None
+        }
+    }
+}
+
+impl Display for BlackoutWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}-{}",
+            self.start.format("%H:%M"),
+            self.end.format("%H:%M")
+        )
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Blackouts(Vec<BlackoutWindow>);
+
+impl Blackouts {
+    /// Parses a comma separated list of windows like `02:00-04:30,23:00-00:15`, ignoring the
+    /// ones it can't make sense of
+    pub fn parse(input: &str) -> Self {
+        let windows = input
+            .split(',')
+            .map(str::trim)
+            .filter(|window| !window.is_empty())
+            .filter_map(|window| {
+                let parsed = BlackoutWindow::parse(window);
+                if parsed.is_none() {
+                    warn!("Ignoring invalid blackout window {}", window);
+                }
+                parsed
+            })
+            .collect();
+        Self(windows)
+    }
+
+    pub fn from_column(column: Option<&str>) -> Self {
+        column.map(Self::parse).unwrap_or_default()
+    }
+
+    pub fn windows(&self) -> &[BlackoutWindow] {
+        &self.0
+    }
+
+    pub fn contains(&self, at: NaiveDateTime) -> bool {
+        self.0.iter().any(|window| window.end_after(at).is_some())
+    }
+
+    /// The first time at or after `at` that isn't in any of the windows. Windows that cover
+    /// the whole day can't be honored and are ignored
+    pub fn next_allowed(&self, at: NaiveDateTime) -> NaiveDateTime {
+        let mut candidate = at;
+        // every window can push the time forward at most once before it runs out of windows
+        for _ in 0..=self.0.len() {
+            match self.0.iter().find_map(|window| window.end_after(candidate)) {
+                Some(end) => candidate = end,
+                None => return candidate,
+            }
+        }
+        warn!("Blackout windows cover the entire day, ignoring them");
+        at
+    }
+}
+
+/// Keeps a priority within the bounds a resource was given
+pub fn clamp_priority(
+    priority: Priority,
+    min: Option<&BigDecimal>,
+    max: Option<&BigDecimal>,
+) -> Priority {
+    let mut level = priority.level;
+    if let Some(min) = min {
+        level = level.max(min.clone());
+    }
+    if let Some(max) = max {
+        level = level.min(max.clone());
+    }
+    Priority { level }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{NaiveDate, NaiveDateTime};
+    use num_traits::FromPrimitive;
+    use sqlx::types::BigDecimal;
+
+    use crate::scheduler::Priority;
+
+    use super::{clamp_priority, Blackouts};
+
+    fn at(day: u32, hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2022, 2, day).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn scrapes_wait_for_blackouts_to_end() {
+        let blackouts = Blackouts::parse("02:00-04:30, 23:00-00:15,nonsense");
+        assert_eq!(blackouts.windows().len(), 2);
+        assert_eq!(blackouts.next_allowed(at(1, 1, 0)), at(1, 1, 0));
+        assert_eq!(blackouts.next_allowed(at(1, 3, 0)), at(1, 4, 30));
+        // windows going over midnight
+        assert_eq!(blackouts.next_allowed(at(1, 23, 30)), at(2, 0, 15));
+        assert_eq!(blackouts.next_allowed(at(2, 0, 10)), at(2, 0, 15));
+        assert!(!blackouts.contains(at(1, 4, 30)));
+
+        // back to back windows are skipped together
+        let chained = Blackouts::parse("01:00-02:00,02:00-03:00");
+        assert_eq!(chained.next_allowed(at(1, 1, 30)), at(1, 3, 0));
+
+        let always = Blackouts::parse("00:00-12:00,12:00-00:00");
+        assert_eq!(always.next_allowed(at(1, 5, 0)), at(1, 5, 0));
+    }
+
+    #[test]
+    fn priorities_stay_within_bounds() {
+        let level = |n: f32| BigDecimal::from_f32(n).unwrap();
+        let priority = Priority::unchecked_clamp(1.5);
+        assert_eq!(
+            clamp_priority(priority.clone(), None, Some(&level(1.0))).level,
+            level(1.0)
+        );
+        assert_eq!(
+            clamp_priority(priority.clone(), Some(&level(1.6)), None).level,
+            level(1.6)
+        );
+        assert_eq!(clamp_priority(priority.clone(), None, None), priority);
+    }
+}
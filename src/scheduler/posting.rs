@@ -0,0 +1,125 @@
+//! Learns the hours of the day resources tend to post at from `media.posted_at`, so their
+//! scrapes can be placed shortly after they're likely to have posted something instead of
+//! at whatever time a slot happened to be free.
+use std::collections::HashMap;
+
+use chrono::{Duration, NaiveDateTime, Timelike};
+
+use crate::db::Database;
+
+/// How far back posts are looked at, older habits don't say much about the current ones
+const HISTORY_DAYS: f64 = 60.0;
+
+/// Resources with fewer posts than this don't have a pattern worth following
+const MIN_POSTS: i64 = 10;
+
+/// An hour is a likely posting window when it has at least this many times its fair share
+/// of the posts of a resource
+const LIKELY_WINDOW_SHARE: f32 = 2.0;
+
+/// How long after the end of a posting window the scrape happens, giving posts that went
+/// up late in the hour a moment to show up
+const WINDOW_DELAY_MINUTES: i64 = 10;
+
+/// The share of a resource's posts made in every hour of the day (in UTC)
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostingHours([f32; 24]);
+
+impl PostingHours {
+    /// Builds the distribution from the number of posts made in each hour
+    pub fn from_counts(counts: &[(u32, i64)]) -> Option<Self> {
+        let total: i64 = counts.iter().map(|(_, count)| count).sum();
+        if total < MIN_POSTS {
+            return None;
+        }
+        let mut shares = [0f32; 24];
+        for (hour, count) in counts {
+            shares[*hour as usize % 24] += *count as f32 / total as f32;
+        }
+        Some(Self(shares))
+    }
+
+    fn is_likely(&self, hour: u32) -> bool {
+        self.0[hour as usize % 24] >= LIKELY_WINDOW_SHARE / 24.0
+    }
+
+    /// The first time within `horizon` after `after` that follows the end of a likely posting
+    /// window, if there is one
+    pub fn next_window(&self, after: NaiveDateTime, horizon: Duration) -> Option<NaiveDateTime> {
+        // windows that ended shortly before `after` can still be scraped after it
+        let first_window = after.date().and_hms(after.hour(), 0, 0) - Duration::hours(2);
+        (0..=horizon.num_hours() + 3)
+            .map(|offset| first_window + Duration::hours(offset))
+            .filter(|window| self.is_likely(window.hour()))
+            .map(|window| window + Duration::hours(1) + Duration::minutes(WINDOW_DELAY_MINUTES))
+            .find(|scrape| *scrape >= after && *scrape <= after + horizon)
+    }
+}
+
+/// The posting hours of every resource that posts often enough to have them
+pub async fn posting_hours(
+    db: &Database,
+    resource_ids: &[i32],
+) -> anyhow::Result<HashMap<i32, PostingHours>> {
+    let rows = sqlx::query!(
+        r#"SELECT
+            pr.id,
+            EXTRACT(HOUR FROM m.posted_at)::int as "hour!",
+            -- images of the same post share a post date
+            COUNT(DISTINCT m.posted_at) as "posts!"
+        FROM provider_resource pr
+        JOIN media m ON m.provider_name = pr.name AND m.provider_destination = pr.destination
+        WHERE pr.id = ANY($1)
+          AND m.posted_at IS NOT NULL
+          AND m.posted_at > NOW() - $2 * interval '1 day'
+        GROUP BY pr.id, 2"#,
+        resource_ids,
+        HISTORY_DAYS
+    )
+    .fetch_all(db)
+    .await?;
+    let mut counts: HashMap<i32, Vec<(u32, i64)>> = HashMap::new();
+    for row in rows {
+        counts
+            .entry(row.id)
+            .or_default()
+            .push((row.hour as u32, row.posts));
+    }
+    Ok(counts
+        .into_iter()
+        .filter_map(|(id, counts)| Some((id, PostingHours::from_counts(&counts)?)))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, NaiveDate, NaiveDateTime};
+
+    use super::PostingHours;
+
+    fn at(hour: u32, minute: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd(2022, 2, 14).and_hms(hour, minute, 0)
+    }
+
+    #[test]
+    fn rare_posters_have_no_pattern() {
+        assert_eq!(PostingHours::from_counts(&[(9, 3), (18, 2)]), None);
+    }
+
+    #[test]
+    fn scrapes_follow_posting_windows() {
+        // posts mostly at 9 and 18 with a few stragglers
+        let hours = PostingHours::from_counts(&[(9, 20), (18, 15), (3, 1), (12, 1)]).unwrap();
+        let day = Duration::hours(24);
+        assert_eq!(hours.next_window(at(6, 30), day), Some(at(10, 10)));
+        // in the middle of the window, it's scraped once the hour is over
+        assert_eq!(hours.next_window(at(9, 30), day), Some(at(10, 10)));
+        assert_eq!(hours.next_window(at(10, 20), day), Some(at(19, 10)));
+        assert_eq!(
+            hours.next_window(at(20, 0), day),
+            Some(at(10, 10) + Duration::days(1))
+        );
+        // nothing is worth waiting this long for
+        assert_eq!(hours.next_window(at(10, 20), Duration::hours(2)), None);
+    }
+}
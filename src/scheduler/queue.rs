@@ -0,0 +1,456 @@
+//! Scrapes are planned ahead of time into the `scheduled_scrape` table with absolute
+//! timestamps. Workers claim rows as they become due and record how each one ended,
+//! so a restart picks the plan back up instead of throwing away the rest of the day.
+//!
+//! Any number of workers can share the queue. Claimed rows are leased to the worker that
+//! claimed them, which keeps extending the lease while it's alive. Rows whose lease ran
+//! out belong to a worker that died and go back to the queue for someone else.
+use std::env;
+use std::str::FromStr;
+
+use chrono::NaiveDateTime;
+use log::{info, warn};
+use rand::Rng;
+
+use crate::db::Database;
+use crate::models::PendingProvider;
+use crate::scheduler::{overrides::Blackouts, Priority};
+use crate::scraper::{AllProviders, ScopedProvider};
+
+/// How often workers look for scrapes that are due
+pub const WORKER_POLL_INTERVAL_SECONDS: u64 = if cfg!(debug_assertions) { 1 } else { 5 };
+
+/// The maximum number of scrapes a worker claims in a single poll
+pub const WORKER_BATCH_SIZE: i64 = 20;
+
+/// How long a claimed scrape belongs to a worker without hearing back from it
+const LEASE_SECONDS: f64 = 120.0;
+
+/// How often workers extend the leases of the scrapes they're running
+pub const HEARTBEAT_INTERVAL_SECONDS: u64 = 30;
+
+/// Key of the advisory lock held while a plan is written, so two planners running at
+/// the same time don't queue the same resources twice
+const PLAN_LOCK: i64 = 0x6a6975;
+
+/// Identifies this process in the leases it holds. Can be set with `WORKER_ID`, a random
+/// suffix is always added so a restarted process never mistakes old leases for its own
+pub fn worker_id() -> String {
+    let name = env::var("WORKER_ID")
+        .or_else(|_| env::var("HOSTNAME"))
+        .unwrap_or_else(|_| "jiu".to_owned());
+    let suffix: u32 = rand::thread_rng().gen();
+    format!("{}-{}-{:08x}", name, std::process::id(), suffix)
+}
+
+/// A planned scrape a worker has started
+#[derive(Debug)]
+pub struct ClaimedScrape {
+    /// id of the `scheduled_scrape` row
+    pub id: i32,
+    pub pending: PendingProvider,
+    /// scrapes that end up being due in one of these are put off until it's over
+    pub blackouts: Blackouts,
+}
+
+/// The scrapes of a plan that still need a row. Resources that already have a scrape waiting
+/// or running are left to that one
+fn unqueued<'a>(pending: &'a [PendingProvider], queued: &[i32]) -> Vec<&'a PendingProvider> {
+    pending
+        .iter()
+        .filter(|scrape| !queued.contains(&scrape.id))
+        .collect()
+}
+
+/// Writes the plan to the database and marks its resources as queued. Resources another
+/// planner queued in the meantime are skipped
+pub async fn enqueue_scrapes(db: &Database, pending: &[PendingProvider]) -> anyhow::Result<()> {
+    let mut tx = db.begin().await?;
+    sqlx::query!("SELECT pg_advisory_xact_lock($1)", PLAN_LOCK)
+        .execute(&mut tx)
+        .await?;
+    let queued = sqlx::query!(
+        "SELECT DISTINCT provider_resource_id FROM scheduled_scrape
+        WHERE status IN ('pending', 'running') AND provider_resource_id = ANY($1)",
+        &pending.iter().map(|p| p.id).collect::<Vec<_>>()
+    )
+    .fetch_all(&mut tx)
+    .await?
+    .into_iter()
+    .map(|row| row.provider_resource_id)
+    .collect::<Vec<_>>();
+    let pending = unqueued(pending, &queued);
+    for scrape in &pending {
+        sqlx::query!(
+            "INSERT INTO scheduled_scrape (provider_resource_id, priority, scheduled_for)
+            VALUES ($1, $2, $3)",
+            scrape.id,
+            scrape.priority.level,
+            scrape.scheduled_for
+        )
+        .execute(&mut tx)
+        .await?;
+    }
+    sqlx::query!(
+        "UPDATE provider_resource SET last_queue = NOW() WHERE id = ANY($1)",
+        &pending.iter().map(|p| p.id).collect::<Vec<_>>()
+    )
+    .execute(&mut tx)
+    .await?;
+    tx.commit().await?;
+    info!("Scheduled {} scrapes", pending.len());
+    Ok(())
+}
+
+/// Puts scrapes whose worker stopped renewing its lease back in the queue. Scrapes that
+/// were running before leases existed don't have one and are requeued as well
+pub async fn expire_leases(db: &Database) -> anyhow::Result<()> {
+    let expired = sqlx::query!(
+        "UPDATE scheduled_scrape
+        SET status = 'pending', started_at = NULL, leased_by = NULL, lease_expires_at = NULL
+        WHERE status = 'running' AND (lease_expires_at IS NULL OR lease_expires_at < NOW())"
+    )
+    .execute(db)
+    .await?
+    .rows_affected();
+    if expired > 0 {
+        warn!(
+            "Requeued {} scrapes whose worker stopped responding",
+            expired
+        );
+    }
+    Ok(())
+}
+
+/// Extends the leases of every scrape the worker is running
+pub async fn renew_leases(db: &Database, worker: &str) -> anyhow::Result<()> {
+    sqlx::query!(
+        "UPDATE scheduled_scrape
+        SET lease_expires_at = NOW() + $2 * interval '1 second'
+        WHERE status = 'running' AND leased_by = $1",
+        worker,
+        LEASE_SECONDS
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Leases scrapes that are due to the worker and returns them. Only the earliest due scrape
+/// of a resource is claimed and never while another one of its scrapes is running, so a
+/// resource is only ever scraped by one worker at a time. Rows other workers are in the
+/// middle of claiming are skipped instead of waited on, and so are paused resources
+pub async fn claim_due_scrapes(
+    db: &Database,
+    worker: &str,
+    limit: i64,
+) -> anyhow::Result<Vec<ClaimedScrape>> {
+    let rows = sqlx::query!(
+        r#"WITH claimed AS (
+            UPDATE scheduled_scrape
+            SET
+                status = 'running',
+                started_at = NOW(),
+                leased_by = $2,
+                lease_expires_at = NOW() + $3 * interval '1 second'
+            WHERE id IN (
+                SELECT ss.id FROM scheduled_scrape ss
+                JOIN provider_resource pr ON pr.id = ss.provider_resource_id
+                WHERE ss.status = 'pending' AND ss.scheduled_for <= NOW() AND pr.enabled
+                AND (pr.paused_until IS NULL OR pr.paused_until <= NOW())
+                AND NOT EXISTS (
+                    SELECT 1 FROM scheduled_scrape other
+                    WHERE other.provider_resource_id = ss.provider_resource_id
+                    AND (
+                        other.status = 'running'
+                        OR (other.status = 'pending' AND (other.scheduled_for, other.id) < (ss.scheduled_for, ss.id))
+                    )
+                )
+                ORDER BY ss.scheduled_for
+                LIMIT $1
+                FOR UPDATE OF ss, pr SKIP LOCKED
+            )
+            RETURNING id, provider_resource_id, priority, scheduled_for
+        )
+        SELECT
+            claimed.id as "id!",
+            claimed.priority as "priority!",
+            claimed.scheduled_for as "scheduled_for!",
+            pr.id as resource_id,
+            pr.name,
+            pr.destination,
+            pr.official,
+            pr.last_scrape,
+            pr.default_name,
+            pr.blackout_windows
+        FROM claimed
+        JOIN provider_resource pr ON pr.id = claimed.provider_resource_id
+        ORDER BY claimed.scheduled_for"#,
+        limit,
+        worker,
+        LEASE_SECONDS
+    )
+    .fetch_all(db)
+    .await?;
+    let claimed = rows
+        .into_iter()
+        .filter_map(|row| {
+            let name = match AllProviders::from_str(&row.name) {
+                Ok(name) => name,
+                Err(_) => {
+                    warn!(
+                        "Skipping a scheduled scrape for unknown provider {}",
+                        row.name
+                    );
+                    return None;
+                }
+            };
+            Some(ClaimedScrape {
+                id: row.id,
+                pending: PendingProvider {
+                    id: row.resource_id,
+                    default_name: row.default_name,
+                    priority: Priority {
+                        level: row.priority,
+                    },
+                    provider: ScopedProvider {
+                        name,
+                        destination: row.destination,
+                        official: row.official,
+                    },
+                    scheduled_for: row.scheduled_for,
+                    last_scrape: row.last_scrape,
+                },
+                blackouts: Blackouts::from_column(row.blackout_windows.as_deref()),
+            })
+        })
+        .collect();
+    Ok(claimed)
+}
+
+/// Leases a resource for a scrape that runs right away instead of waiting in the queue, like
+/// the ones asked for through the API. Returns the id of the `scheduled_scrape` row holding
+/// the lease, or nothing if a worker is already scraping the resource
+pub async fn lease_resource_now(
+    db: &Database,
+    worker: &str,
+    resource_id: i32,
+) -> anyhow::Result<Option<i32>> {
+    let mut tx = db.begin().await?;
+    // workers lock the resource while claiming, so nobody can claim it in the meantime
+    sqlx::query!(
+        "SELECT id FROM provider_resource WHERE id = $1 FOR UPDATE",
+        resource_id
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    let row = sqlx::query!(
+        "INSERT INTO scheduled_scrape
+            (provider_resource_id, priority, scheduled_for, status, started_at, leased_by, lease_expires_at)
+        SELECT pr.id, pr.priority, NOW(), 'running', NOW(), $2, NOW() + $3 * interval '1 second'
+        FROM provider_resource pr
+        WHERE pr.id = $1 AND NOT EXISTS (
+            SELECT 1 FROM scheduled_scrape ss
+            WHERE ss.provider_resource_id = pr.id AND ss.status = 'running'
+        )
+        RETURNING id",
+        resource_id,
+        worker,
+        LEASE_SECONDS
+    )
+    .fetch_optional(&mut tx)
+    .await?;
+    tx.commit().await?;
+    Ok(row.map(|row| row.id))
+}
+
+/// Gives up a lease without recording anything, for scrapes that were never meant to be kept
+pub async fn release_scheduled_scrape(db: &Database, worker: &str, id: i32) -> anyhow::Result<()> {
+    sqlx::query!(
+        "DELETE FROM scheduled_scrape WHERE id = $1 AND leased_by = $2 AND status = 'running'",
+        id,
+        worker
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Scrapes are only finished by the worker holding their lease. A worker that lost its
+/// lease leaves the row alone since someone else is already running it again
+pub async fn complete_scheduled_scrape(
+    db: &Database,
+    worker: &str,
+    id: i32,
+    scrape_id: i32,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "UPDATE scheduled_scrape
+        SET status = 'completed', finished_at = NOW(), scrape_id = $3, lease_expires_at = NULL
+        WHERE id = $1 AND leased_by = $2 AND status = 'running'",
+        id,
+        worker,
+        scrape_id
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+/// Hands a claimed scrape back to the queue to be picked up again at `until`
+pub async fn postpone_scheduled_scrape(
+    db: &Database,
+    worker: &str,
+    id: i32,
+    until: NaiveDateTime,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "UPDATE scheduled_scrape
+        SET status = 'pending', scheduled_for = $3, started_at = NULL, leased_by = NULL, lease_expires_at = NULL
+        WHERE id = $1 AND leased_by = $2 AND status = 'running'",
+        id,
+        worker,
+        until
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+pub async fn fail_scheduled_scrape(
+    db: &Database,
+    worker: &str,
+    id: i32,
+    error: &str,
+) -> anyhow::Result<()> {
+    sqlx::query!(
+        "UPDATE scheduled_scrape
+        SET status = 'failed', finished_at = NOW(), error = $3, lease_expires_at = NULL
+        WHERE id = $1 AND leased_by = $2 AND status = 'running'",
+        id,
+        worker,
+        error
+    )
+    .execute(db)
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use crate::db::test_database;
+    use crate::models::PendingProvider;
+    use crate::scheduler::Priority;
+    use crate::scraper::{AllProviders, ScopedProvider};
+
+    use super::{claim_due_scrapes, unqueued, worker_id};
+
+    fn planned(id: i32, hour: u32) -> PendingProvider {
+        PendingProvider {
+            id,
+            default_name: None,
+            priority: Priority::unchecked_clamp(0.5),
+            provider: ScopedProvider {
+                name: AllProviders::TwitterTimeline,
+                destination: id.to_string(),
+                official: false,
+            },
+            scheduled_for: NaiveDate::from_ymd(2022, 2, 12).and_hms(hour, 0, 0),
+            last_scrape: None,
+        }
+    }
+
+    #[test]
+    fn queued_resources_are_left_out_of_the_plan() {
+        let plan = [planned(1, 3), planned(2, 1), planned(3, 2)];
+        let rows = unqueued(&plan, &[2, 4])
+            .into_iter()
+            .map(|scrape| (scrape.id, scrape.scheduled_for.to_string()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            rows,
+            [
+                (1, "2022-02-12 03:00:00".to_owned()),
+                (3, "2022-02-12 02:00:00".to_owned())
+            ]
+        );
+        assert!(unqueued(&plan, &[1, 2, 3]).is_empty());
+    }
+
+    #[test]
+    fn worker_ids_are_never_reused() {
+        // a restarted worker can't mistake the leases of the one before it for its own
+        assert_ne!(worker_id(), worker_id());
+    }
+
+    #[tokio::test]
+    async fn claims_take_the_earliest_scrape_of_idle_resources() {
+        // needs a database to claim from
+        let db = match test_database().await {
+            Some(db) => db,
+            None => return,
+        };
+        let mut resources = vec![];
+        for _ in 0..3 {
+            let resource = sqlx::query!(
+                "INSERT INTO provider_resource (name, destination, url, priority, last_token_update)
+                VALUES ($1, 'queue-' || gen_random_uuid(), '', 1, NOW())
+                RETURNING id",
+                AllProviders::TwitterTimeline.to_string()
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            resources.push(resource.id);
+        }
+        let schedule = |resource: i32, hours_ago: i32, status: &'static str| {
+            let db = &db;
+            async move {
+                sqlx::query!(
+                    "INSERT INTO scheduled_scrape (provider_resource_id, priority, scheduled_for, status)
+                    VALUES ($1, 1, NOW() - $2 * interval '1 hour', $3)
+                    RETURNING id",
+                    resource,
+                    hours_ago as f64,
+                    status
+                )
+                .fetch_one(db)
+                .await
+                .unwrap()
+                .id
+            }
+        };
+        let later = schedule(resources[0], 1, "pending").await;
+        let earliest = schedule(resources[0], 2, "pending").await;
+        let other = schedule(resources[1], 3, "pending").await;
+        schedule(resources[2], 2, "running").await;
+        let busy = schedule(resources[2], 4, "pending").await;
+
+        let claimed = claim_due_scrapes(&db, "queue-test", 1000)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|scrape| scrape.id)
+            .filter(|id| [later, earliest, other, busy].contains(id))
+            .collect::<Vec<_>>();
+        sqlx::query!(
+            "DELETE FROM scheduled_scrape WHERE provider_resource_id = ANY($1)",
+            &resources
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "DELETE FROM provider_resource WHERE id = ANY($1)",
+            &resources
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        // one scrape per resource, in the order they were due, and none for resources
+        // that are already being scraped
+        assert_eq!(claimed, [other, earliest]);
+    }
+}
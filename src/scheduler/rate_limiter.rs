@@ -1,16 +1,256 @@
+use std::env;
+use std::num::NonZeroU32;
+use std::sync::Arc;
+use std::time::Duration;
+
 use governor::{
     clock::QuantaClock,
-    state::{DirectStateStore, InMemoryState, NotKeyed},
-    RateLimiter,
+    state::{keyed::DefaultKeyedStateStore, InMemoryState, NotKeyed},
+    Jitter, Quota, RateLimiter,
 };
+use log::{error, warn};
+use parking_lot::RwLock;
+
+use crate::db::Database;
+use crate::scheduler::budget::spend_request;
+use crate::scheduler::env_name;
+use crate::scraper::AllProviders;
 
 /// Most providers use rate limiter at the domain level and not at the page level
 /// in order to prevent exceeding rate limits imposed by webservers
 pub type UnscopedLimiter = RateLimiter<NotKeyed, InMemoryState, QuantaClock>;
 
 /// Some providers can use rate limiting at the page level imposed by set limits of API keys
-#[allow(dead_code)]
-pub type ScopedLimiter = RateLimiter<dyn DirectStateStore, InMemoryState, QuantaClock>;
+pub type ScopedLimiter = RateLimiter<String, DefaultKeyedStateStore<String>, QuantaClock>;
+
+/// A domain-wide limiter combined with an optional limiter for every key,
+/// where a key is whatever the provider's limits are scoped to (a destination, a credential...)
+pub struct ProviderRateLimiter {
+    quota: Quota,
+    /// how many times slower than its quota the provider is currently going
+    slowdown: RwLock<u32>,
+    global: RwLock<Arc<UnscopedLimiter>>,
+    scoped: Option<ScopedLimiter>,
+    /// set when other processes make requests to the same provider
+    shared: RwLock<Option<SharedQuota>>,
+    /// the number of requests the provider can make in a day, see [`crate::scheduler::budget`]
+    budget: Option<u32>,
+}
+
+/// The provider-wide quota shared with every other worker through Postgres.
+/// Each request reserves the next free slot, one replenish interval after the last one
+#[derive(Clone)]
+struct SharedQuota {
+    db: Arc<Database>,
+    provider: AllProviders,
+}
+
+impl SharedQuota {
+    /// How long to wait for the reserved slot
+    async fn reserve(&self, interval: Duration) -> anyhow::Result<Duration> {
+        let interval_ms = interval.as_secs_f64() * 1000.0;
+        let row = sqlx::query!(
+            r#"INSERT INTO provider_rate_limit AS l (provider_name, next_request_at)
+            VALUES ($1, NOW() + $2 * interval '1 millisecond')
+            ON CONFLICT (provider_name) DO UPDATE
+            SET next_request_at = GREATEST(l.next_request_at, NOW()) + $2 * interval '1 millisecond'
+            RETURNING (EXTRACT(EPOCH FROM (l.next_request_at - NOW())) * 1000 - $2)::float8 as "wait_ms!""#,
+            self.provider.to_string(),
+            interval_ms
+        )
+        .fetch_one(&*self.db)
+        .await?;
+        Ok(Duration::from_secs_f64(row.wait_ms.max(0.0) / 1000.0))
+    }
+}
+
+/// Providers that keep getting rate limited are never slowed down more than this
+const MAX_SLOWDOWN: u32 = 8;
+
+impl ProviderRateLimiter {
+    pub fn new(quota: Quota, scoped_quota: Option<Quota>) -> Self {
+        Self {
+            quota,
+            slowdown: RwLock::new(1),
+            global: RwLock::new(Arc::new(RateLimiter::direct(quota))),
+            scoped: scoped_quota.map(RateLimiter::keyed),
+            shared: RwLock::new(None),
+            budget: None,
+        }
+    }
+
+    pub fn with_budget(self, budget: Option<u32>) -> Self {
+        Self { budget, ..self }
+    }
+
+    /// Coordinates the provider-wide quota with other processes sharing the database.
+    /// Scoped quotas stay local since a destination is only scraped by one worker at a time
+    pub fn share(&self, db: Arc<Database>, provider: AllProviders) {
+        *self.shared.write() = Some(SharedQuota { db, provider });
+    }
+
+    /// Halves the rate requests are made at for the rest of the run after
+    /// the provider told us we're going too fast
+    pub fn slow_down(&self) {
+        let mut slowdown = self.slowdown.write();
+        if *slowdown >= MAX_SLOWDOWN {
+            return;
+        }
+        *slowdown *= 2;
+        // the new limiter starts out with a full bucket, bursting right after being told
+        // to slow down would only get us rate limited again
+        let quota = Quota::with_period(self.quota.replenish_interval() * *slowdown).unwrap();
+        warn!(
+            "Slowing down to one request every {:?}",
+            quota.replenish_interval()
+        );
+        *self.global.write() = Arc::new(RateLimiter::direct(quota));
+    }
+
+    /// Wait until both the key and the whole provider have capacity for another request
+    pub async fn wait(&self, key: &str, jitter: Jitter) {
+        // the key is waited on first so a single busy key doesn't hold on to global capacity
+        if let Some(scoped) = &self.scoped {
+            scoped
+                .until_key_ready_with_jitter(&key.to_owned(), jitter)
+                .await;
+        }
+        let global = Arc::clone(&self.global.read());
+        global.until_ready_with_jitter(jitter).await;
+        let shared = self.shared.read().clone();
+        if let Some(shared) = shared {
+            let interval = self.quota.replenish_interval() * *self.slowdown.read();
+            match shared.reserve(interval).await {
+                Ok(delay) => tokio::time::sleep(delay).await,
+                // a database hiccup shouldn't stop scrapes, the local quota still applies
+                Err(err) => {
+                    error!("Could not reserve a request for {}", shared.provider);
+                    error!("{:?}", err);
+                }
+            }
+        }
+    }
+
+    /// Counts a request towards the provider's daily budget, returning false if there's
+    /// nothing left of it. Usage is kept in the database, so providers that aren't shared
+    /// with it are never held back
+    pub async fn spend_budget(&self) -> bool {
+        let shared = match self.shared.read().clone() {
+            Some(shared) => shared,
+            None => return true,
+        };
+        match spend_request(&shared.db, shared.provider, self.budget).await {
+            Ok(spent) => spent,
+            // same as the shared quota, a database hiccup shouldn't stop scrapes
+            Err(err) => {
+                error!("Could not count a request for {}", shared.provider);
+                error!("{:?}", err);
+                true
+            }
+        }
+    }
+}
+
+pub enum QuotaScope {
+    Global,
+    Scoped,
+}
+
+/// Parses quotas in the format of `{requests}/{period}` like `1/3500ms` or `180/15m`.
+/// Requests can be made in a burst up to the given count
+pub fn parse_quota(input: &str) -> Option<Quota> {
+    let (count, period) = input.trim().split_once('/')?;
+    let count = NonZeroU32::new(count.trim().parse::<u32>().ok()?)?;
+    let period = period.trim();
+    let unit_start = period.find(|c: char| !c.is_ascii_digit())?;
+    let (amount, unit) = period.split_at(unit_start);
+    let amount = amount.parse::<u64>().ok()?;
+    let period = match unit {
+        "ms" => Duration::from_millis(amount),
+        "s" => Duration::from_secs(amount),
+        "m" => Duration::from_secs(amount.checked_mul(60)?),
+        "h" => Duration::from_secs(amount.checked_mul(60 * 60)?),
+        _ => return None,
+    };
+    Quota::with_period(period / count.get()).map(|quota| quota.allow_burst(count))
+}
+
+/// The environment variable that overrides a provider's quota,
+/// `RATE_LIMIT_TWITTER_TIMELINE` and `RATE_LIMIT_TWITTER_TIMELINE_SCOPED` for `twitter.timeline`
+pub fn quota_variable(provider: AllProviders, scope: QuotaScope) -> String {
+    let name = env_name(provider, "RATE_LIMIT");
+    match scope {
+        QuotaScope::Global => name,
+        QuotaScope::Scoped => format!("{}_SCOPED", name),
+    }
+}
+
+/// The quota configured for a provider through the environment, if there is a valid one
+pub fn configured_quota(provider: AllProviders, scope: QuotaScope) -> Option<Quota> {
+    let variable = quota_variable(provider, scope);
+    let value = env::var(&variable).ok()?;
+    let quota = parse_quota(&value);
+    if quota.is_none() {
+        warn!("Ignoring invalid quota {} in {}", value, variable);
+    }
+    quota
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::scraper::AllProviders;
+
+    use super::{parse_quota, quota_variable, ProviderRateLimiter, QuotaScope, MAX_SLOWDOWN};
+
+    #[test]
+    fn quotas_are_parsed() {
+        let quota = parse_quota("1/3500ms").unwrap();
+        assert_eq!(quota.replenish_interval(), Duration::from_millis(3500));
+        assert_eq!(quota.burst_size().get(), 1);
+
+        let quota = parse_quota("180/15m").unwrap();
+        assert_eq!(quota.replenish_interval(), Duration::from_secs(5));
+        assert_eq!(quota.burst_size().get(), 180);
+
+        let too_long = format!("1/{}h", u64::MAX / 60);
+        for invalid in [
+            "", "1", "0/1s", "1/0s", "1/1", "1/1d", "a/1s", "1/s", &too_long,
+        ] {
+            assert!(parse_quota(invalid).is_none(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn slowdowns_are_capped() {
+        let limiter = ProviderRateLimiter::new(parse_quota("1/1s").unwrap(), None);
+        limiter.slow_down();
+        assert_eq!(*limiter.slowdown.read(), 2);
+        for _ in 0..10 {
+            limiter.slow_down();
+        }
+        assert_eq!(*limiter.slowdown.read(), MAX_SLOWDOWN);
+    }
+
+    #[test]
+    fn slowdowns_dont_burst() {
+        let limiter = ProviderRateLimiter::new(parse_quota("180/15m").unwrap(), None);
+        limiter.slow_down();
+        let global = limiter.global.read();
+        assert!(global.check().is_ok());
+        assert!(global.check().is_err());
+    }
 
-/// Global rate limiting wrapper for limits imposed on individual providers being run concurrently
-pub struct GlobalRateLimiter(UnscopedLimiter);
+    #[test]
+    fn quota_variables_are_named_after_providers() {
+        assert_eq!(
+            quota_variable(AllProviders::TwitterTimeline, QuotaScope::Global),
+            "RATE_LIMIT_TWITTER_TIMELINE"
+        );
+        assert_eq!(
+            quota_variable(AllProviders::WeverseArtistFeed, QuotaScope::Scoped),
+            "RATE_LIMIT_WEVERSE_ARTIST_FEED_SCOPED"
+        );
+    }
+}
@@ -1,8 +1,8 @@
-use std::ops::Add;
-use std::time::Duration;
-use std::{collections::HashSet, convert::TryInto, hash::Hash, iter::FromIterator, str::FromStr};
+use std::collections::{HashMap, HashSet};
+use std::{convert::TryInto, hash::Hash, iter::FromIterator, str::FromStr};
 
-use itertools::{unfold, Itertools};
+use chrono::{NaiveDateTime, Utc};
+use itertools::Itertools;
 use log::{debug, info};
 use num_traits::cast::ToPrimitive;
 use rand::Rng;
@@ -10,19 +10,28 @@ use rand::Rng;
 use crate::{
     db::Database,
     models::{PendingProvider, ScrapeHistory},
-    scheduler::Priority,
+    scheduler::{
+        budget::{budget_day_end, budget_usage, configured_budget, plan_within_budget},
+        overrides::{clamp_priority, Blackouts},
+        posting::{posting_hours, PostingHours},
+        strategy::{resolve_strategy, PriorityLimits},
+        Priority,
+    },
     scraper::{AllProviders, ScopedProvider},
 };
 
 const MAX_TESTING_PROVIDERS: usize = 10;
-pub const SCHEDULER_START_MILLISECONDS: u64 = if cfg!(debug_assertions) {
+
+/// How often the planner looks for resources that earned another scrape
+pub const PLANNER_INTERVAL_MILLISECONDS: u64 = if cfg!(debug_assertions) {
     1000 * 3
 } else {
-    1000 * 30
+    1000 * 60
 };
 
 // making life easier for testing. Could blow up in my face some day...
-pub const SCHEDULER_END_MILLISECONDS: u64 = if cfg!(debug_assertions) {
+/// Resources earn tokens equal to their priority over this period
+pub const TOKEN_PERIOD_MILLISECONDS: u64 = if cfg!(debug_assertions) {
     1000 * 10
 } else {
     8.64e7 as u64
@@ -44,25 +53,85 @@ impl ScheduledProviders {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
 }
 
-/// Get a list of sorted scrapes that need to happen for that day
+/// Plans scrapes for every resource that earned a token and doesn't have any scrapes queued
+/// yet. Resources that were never queued since they were added or re-enabled are scraped right
+/// away, everything else takes the earliest free slots of its provider.
+///
+/// Resources with a fixed interval skip tokens and slots and are scraped once their interval
+/// since the last scrape is up, paused resources aren't planned at all and nothing is planned
+/// inside the blackout windows of a resource. Scrapes that don't fit in the daily request
+/// budget of their provider are left for later
 pub async fn pending_scrapes(db: &Database) -> anyhow::Result<Vec<PendingProvider>> {
     // all future scrapes that are specifically grouped by their provider name first
-    let potential_target_providers = sqlx::query!(
+    let candidates = sqlx::query!(
         "SELECT * FROM provider_resource pr
-        WHERE pr.enabled AND pr.tokens >= 1
+        WHERE pr.enabled
+        AND (pr.tokens >= 1 OR pr.fixed_interval_minutes IS NOT NULL)
+        AND (pr.paused_until IS NULL OR pr.paused_until <= NOW())
+        AND NOT EXISTS (
+            SELECT 1 FROM scheduled_scrape ss
+            WHERE ss.provider_resource_id = pr.id AND ss.status IN ('pending', 'running')
+        )
         ORDER BY pr.name DESC, pr.destination desc"
     )
     .fetch_all(db)
     .await?;
+    let now = Utc::now().naive_utc();
+    let blackouts = candidates
+        .iter()
+        .map(|row| {
+            (
+                row.id,
+                Blackouts::from_column(row.blackout_windows.as_deref()),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    let (pinned, potential_target_providers): (Vec<_>, Vec<_>) = candidates
+        .into_iter()
+        .partition(|row| row.fixed_interval_minutes.is_some());
+    let pinned_ids = pinned.iter().map(|row| row.id).collect::<HashSet<_>>();
+    let pinned = pinned
+        .into_iter()
+        .map(|row| {
+            let interval =
+                chrono::Duration::minutes(row.fixed_interval_minutes.unwrap_or(0) as i64);
+            PendingProvider {
+                id: row.id,
+                priority: Priority::unchecked_clamp(row.priority.to_f32().unwrap()),
+                provider: ScopedProvider {
+                    destination: row.destination,
+                    name: AllProviders::from_str(&row.name).unwrap(),
+                    official: row.official,
+                },
+                scheduled_for: row
+                    .last_scrape
+                    .map(|last_scrape| (last_scrape + interval).max(now))
+                    .unwrap_or(now),
+                last_scrape: row.last_scrape,
+                default_name: row.default_name,
+            }
+        })
+        .collect::<Vec<_>>();
 
+    let posting = posting_hours(
+        db,
+        &potential_target_providers
+            .iter()
+            .map(|row| row.id)
+            .collect::<Vec<_>>(),
+    )
+    .await?;
     let groups = potential_target_providers
         .into_iter()
         .flat_map(|row| {
             let tokens = row.tokens.to_f32().unwrap().trunc() as i32;
             (0..tokens.min(MAX_DAILY_SCRAPE_COUNT))
-                .map(|_| {
+                .map(|i| {
                     (
                         row.id,
                         Priority::unchecked_clamp(row.priority.to_f32().unwrap()),
@@ -73,50 +142,73 @@ pub async fn pending_scrapes(db: &Database) -> anyhow::Result<Vec<PendingProvide
                         }, // last_scrape: row.last_scrape,
                         row.last_scrape,
                         row.default_name.clone(),
+                        // only the first scrape of a new resource skips the line
+                        i == 0 && row.last_queue.is_none(),
                     )
                 })
                 .collect::<Vec<_>>()
         })
         .group_by(|p| p.2.name);
 
-    let out: Vec<PendingProvider> = groups
+    let schedule = provider_schedule(db).await?;
+    let mut out: Vec<PendingProvider> = groups
         .into_iter()
-        .flat_map(|(_, group)| {
-            let endpoints = group.collect::<Vec<_>>();
-            let maximized_endpoints = maximize_distance(&endpoints, quality_maxmindist);
-            let dates = interpolate_dates(
-                maximized_endpoints.len(),
-                // We want to give the
-                &Duration::from_millis(SCHEDULER_START_MILLISECONDS),
-                // One day
-                &Duration::from_millis(SCHEDULER_END_MILLISECONDS),
+        .flat_map(|(name, group)| {
+            let (asap, endpoints): (Vec<_>, Vec<_>) = group.partition(|endpoint| endpoint.5);
+            let (mut taken, gap) = schedule
+                .get(&name)
+                .cloned()
+                .unwrap_or_else(|| (vec![], slot_gap(0f32)));
+            taken.extend(asap.iter().map(|_| now));
+            let maximized_endpoints = if endpoints.is_empty() {
+                endpoints
+            } else {
+                maximize_distance(&endpoints, quality_maxmindist)
+            };
+            let earliest = preferred_times(
+                &maximized_endpoints
+                    .iter()
+                    .map(|endpoint| (endpoint.0, &endpoint.1))
+                    .collect::<Vec<_>>(),
+                &posting,
+                now,
+                chrono::Duration::milliseconds(TOKEN_PERIOD_MILLISECONDS as i64),
             );
-            maximized_endpoints
-                .iter()
-                .zip(dates)
+            let dates = next_slots(&taken, &earliest, gap);
+            asap.into_iter()
+                .zip(std::iter::repeat(now))
+                .chain(maximized_endpoints.into_iter().zip(dates))
                 .map(
-                    |((id, priority, provider, last_scrape, default_name), scrape_date)| {
+                    |((id, priority, provider, last_scrape, default_name, _), scheduled_for)| {
                         PendingProvider {
-                            id: *id,
-                            priority: priority.clone(),
-                            provider: provider.clone(),
-                            scrape_date,
-                            last_scrape: *last_scrape,
-                            default_name: default_name.clone(),
+                            id,
+                            priority,
+                            provider,
+                            scheduled_for,
+                            last_scrape,
+                            default_name,
                         }
                     },
                 )
                 .collect::<Vec<_>>()
         })
         .collect::<Vec<_>>();
+    out.extend(pinned);
+    for pending in out.iter_mut() {
+        if let Some(blackouts) = blackouts.get(&pending.id) {
+            pending.scheduled_for = blackouts.next_allowed(pending.scheduled_for);
+        }
+    }
+    let allowances = budget_usage(db)
+        .await?
+        .into_iter()
+        .filter_map(|(provider, usage)| {
+            Some((provider, usage.allowance(configured_budget(provider)?)))
+        })
+        .collect::<HashMap<_, _>>();
+    let out = plan_within_budget(out, &allowances, &pinned_ids, budget_day_end(now));
 
     let original_length = out.len();
-    sqlx::query!(
-        "UPDATE provider_resource SET last_queue = NOW() WHERE id = ANY($1)",
-        &out.iter().map(|p| p.id).collect::<Vec<_>>()
-    )
-    .fetch_one(db)
-    .await;
     let safe_providers = if cfg!(debug_assertions) {
         // making sure we don't blow things up in case we're running this in development with tons of
         // pending providers
@@ -136,22 +228,123 @@ pub async fn pending_scrapes(db: &Database) -> anyhow::Result<Vec<PendingProvide
     Ok(safe_providers)
 }
 
-/// Vec length is equal to the length of the items passed in
-fn interpolate_dates(
-    item_count: usize,
-    start_duration: &Duration,
-    end_duration: &Duration,
-) -> Vec<Duration> {
-    let duration = *end_duration - *start_duration;
-    let initial_gap = duration.checked_div(item_count as u32 + 1).unwrap();
-    unfold(*start_duration, |duration| {
-        let next = duration.add(initial_gap);
-        *duration = next;
-        Some(next)
-    })
-    .by_ref()
-    .take(item_count)
-    .collect::<Vec<_>>()
+/// The scrapes every provider already has queued along with how far apart its scrapes should be
+async fn provider_schedule(
+    db: &Database,
+) -> anyhow::Result<HashMap<AllProviders, (Vec<NaiveDateTime>, chrono::Duration)>> {
+    let rows = sqlx::query!(
+        r#"SELECT
+            pr.name,
+            SUM(pr.priority) as "total_priority!",
+            ARRAY(
+                SELECT ss.scheduled_for FROM scheduled_scrape ss
+                JOIN provider_resource queued ON queued.id = ss.provider_resource_id
+                WHERE queued.name = pr.name AND ss.status = 'pending'
+            ) as "queued!"
+        FROM provider_resource pr
+        WHERE pr.enabled
+        GROUP BY pr.name"#
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|row| {
+            let name = AllProviders::from_str(&row.name).ok()?;
+            let gap = slot_gap(row.total_priority.to_f32().unwrap_or(0f32));
+            Some((name, (row.queued, gap)))
+        })
+        .collect())
+}
+
+/// A provider is scraped about as often as the priorities of its resources add up to in a
+/// token period, so its scrapes are spread that far apart to keep them even
+fn slot_gap(total_priority: f32) -> chrono::Duration {
+    let millis = TOKEN_PERIOD_MILLISECONDS as f32 / total_priority.max(1f32);
+    chrono::Duration::milliseconds(millis as i64)
+}
+
+/// The earliest times each endpoint would like to be scraped at. Endpoints with a posting
+/// pattern wait for the end of their next likely posting window, as long as that's sooner than
+/// their next scrape would be due anyway, and their later scrapes follow their own windows at
+/// the rate their priority allows. Everything else is scraped as soon as possible
+fn preferred_times(
+    endpoints: &[(i32, &Priority)],
+    posting: &HashMap<i32, PostingHours>,
+    now: NaiveDateTime,
+    period: chrono::Duration,
+) -> Vec<NaiveDateTime> {
+    let mut not_before: HashMap<i32, NaiveDateTime> = HashMap::new();
+    endpoints
+        .iter()
+        .map(|(id, priority)| {
+            let hours = match posting.get(id) {
+                Some(hours) => hours,
+                None => return now,
+            };
+            let after = not_before.get(id).copied().unwrap_or(now);
+            let priority = priority.level.to_f32().unwrap_or(1f32);
+            let interval = chrono::Duration::milliseconds(
+                (period.num_milliseconds() as f32 / priority) as i64,
+            );
+            let preferred = hours
+                .next_window(after, interval.min(period))
+                .unwrap_or(after);
+            not_before.insert(*id, preferred + interval);
+            preferred
+        })
+        .collect()
+}
+
+/// Times at or after the earliest ones asked for that are at least `gap` away from
+/// everything already taken and from each other
+fn next_slots(
+    taken: &[NaiveDateTime],
+    earliest: &[NaiveDateTime],
+    gap: chrono::Duration,
+) -> Vec<NaiveDateTime> {
+    let mut taken = taken.to_vec();
+    taken.sort();
+    let mut out = Vec::with_capacity(earliest.len());
+    for &earliest in earliest {
+        let mut candidate = earliest;
+        for slot in &taken {
+            if *slot - candidate >= gap {
+                break;
+            }
+            candidate = candidate.max(*slot + gap);
+        }
+        let index = taken.partition_point(|slot| *slot <= candidate);
+        taken.insert(index, candidate);
+        out.push(candidate);
+    }
+    out
+}
+
+/// Adds tokens to every resource for the time that passed since it last got some.
+/// We don't want to give any endpoint more than 4 tokens (in case something goes wrong).
+/// Paused resources don't earn anything so they don't come back with a burst of scrapes
+pub async fn accrue_tokens(db: &Database) -> anyhow::Result<()> {
+    sqlx::query!(
+        "UPDATE provider_resource
+        SET
+            tokens = CASE
+                WHEN last_token_update IS NULL THEN tokens
+                WHEN paused_until > NOW() THEN tokens
+                ELSE LEAST(
+                    4,
+                    tokens
+                    + LEAST(GREATEST(priority, COALESCE(min_priority, priority)), COALESCE(max_priority, priority))
+                    * EXTRACT(EPOCH FROM (NOW() - last_token_update)) * 1000 / $1
+                )
+            END,
+            last_token_update = NOW()
+        WHERE enabled = True",
+        TOKEN_PERIOD_MILLISECONDS as f64
+    )
+    .execute(db)
+    .await?;
+    Ok(())
 }
 
 pub async fn update_priorities(db: &Database, sp: &[PendingProvider]) -> anyhow::Result<()> {
@@ -161,6 +354,9 @@ pub async fn update_priorities(db: &Database, sp: &[PendingProvider]) -> anyhow:
             pr.name,
             pr.destination,
             pr.official,
+            pr.priority_strategy,
+            pr.min_priority,
+            pr.max_priority,
             s.priority as resource_priority,
             s.scraped_at,
             s.priority,
@@ -192,10 +388,14 @@ pub async fn update_priorities(db: &Database, sp: &[PendingProvider]) -> anyhow:
             row.name.clone(),
             row.destination.clone(),
             row.priority.clone(),
+            row.priority_strategy.clone(),
+            row.min_priority.clone(),
+            row.max_priority.clone(),
         )
     });
 
-    for ((id, name, destination, priority), rows) in groups {
+    for ((id, name, destination, priority, strategy, min_priority, max_priority), rows) in groups {
+        let provider_name = AllProviders::from_str(&name).unwrap();
         let histories = rows
             .into_iter()
             .filter(|&row| row.scraped_at.is_some())
@@ -205,7 +405,7 @@ pub async fn update_priorities(db: &Database, sp: &[PendingProvider]) -> anyhow:
                 result_count: row.discovery_count.unwrap_or(0i64).try_into().unwrap(),
                 provider: ScopedProvider {
                     destination: destination.clone(),
-                    name: AllProviders::from_str(&name).unwrap(),
+                    name: provider_name,
                     official: row.official,
                 },
             })
@@ -213,9 +413,19 @@ pub async fn update_priorities(db: &Database, sp: &[PendingProvider]) -> anyhow:
 
         if !histories.is_empty() {
             let provider_priority = Priority::unchecked_clamp(priority.to_f32().unwrap());
-            let next_priority = provider_priority.next(&histories[..]);
+            let strategy = resolve_strategy(provider_name, strategy.as_deref());
+            let next_priority = clamp_priority(
+                strategy.strategy().next(
+                    &provider_priority,
+                    &histories[..],
+                    &PriorityLimits::default(),
+                ),
+                min_priority.as_ref(),
+                max_priority.as_ref(),
+            );
             debug!(
-                "Setting the next priority for [{}] from {} to {} because {:?}",
+                "Setting the next {} priority for [{}] from {} to {} because {:?}",
+                strategy,
                 &name,
                 provider_priority.level.to_f32().unwrap_or(-1.0),
                 next_priority.level.to_f32().unwrap_or(-1.0),
@@ -233,19 +443,6 @@ pub async fn update_priorities(db: &Database, sp: &[PendingProvider]) -> anyhow:
             .await?;
         }
     }
-    // return Ok(());
-    // Update tokens for all resources. This has to be run after priorities are
-    // updated
-    // We don't want to give any endpoint more than 4 tokens (in case something goes wrong)
-    sqlx::query!(
-        "UPDATE provider_resource
-        SET
-            tokens = LEAST(4, tokens + priority),
-            last_token_update = NOW()
-        WHERE enabled = True AND (last_token_update IS NULL OR last_token_update + interval '1 day' <= NOW())"
-    )
-    .fetch_optional(db)
-    .await?;
     Ok(())
 }
 
@@ -296,11 +493,19 @@ fn quality_maxmindist<T: Hash + Eq>(items: &[T]) -> f32 {
 
 #[cfg(test)]
 mod tests {
-    use std::time::Duration;
+    use chrono::{Duration, NaiveDate, NaiveDateTime};
 
     use crate::scheduler::scheduler::quality_maxmindist;
 
-    use super::{interpolate_dates, maximize_distance};
+    use std::collections::HashMap;
+
+    use crate::scheduler::{posting::PostingHours, Priority};
+
+    use super::{maximize_distance, next_slots, preferred_times};
+
+    fn at(minute: i64) -> NaiveDateTime {
+        NaiveDate::from_ymd(2022, 2, 14).and_hms(0, 0, 0) + Duration::minutes(minute)
+    }
 
     #[test]
     fn spacing_test() {
@@ -311,14 +516,32 @@ mod tests {
     }
 
     #[test]
-    fn interpolate() {
-        let out: Vec<Duration> =
-            interpolate_dates(3, &Duration::from_millis(0), &Duration::from_millis(3000));
-        let res: Vec<Duration> = vec![
-            Duration::from_millis(750),
-            Duration::from_millis(1500),
-            Duration::from_millis(2250),
-        ];
-        assert_eq!(out, res)
+    fn slots_are_spaced_out() {
+        let out = next_slots(&[], &[at(0); 3], Duration::minutes(10));
+        assert_eq!(out, vec![at(0), at(10), at(20)]);
+    }
+
+    #[test]
+    fn slots_fill_gaps_between_queued_scrapes() {
+        let taken = [at(25), at(5), at(40)];
+        let out = next_slots(&taken, &[at(0); 3], Duration::minutes(10));
+        assert_eq!(out, vec![at(15), at(50), at(60)]);
+    }
+
+    #[test]
+    fn slots_keep_their_spacing_around_preferred_times() {
+        let taken = [at(30)];
+        let out = next_slots(&taken, &[at(35), at(0), at(36)], Duration::minutes(10));
+        assert_eq!(out, vec![at(40), at(0), at(50)]);
+    }
+
+    #[test]
+    fn endpoints_that_post_at_set_hours_wait_for_them() {
+        let mut posting = HashMap::new();
+        posting.insert(1, PostingHours::from_counts(&[(1, 20)]).unwrap());
+        let priority = Priority::unchecked_clamp(1f32);
+        let endpoints = [(1, &priority), (1, &priority), (2, &priority)];
+        let out = preferred_times(&endpoints, &posting, at(0), Duration::days(1));
+        assert_eq!(out, vec![at(130), at(130 + 24 * 60), at(0)]);
     }
 }
@@ -0,0 +1,625 @@
+//! Replays the scrape and media history of the database through the token and priority
+//! logic of the planner with different parameters, to see how they would have done
+//! compared to what actually happened before changing them for real.
+//!
+//! Posts are only known once they've been discovered, so the replay can only ever find
+//! media that the real scrapes found at some point. Media without a `posted_at` can't
+//! be placed in time and are left out entirely.
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use chrono::{Duration, NaiveDateTime};
+use log::warn;
+use num_traits::ToPrimitive;
+
+use crate::db::Database;
+use crate::models::ScrapeHistory;
+use crate::scheduler::strategy::{resolve_strategy, PriorityLimits, StrategyName};
+use crate::scheduler::Priority;
+use crate::scraper::{AllProviders, ScopedProvider};
+
+/// Resources can't save up more tokens than this, same as in `accrue_tokens`
+const MAX_TOKENS: f64 = 4.0;
+
+/// Tokens are added up in small fractions every step and land just short of a whole token
+/// more often than not
+const TOKEN_EPSILON: f64 = 1e-6;
+
+/// The number of scrapes priorities are calculated from, same as in `update_priorities`
+const HISTORY_LENGTH: usize = 30;
+
+#[derive(Debug, Clone)]
+pub struct SimulationParams {
+    /// Replaces the strategy of every resource when set
+    pub strategy: Option<StrategyName>,
+    pub limits: PriorityLimits,
+    /// How long it takes a resource with a priority of 1 to earn a token
+    pub token_period: Duration,
+    /// Posts discovered later than this count as starved
+    pub starved_after: Duration,
+    /// How often the replay checks resources for tokens, like the planner interval
+    pub step: Duration,
+}
+
+impl Default for SimulationParams {
+    fn default() -> Self {
+        Self {
+            strategy: None,
+            limits: PriorityLimits::default(),
+            // the debug token period is too short to say anything about production
+            token_period: Duration::days(1),
+            starved_after: Duration::days(7),
+            step: Duration::hours(1),
+        }
+    }
+}
+
+/// A post and when the real scrapes found it
+#[derive(Debug, Clone, PartialEq)]
+pub struct Post {
+    pub posted_at: NaiveDateTime,
+    pub discovered_at: NaiveDateTime,
+    /// scrapes are judged by the media they find like in `update_priorities`, not the posts
+    pub media: u32,
+}
+
+/// Everything that happened to a resource over the simulated window
+#[derive(Debug, Clone)]
+pub struct ResourceHistory {
+    pub provider: ScopedProvider,
+    pub strategy: Option<String>,
+    /// the priority the resource had when the window started
+    pub priority: Priority,
+    /// when the replay starts scraping the resource, later than the start of the window
+    /// for resources that were added during it
+    pub tracked_from: NaiveDateTime,
+    /// when the scrapes that actually ran happened, oldest first
+    pub scrapes: Vec<NaiveDateTime>,
+    /// requests the scrapes that actually ran made, a scrape can go through many pages
+    pub requests: u32,
+    /// oldest first, only posts made after `tracked_from`
+    pub posts: Vec<Post>,
+}
+
+impl ResourceHistory {
+    fn requests_per_scrape(&self) -> f32 {
+        if self.scrapes.is_empty() {
+            1f32
+        } else {
+            (self.requests as f32 / self.scrapes.len() as f32).max(1f32)
+        }
+    }
+}
+
+/// How a schedule did over the window
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Outcome {
+    pub scrapes: u32,
+    pub requests: f32,
+    pub discovered: u32,
+    /// posts no scrape had found by the end of the window
+    pub undiscovered: u32,
+    /// the sum of how long every discovered post waited, in seconds
+    pub total_latency: i64,
+    pub max_latency: i64,
+    /// resources that had a post wait longer than `starved_after`
+    pub starved_resources: u32,
+}
+
+impl Outcome {
+    pub fn mean_latency(&self) -> Option<Duration> {
+        if self.discovered == 0 {
+            None
+        } else {
+            Some(Duration::seconds(
+                self.total_latency / self.discovered as i64,
+            ))
+        }
+    }
+
+    pub fn max_latency(&self) -> Duration {
+        Duration::seconds(self.max_latency)
+    }
+
+    fn add(&mut self, other: Outcome) {
+        self.scrapes += other.scrapes;
+        self.requests += other.requests;
+        self.discovered += other.discovered;
+        self.undiscovered += other.undiscovered;
+        self.total_latency += other.total_latency;
+        self.max_latency = self.max_latency.max(other.max_latency);
+        self.starved_resources += other.starved_resources;
+    }
+}
+
+/// Tallies the posts of a resource along with when each one was found
+fn tally<'a>(
+    posts: impl Iterator<Item = (&'a Post, Option<NaiveDateTime>)>,
+    end: NaiveDateTime,
+    starved_after: Duration,
+) -> Outcome {
+    let mut outcome = Outcome::default();
+    let mut starved = false;
+    for (post, found_at) in posts {
+        match found_at {
+            Some(found) if found <= end => {
+                let latency = (found - post.posted_at).num_seconds().max(0);
+                outcome.discovered += 1;
+                outcome.total_latency += latency;
+                outcome.max_latency = outcome.max_latency.max(latency);
+                starved |= latency > starved_after.num_seconds();
+            }
+            _ => {
+                outcome.undiscovered += 1;
+                starved |= end - post.posted_at > starved_after;
+            }
+        }
+    }
+    outcome.starved_resources = starved as u32;
+    outcome
+}
+
+/// What actually happened to a resource
+pub fn actual_outcome(
+    resource: &ResourceHistory,
+    end: NaiveDateTime,
+    params: &SimulationParams,
+) -> Outcome {
+    Outcome {
+        scrapes: resource.scrapes.len() as u32,
+        requests: resource.requests as f32,
+        ..tally(
+            resource
+                .posts
+                .iter()
+                .map(|post| (post, Some(post.discovered_at))),
+            end,
+            params.starved_after,
+        )
+    }
+}
+
+/// The scrapes a replay made and when they found every post
+struct Replay {
+    /// most recent first
+    history: Vec<ScrapeHistory>,
+    found_at: Vec<Option<NaiveDateTime>>,
+}
+
+fn replay(resource: &ResourceHistory, end: NaiveDateTime, params: &SimulationParams) -> Replay {
+    let strategy = params
+        .strategy
+        .unwrap_or_else(|| resolve_strategy(resource.provider.name, resource.strategy.as_deref()));
+    let step_share = params.step.num_seconds() as f64 / params.token_period.num_seconds() as f64;
+    let mut priority = resource.priority.clone();
+    let mut tokens = 0f64;
+    let mut history: Vec<ScrapeHistory> = vec![];
+    let mut found_at: Vec<Option<NaiveDateTime>> = vec![None; resource.posts.len()];
+
+    let mut now = resource.tracked_from + params.step;
+    while now <= end {
+        let level = priority.level.to_f64().unwrap_or(1f64);
+        tokens = (tokens + level * step_share).min(MAX_TOKENS);
+        if tokens >= 1f64 - TOKEN_EPSILON {
+            tokens = (tokens - 1f64).max(0f64);
+            let mut result_count = 0;
+            for (post, found) in resource.posts.iter().zip(found_at.iter_mut()) {
+                if post.posted_at <= now && found.is_none() {
+                    *found = Some(now);
+                    result_count += post.media;
+                }
+            }
+            history.insert(
+                0,
+                ScrapeHistory {
+                    priority: priority.clone(),
+                    provider: resource.provider.clone(),
+                    date: now,
+                    result_count,
+                },
+            );
+            priority = strategy.strategy().next(
+                &priority,
+                &history[..history.len().min(HISTORY_LENGTH)],
+                &params.limits,
+            );
+        }
+        now += params.step;
+    }
+    Replay { history, found_at }
+}
+
+/// Replays a resource with the given parameters
+pub fn simulate_resource(
+    resource: &ResourceHistory,
+    end: NaiveDateTime,
+    params: &SimulationParams,
+) -> Outcome {
+    let Replay { history, found_at } = replay(resource, end, params);
+    let scrapes = history.len() as u32;
+    Outcome {
+        scrapes,
+        requests: scrapes as f32 * resource.requests_per_scrape(),
+        ..tally(
+            resource.posts.iter().zip(found_at),
+            end,
+            params.starved_after,
+        )
+    }
+}
+
+/// The actual and simulated outcomes of every resource added up
+pub fn compare(
+    resources: &[ResourceHistory],
+    end: NaiveDateTime,
+    params: &SimulationParams,
+) -> (Outcome, Outcome) {
+    let mut actual = Outcome::default();
+    let mut simulated = Outcome::default();
+    for resource in resources {
+        actual.add(actual_outcome(resource, end, params));
+        simulated.add(simulate_resource(resource, end, params));
+    }
+    (actual, simulated)
+}
+
+/// Loads the history of every enabled resource between `start` and `end`
+pub async fn load_history(
+    db: &Database,
+    start: NaiveDateTime,
+    end: NaiveDateTime,
+) -> anyhow::Result<Vec<ResourceHistory>> {
+    let resources = sqlx::query!(
+        r#"SELECT
+            pr.id,
+            pr.name,
+            pr.destination,
+            pr.official,
+            pr.priority,
+            pr.priority_strategy,
+            (SELECT MIN(s.scraped_at) FROM scrape s
+              WHERE s.provider_name = pr.name AND s.provider_destination = pr.destination
+            ) as first_scrape,
+            (SELECT s.priority FROM scrape s
+              WHERE s.provider_name = pr.name AND s.provider_destination = pr.destination
+                AND s.scraped_at >= $1
+              ORDER BY s.scraped_at
+              LIMIT 1
+            ) as window_priority
+        FROM provider_resource pr
+        WHERE pr.enabled"#,
+        start
+    )
+    .fetch_all(db)
+    .await?;
+
+    let scrapes = sqlx::query!(
+        r#"SELECT
+            pr.id,
+            s.scraped_at as "scraped_at!",
+            (SELECT COUNT(*) FROM scrape_request sr WHERE sr.scrape_id = s.id) as "requests!"
+        FROM provider_resource pr
+        JOIN scrape s ON s.provider_name = pr.name AND s.provider_destination = pr.destination
+        WHERE pr.enabled AND s.scraped_at BETWEEN $1 AND $2
+        ORDER BY s.scraped_at"#,
+        start,
+        end
+    )
+    .fetch_all(db)
+    .await?;
+    let mut scrapes_by_resource: HashMap<i32, Vec<(NaiveDateTime, i64)>> = HashMap::new();
+    for row in scrapes {
+        scrapes_by_resource
+            .entry(row.id)
+            .or_default()
+            .push((row.scraped_at, row.requests));
+    }
+
+    // images of the same post share a post date and are found together
+    let posts = sqlx::query!(
+        r#"SELECT
+            pr.id,
+            m.posted_at as "posted_at!",
+            MIN(m.discovered_at) as "discovered_at!",
+            COUNT(*) as "media!"
+        FROM provider_resource pr
+        JOIN media m ON m.provider_name = pr.name AND m.provider_destination = pr.destination
+        WHERE pr.enabled AND m.posted_at BETWEEN $1 AND $2
+        GROUP BY pr.id, m.posted_at
+        ORDER BY m.posted_at"#,
+        start,
+        end
+    )
+    .fetch_all(db)
+    .await?;
+    let mut posts_by_resource: HashMap<i32, Vec<Post>> = HashMap::new();
+    for row in posts {
+        posts_by_resource.entry(row.id).or_default().push(Post {
+            posted_at: row.posted_at,
+            discovered_at: row.discovered_at,
+            media: row.media as u32,
+        });
+    }
+
+    let histories = resources
+        .into_iter()
+        .filter_map(|row| {
+            let name = match AllProviders::from_str(&row.name) {
+                Ok(name) => name,
+                Err(_) => {
+                    warn!("Not simulating unknown provider {}", row.name);
+                    return None;
+                }
+            };
+            // resources that were never scraped have no history to replay
+            let tracked_from = row.first_scrape?.max(start);
+            let scrapes = scrapes_by_resource.remove(&row.id).unwrap_or_default();
+            let posts = posts_by_resource
+                .remove(&row.id)
+                .unwrap_or_default()
+                .into_iter()
+                .filter(|post| post.posted_at > tracked_from)
+                .collect();
+            Some(ResourceHistory {
+                provider: ScopedProvider {
+                    name,
+                    destination: row.destination,
+                    official: row.official,
+                },
+                strategy: row.priority_strategy,
+                priority: Priority {
+                    level: row.window_priority.unwrap_or(row.priority),
+                },
+                tracked_from,
+                requests: scrapes.iter().map(|(_, requests)| *requests as u32).sum(),
+                scrapes: scrapes.into_iter().map(|(date, _)| date).collect(),
+                posts,
+            })
+        })
+        .collect();
+    Ok(histories)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, NaiveDate, NaiveDateTime};
+    use num_traits::ToPrimitive;
+
+    use crate::{
+        db::test_database,
+        models::{PendingProvider, ScrapeHistory},
+        scheduler::{
+            strategy::{PriorityLimits, StrategyName},
+            update_priorities, Priority,
+        },
+        scraper::{AllProviders, ScopedProvider},
+    };
+
+    use super::{
+        actual_outcome, load_history, replay, simulate_resource, Post, ResourceHistory,
+        SimulationParams,
+    };
+
+    fn day(n: i64) -> NaiveDateTime {
+        NaiveDate::from_ymd(2022, 2, 1).and_hms(0, 0, 0) + Duration::days(n)
+    }
+
+    /// Posts every day at noon and was scraped once a day at midnight
+    fn daily_poster(days: i64) -> ResourceHistory {
+        ResourceHistory {
+            provider: ScopedProvider {
+                name: AllProviders::TwitterTimeline,
+                destination: "1".to_owned(),
+                official: false,
+            },
+            strategy: None,
+            priority: Priority::unchecked_clamp(1f32),
+            tracked_from: day(0),
+            scrapes: (1..=days).map(day).collect(),
+            requests: days as u32 * 2,
+            posts: (0..days)
+                .map(|n| Post {
+                    posted_at: day(n) + Duration::hours(12),
+                    discovered_at: day(n + 1),
+                    media: 1,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn fixed_rates_replay_the_real_schedule() {
+        let resource = daily_poster(10);
+        let params = SimulationParams {
+            strategy: Some(StrategyName::FixedRate),
+            ..SimulationParams::default()
+        };
+        let actual = actual_outcome(&resource, day(10), &params);
+        let simulated = simulate_resource(&resource, day(10), &params);
+        assert_eq!(actual, simulated);
+        assert_eq!(simulated.scrapes, 10);
+        assert_eq!(simulated.requests, 20f32);
+        assert_eq!(simulated.mean_latency(), Some(Duration::hours(12)));
+        assert_eq!(simulated.starved_resources, 0);
+    }
+
+    #[test]
+    fn slow_schedules_starve_resources() {
+        let mut resource = daily_poster(20);
+        resource.priority = Priority::unchecked_clamp(0.1);
+        let params = SimulationParams {
+            strategy: Some(StrategyName::FixedRate),
+            ..SimulationParams::default()
+        };
+        let simulated = simulate_resource(&resource, day(20), &params);
+        assert_eq!(simulated.scrapes, 2);
+        assert_eq!(simulated.undiscovered, 0);
+        assert_eq!(simulated.starved_resources, 1);
+        assert!(simulated.max_latency() > Duration::days(7));
+    }
+
+    #[tokio::test]
+    async fn replays_count_discoveries_like_update_priorities() {
+        // needs a database to hold the history
+        let db = match test_database().await {
+            Some(db) => db,
+            None => return,
+        };
+        let resource = sqlx::query!(
+            "INSERT INTO provider_resource (name, destination, url, priority, priority_strategy, last_token_update)
+            VALUES ($1, 'simulation-' || gen_random_uuid(), '', 1, 'ewma', NOW())
+            RETURNING id, destination",
+            AllProviders::TwitterTimeline.to_string()
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        // a post with 3 images, nothing, then two posts with 3 images between them
+        let found = [
+            (day(0), vec![]),
+            (day(1), vec![(day(0) + Duration::hours(12), 3)]),
+            (day(2), vec![]),
+            (
+                day(3),
+                vec![
+                    (day(2) + Duration::hours(12), 2),
+                    (day(2) + Duration::hours(13), 1),
+                ],
+            ),
+        ];
+        for (scraped_at, posts) in found.iter() {
+            let scrape = sqlx::query!(
+                "INSERT INTO scrape (provider_name, provider_destination, priority, scraped_at)
+                VALUES ($1, $2, 1, $3) RETURNING id",
+                AllProviders::TwitterTimeline.to_string(),
+                resource.destination,
+                scraped_at
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            let request = sqlx::query!(
+                "INSERT INTO scrape_request (scrape_id, scraped_at) VALUES ($1, $2) RETURNING id",
+                scrape.id,
+                scraped_at
+            )
+            .fetch_one(&db)
+            .await
+            .unwrap();
+            for (posted_at, media) in posts {
+                for image in 0..*media {
+                    let id = format!("{}-{}", posted_at.timestamp(), image);
+                    sqlx::query!(
+                        "INSERT INTO media (
+                            provider_name,
+                            provider_destination,
+                            scrape_request_id,
+                            image_url,
+                            unique_identifier,
+                            posted_at,
+                            discovered_at
+                        ) VALUES ($1, $2, $3, $4 || '/' || $5, $5, $6, $7)",
+                        AllProviders::TwitterTimeline.to_string(),
+                        resource.destination,
+                        request.id,
+                        resource.destination,
+                        id,
+                        posted_at,
+                        scraped_at
+                    )
+                    .execute(&db)
+                    .await
+                    .unwrap();
+                }
+            }
+        }
+
+        let pending = PendingProvider {
+            id: resource.id,
+            default_name: None,
+            priority: Priority::unchecked_clamp(1f32),
+            provider: ScopedProvider {
+                name: AllProviders::TwitterTimeline,
+                destination: resource.destination.clone(),
+                official: false,
+            },
+            scheduled_for: day(4),
+            last_scrape: None,
+        };
+        update_priorities(&db, &[pending]).await.unwrap();
+        let updated = sqlx::query!(
+            "SELECT priority FROM provider_resource WHERE id = $1",
+            resource.id
+        )
+        .fetch_one(&db)
+        .await
+        .unwrap();
+        let history = load_history(&db, day(0), day(3))
+            .await
+            .unwrap()
+            .into_iter()
+            .find(|history| history.provider.destination == resource.destination)
+            .unwrap();
+
+        sqlx::query!(
+            "DELETE FROM media WHERE provider_destination = $1",
+            resource.destination
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "DELETE FROM scrape_request WHERE scrape_id IN (
+                SELECT id FROM scrape WHERE provider_destination = $1
+            )",
+            resource.destination
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        sqlx::query!(
+            "DELETE FROM scrape WHERE provider_destination = $1",
+            resource.destination
+        )
+        .execute(&db)
+        .await
+        .unwrap();
+        sqlx::query!("DELETE FROM provider_resource WHERE id = $1", resource.id)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        // scraping at the same times as the real scrapes has to find the same things
+        let params = SimulationParams {
+            strategy: Some(StrategyName::FixedRate),
+            step: Duration::days(1),
+            ..SimulationParams::default()
+        };
+        let mut replayed = replay(&history, day(3), &params).history;
+        assert_eq!(
+            replayed
+                .iter()
+                .map(|scrape| scrape.result_count)
+                .collect::<Vec<_>>(),
+            [3, 0, 3]
+        );
+        // the replay starts from the first scrape instead of making it again
+        replayed.push(ScrapeHistory {
+            date: day(0),
+            result_count: 0,
+            ..replayed[0].clone()
+        });
+        let expected = StrategyName::Ewma.strategy().next(
+            &history.priority,
+            &replayed,
+            &PriorityLimits::default(),
+        );
+        let difference = updated.priority.to_f32().unwrap() - expected.level.to_f32().unwrap();
+        assert!(
+            difference.abs() < 1e-4,
+            "{:?} {:?}",
+            updated.priority,
+            expected
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use std::env;
+use std::str::FromStr;
+
+use log::warn;
+use num_traits::FromPrimitive;
+use num_traits::ToPrimitive;
+use sqlx::types::BigDecimal;
+use strum_macros::{Display, EnumIter, EnumString};
+
+use crate::models::ScrapeHistory;
+use crate::scheduler::{env_name, Priority, MAX_PRIORITY, MIN_PRIORITY};
+use crate::scraper::AllProviders;
+
+/// Scrapes finding more than this many images don't count for more than this
+const MAX_RESULT_CONTRIBUTION: u32 = 3;
+
+/// The knobs every strategy is tuned by
+#[derive(Debug, Clone, PartialEq)]
+pub struct PriorityLimits {
+    pub min_priority: f32,
+    pub max_priority: f32,
+    /// scrapes finding more images than this count the same as finding this many
+    pub max_result_contribution: u32,
+}
+
+impl Default for PriorityLimits {
+    fn default() -> Self {
+        Self {
+            min_priority: MIN_PRIORITY,
+            max_priority: MAX_PRIORITY,
+            max_result_contribution: MAX_RESULT_CONTRIBUTION,
+        }
+    }
+}
+
+impl PriorityLimits {
+    fn clamp(&self, level: f32) -> f32 {
+        level.clamp(self.min_priority, self.max_priority)
+    }
+
+    fn contribution(&self, history: &ScrapeHistory) -> f32 {
+        history.result_count.min(self.max_result_contribution) as f32
+    }
+
+    /// Scales an average result count onto the priority range
+    fn scaled_priority(&self, average: f32, current: &Priority) -> Priority {
+        let scaled = average * (self.max_priority - self.min_priority) + self.min_priority;
+        let level = self.clamp(scaled);
+
+        // in some strange situations f32 is NaN.
+        // These cases are normally handled at the top of the function but if not... we just default to
+        // the existing thing
+        let level = BigDecimal::from_f32(level).unwrap_or_else(|| current.level.clone());
+        Priority { level }
+    }
+}
+
+/// How much the latest scrape counts towards the average of [`Ewma`]
+const EWMA_ALPHA: f32 = 0.3;
+
+/// Decides how often a resource should be scraped based on what its recent scrapes found
+pub trait PriorityStrategy: Send + Sync {
+    /// The next priority of a resource. `history` is ordered from the most recent scrape
+    fn next(
+        &self,
+        current: &Priority,
+        history: &[ScrapeHistory],
+        limits: &PriorityLimits,
+    ) -> Priority;
+}
+
+/// Weighs the last scrapes on a squared curve where recent results count the most
+pub struct Curve;
+
+impl PriorityStrategy for Curve {
+    fn next(
+        &self,
+        current: &Priority,
+        history: &[ScrapeHistory],
+        limits: &PriorityLimits,
+    ) -> Priority {
+        // resources that were never scraped start at 1 no matter the limits, same as before
+        // strategies could be picked
+        if history.is_empty() {
+            return Priority::from(1f32);
+        }
+        let n = history.len() as i32;
+
+        let raw_weights = (0i32..n).map(|x| (x - n - 1).pow(2));
+        let sum_raw_weight: i32 = raw_weights.clone().sum();
+        let weights = raw_weights.map(|x| x as f32 / sum_raw_weight as f32);
+        let weight_sum = weights.clone().sum::<f32>();
+        let z = weights.zip(history);
+        let raw_weighted_average: f32 = z.map(|(a, b)| a * limits.contribution(b)).sum();
+
+        let weighted_average: f32 = (raw_weighted_average * weight_sum) / weight_sum;
+        limits.scaled_priority(weighted_average, current)
+    }
+}
+
+/// An exponentially weighted moving average of discoveries. Reacts to a resource
+/// going quiet or waking up faster than the curve does, and only reaches the highest
+/// priority for resources that keep finding the most images that count
+pub struct Ewma;
+
+impl PriorityStrategy for Ewma {
+    fn next(
+        &self,
+        current: &Priority,
+        history: &[ScrapeHistory],
+        limits: &PriorityLimits,
+    ) -> Priority {
+        let mut oldest_first = history.iter().rev();
+        let first = match oldest_first.next() {
+            Some(first) => limits.contribution(first),
+            None => return Priority::from(limits.clamp(1f32)),
+        };
+        let average = oldest_first.fold(first, |average, scrape| {
+            EWMA_ALPHA * limits.contribution(scrape) + (1f32 - EWMA_ALPHA) * average
+        });
+        limits.scaled_priority(average / limits.max_result_contribution as f32, current)
+    }
+}
+
+/// Never changes the priority, resources are scraped at whatever rate they're set to
+pub struct FixedRate;
+
+impl PriorityStrategy for FixedRate {
+    fn next(
+        &self,
+        current: &Priority,
+        _history: &[ScrapeHistory],
+        limits: &PriorityLimits,
+    ) -> Priority {
+        Priority::from(limits.clamp(current.level.to_f32().unwrap_or(1f32)))
+    }
+}
+
+/// The strategies a resource can pick by name in `provider_resource.priority_strategy`
+#[derive(Display, Debug, Default, Copy, Clone, EnumString, EnumIter, PartialEq, Eq)]
+#[strum(serialize_all = "snake_case")]
+pub enum StrategyName {
+    #[default]
+    Curve,
+    Ewma,
+    FixedRate,
+}
+
+impl StrategyName {
+    pub fn strategy(&self) -> &'static dyn PriorityStrategy {
+        match self {
+            StrategyName::Curve => &Curve,
+            StrategyName::Ewma => &Ewma,
+            StrategyName::FixedRate => &FixedRate,
+        }
+    }
+}
+
+fn parse_strategy(value: &str, source: &str) -> Option<StrategyName> {
+    let strategy = StrategyName::from_str(value).ok();
+    if strategy.is_none() {
+        warn!("Ignoring unknown priority strategy {} in {}", value, source);
+    }
+    strategy
+}
+
+/// The environment variable that sets the strategy of every resource of a provider,
+/// `PRIORITY_STRATEGY_TWITTER_TIMELINE` for `twitter.timeline`
+pub fn strategy_variable(provider: AllProviders) -> String {
+    env_name(provider, "PRIORITY_STRATEGY")
+}
+
+/// Resources use their own strategy if they have one, then the one configured for their
+/// provider, then the one configured with `PRIORITY_STRATEGY`, and the curve otherwise
+pub fn resolve_strategy(provider: AllProviders, resource: Option<&str>) -> StrategyName {
+    let variable = strategy_variable(provider);
+    resource
+        .and_then(|name| parse_strategy(name, "provider_resource"))
+        .or_else(|| {
+            let value = env::var(&variable).ok()?;
+            parse_strategy(&value, &variable)
+        })
+        .or_else(|| {
+            let value = env::var("PRIORITY_STRATEGY").ok()?;
+            parse_strategy(&value, "PRIORITY_STRATEGY")
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDateTime;
+    use num_traits::FromPrimitive;
+    use sqlx::types::BigDecimal;
+
+    use crate::{
+        models::ScrapeHistory,
+        scheduler::{Priority, MAX_PRIORITY, MIN_PRIORITY},
+        scraper::{AllProviders, ScopedProvider},
+    };
+
+    use super::{
+        resolve_strategy, Curve, Ewma, FixedRate, PriorityLimits, PriorityStrategy, StrategyName,
+    };
+
+    fn make_hist(count: u32) -> ScrapeHistory {
+        ScrapeHistory {
+            date: NaiveDateTime::from_timestamp(0, 0),
+            priority: Priority::unchecked_clamp(0f32),
+            provider: ScopedProvider {
+                destination: "".to_owned(),
+                name: AllProviders::PinterestBoardFeed,
+                official: false,
+            },
+            result_count: count,
+        }
+    }
+
+    fn level(priority: f32) -> BigDecimal {
+        BigDecimal::from_f32(priority).unwrap()
+    }
+
+    #[test]
+    fn priority_check() {
+        let limits = PriorityLimits::default();
+        let prio = Priority::unchecked_clamp(0f32);
+        let hist = make_hist(1);
+        let n = Curve.next(
+            &prio,
+            &[hist.clone(), hist.clone(), hist.clone(), hist],
+            &limits,
+        );
+        assert_eq!(n.level, level(MAX_PRIORITY));
+
+        let n = Curve.next(
+            &prio,
+            &(0..15).map(|_| make_hist(1)).collect::<Vec<_>>(),
+            &limits,
+        );
+        assert_eq!(n.level, level(MAX_PRIORITY));
+
+        let n = Curve.next(
+            &prio,
+            &(0..15).map(|_| make_hist(0)).collect::<Vec<_>>(),
+            &limits,
+        );
+        assert_eq!(n.level, level(MIN_PRIORITY))
+    }
+
+    #[test]
+    fn curve_starts_unscraped_resources_at_one() {
+        let limits = PriorityLimits {
+            max_priority: 0.5,
+            ..PriorityLimits::default()
+        };
+        let prio = Priority::unchecked_clamp(0.2);
+        assert_eq!(Curve.next(&prio, &[], &limits).level, level(1f32));
+    }
+
+    #[test]
+    fn ewma_follows_recent_discoveries() {
+        let limits = PriorityLimits::default();
+        let prio = Priority::unchecked_clamp(1f32);
+        let quiet = (0..10).map(|_| make_hist(0)).collect::<Vec<_>>();
+        assert_eq!(Ewma.next(&prio, &quiet, &limits).level, level(MIN_PRIORITY));
+
+        // most recent first, a resource that just started posting again climbs quickly
+        let mut waking = vec![make_hist(3), make_hist(3)];
+        waking.extend(quiet);
+        let next = Ewma.next(&prio, &waking, &limits);
+        assert!(next.level > level(0.5));
+        assert!(next.level < level(MAX_PRIORITY));
+
+        let busy = (0..10).map(|_| make_hist(5)).collect::<Vec<_>>();
+        assert_eq!(Ewma.next(&prio, &busy, &limits).level, level(MAX_PRIORITY));
+    }
+
+    #[test]
+    fn fixed_rate_keeps_the_priority() {
+        let limits = PriorityLimits::default();
+        let prio = Priority::unchecked_clamp(0.5);
+        let quiet = (0..10).map(|_| make_hist(0)).collect::<Vec<_>>();
+        assert_eq!(FixedRate.next(&prio, &quiet, &limits), prio);
+    }
+
+    #[test]
+    fn resource_strategies_win() {
+        assert_eq!(
+            resolve_strategy(AllProviders::TwitterTimeline, Some("ewma")),
+            StrategyName::Ewma
+        );
+        assert_eq!(
+            resolve_strategy(AllProviders::TwitterTimeline, Some("fixed_rate")),
+            StrategyName::FixedRate
+        );
+        assert_eq!(
+            resolve_strategy(AllProviders::PinterestBoardFeed, Some("unknown")),
+            StrategyName::Curve
+        );
+    }
+}
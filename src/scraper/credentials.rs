@@ -0,0 +1,161 @@
+//! Provider credentials persisted in Postgres so restarts don't have to log into
+//! every provider again. Credentials are encrypted with AES-256-GCM using the
+//! hex encoded 32 byte key in the `CREDENTIALS_KEY` environment variable.
+use std::env;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use log::{debug, warn};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+
+use crate::db::Database;
+use crate::scraper::{AllProviders, ProviderCredentials};
+
+pub struct CredentialStore {
+    db: Arc<Database>,
+    key: LessSafeKey,
+}
+
+fn parse_key(key: &str) -> anyhow::Result<LessSafeKey> {
+    let bytes = hex::decode(key.trim())?;
+    let key = UnboundKey::new(&AES_256_GCM, &bytes)
+        .map_err(|_| anyhow!("CREDENTIALS_KEY must be 32 bytes encoded as hex"))?;
+    Ok(LessSafeKey::new(key))
+}
+
+fn associated_data(provider: AllProviders, account: &str) -> Aad<String> {
+    Aad::from(format!("{}:{}", provider, account))
+}
+
+/// Encrypts a plaintext with a random nonce. The provider and account are used as associated
+/// data so a row can't be copied over to a different account
+fn encrypt(
+    key: &LessSafeKey,
+    provider: AllProviders,
+    account: &str,
+    plaintext: &[u8],
+) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+    let mut nonce = [0u8; NONCE_LEN];
+    OsRng.fill_bytes(&mut nonce);
+    let mut ciphertext = plaintext.to_vec();
+    key.seal_in_place_append_tag(
+        Nonce::assume_unique_for_key(nonce),
+        associated_data(provider, account),
+        &mut ciphertext,
+    )
+    .map_err(|_| anyhow!("Could not encrypt credentials for {}", provider))?;
+    Ok((nonce.to_vec(), ciphertext))
+}
+
+fn decrypt(
+    key: &LessSafeKey,
+    provider: AllProviders,
+    account: &str,
+    nonce: &[u8],
+    mut ciphertext: Vec<u8>,
+) -> anyhow::Result<Vec<u8>> {
+    let nonce = Nonce::try_assume_unique_for_key(nonce)
+        .map_err(|_| anyhow!("Invalid nonce for {} credentials", provider))?;
+    let plaintext = key
+        .open_in_place(nonce, associated_data(provider, account), &mut ciphertext)
+        .map_err(|_| anyhow!("Could not decrypt credentials for {}", provider))?;
+    Ok(plaintext.to_vec())
+}
+
+impl CredentialStore {
+    pub fn new(db: Arc<Database>, key: &str) -> anyhow::Result<Self> {
+        Ok(Self {
+            db,
+            key: parse_key(key)?,
+        })
+    }
+
+    /// Credentials are only persisted if there is a key to encrypt them with
+    pub fn from_env(db: Arc<Database>) -> Option<Self> {
+        let key = match env::var("CREDENTIALS_KEY") {
+            Ok(key) => key,
+            Err(_) => {
+                warn!("CREDENTIALS_KEY is not set, provider credentials will not be persisted");
+                return None;
+            }
+        };
+        Some(Self::new(db, &key).expect("Invalid CREDENTIALS_KEY"))
+    }
+
+    pub async fn load(
+        &self,
+        provider: AllProviders,
+        account: &str,
+    ) -> anyhow::Result<Option<ProviderCredentials>> {
+        let row = sqlx::query!(
+            "SELECT nonce, ciphertext FROM provider_credential WHERE provider_name = $1 AND account = $2",
+            provider.to_string(),
+            account
+        )
+        .fetch_optional(&*self.db)
+        .await?;
+        let row = match row {
+            None => return Ok(None),
+            Some(row) => row,
+        };
+        let plaintext = decrypt(&self.key, provider, account, &row.nonce, row.ciphertext)?;
+        Ok(Some(serde_json::from_slice(&plaintext)?))
+    }
+
+    pub async fn save(
+        &self,
+        provider: AllProviders,
+        account: &str,
+        credentials: &ProviderCredentials,
+    ) -> anyhow::Result<()> {
+        let (nonce, ciphertext) =
+            encrypt(&self.key, provider, account, &serde_json::to_vec(credentials)?)?;
+        sqlx::query!(
+            "INSERT INTO provider_credential (provider_name, account, nonce, ciphertext)
+            VALUES ($1, $2, $3, $4)
+            ON CONFLICT (provider_name, account) DO UPDATE SET
+                nonce = excluded.nonce,
+                ciphertext = excluded.ciphertext,
+                updated_at = NOW()",
+            provider.to_string(),
+            account,
+            nonce,
+            ciphertext
+        )
+        .execute(&*self.db)
+        .await?;
+        debug!("Persisted credentials for {} ({})", provider, account);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scraper::AllProviders;
+
+    use super::{decrypt, encrypt, parse_key};
+
+    const KEY: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    #[test]
+    fn credentials_round_trip() {
+        let key = parse_key(KEY).unwrap();
+        let provider = AllProviders::WeverseArtistFeed;
+        let (nonce, ciphertext) = encrypt(&key, provider, "a", b"secret token").unwrap();
+        assert_ne!(ciphertext, b"secret token");
+        let plaintext = decrypt(&key, provider, "a", &nonce, ciphertext.clone()).unwrap();
+        assert_eq!(plaintext, b"secret token");
+        // ciphertexts are bound to their provider and account
+        let other = AllProviders::UnitedCubeArtistFeed;
+        assert!(decrypt(&key, other, "a", &nonce, ciphertext.clone()).is_err());
+        assert!(decrypt(&key, provider, "b", &nonce, ciphertext).is_err());
+    }
+
+    #[test]
+    fn invalid_keys_are_rejected() {
+        assert!(parse_key("not hex").is_err());
+        assert!(parse_key("0001").is_err());
+    }
+}
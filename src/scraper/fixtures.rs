@@ -0,0 +1,276 @@
+//! Offline fixtures for provider and webhook tests. A fixture is a JSON file in `fixtures/`
+//! listing every request a test is expected to make, in order, along with the response served
+//! for it. Providers are pointed at a local server through their [`Upstream`], which replays the
+//! fixture and complains about any request that doesn't line up with it.
+//!
+//! Running the tests with `RECORD_FIXTURES=1` forwards requests to the real sites instead
+//! and overwrites the fixtures with whatever they responded with, so a diff of the fixture
+//! and the failing assertions show exactly what a site changed. Request headers and bodies
+//! are never recorded, but tokens in responses are and have to be scrubbed before committing.
+use std::collections::{BTreeMap, VecDeque};
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use axum::body::Bytes;
+use axum::handler::Handler;
+use axum::http::header::{HeaderName, CONTENT_TYPE, HOST, RETRY_AFTER};
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode, Uri};
+use governor::Quota;
+use nonzero_ext::nonzero;
+use parking_lot::Mutex;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+
+use crate::request::Upstream;
+use crate::scheduler::ProviderRateLimiter;
+use crate::scraper::scraper::{Scrape, ScraperStep};
+use crate::scraper::{AllProviders, ProviderInput, ScopedProvider, ScrapeRequestInput};
+
+/// A single request and the response it got
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exchange {
+    pub method: String,
+    /// the requested url without its scheme like `api.twitter.com/2/timeline/profile/1.json?count=20`
+    pub url: String,
+    pub status: u16,
+    /// only the response headers providers care about are kept
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub headers: BTreeMap<String, String>,
+    /// JSON responses are stored as they are, anything else is stored as a string
+    pub body: serde_json::Value,
+}
+
+impl Exchange {
+    fn response(&self) -> (StatusCode, HeaderMap, String) {
+        let status = StatusCode::from_u16(self.status).expect("Invalid status code in fixture");
+        let mut headers = HeaderMap::new();
+        for (name, value) in &self.headers {
+            headers.insert(
+                name.parse::<HeaderName>().unwrap(),
+                HeaderValue::from_str(value).unwrap(),
+            );
+        }
+        let body = match &self.body {
+            serde_json::Value::String(text) => text.clone(),
+            json => {
+                headers
+                    .entry(CONTENT_TYPE)
+                    .or_insert_with(|| HeaderValue::from_static("application/json"));
+                json.to_string()
+            }
+        };
+        (status, headers, body)
+    }
+}
+
+enum Mode {
+    Replay(VecDeque<Exchange>),
+    Record(Vec<Exchange>),
+}
+
+struct Fixture {
+    mode: Mutex<Mode>,
+    mismatches: Mutex<Vec<String>>,
+    client: Client,
+}
+
+/// The url a request was meant for, the original host is the first segment of the path
+fn requested_url(uri: &Uri) -> String {
+    let path_and_query = uri.path_and_query().map_or("", |path| path.as_str());
+    path_and_query.trim_start_matches('/').to_owned()
+}
+
+fn mismatch(message: String) -> (StatusCode, HeaderMap, String) {
+    (StatusCode::INTERNAL_SERVER_ERROR, HeaderMap::new(), message)
+}
+
+async fn replay(fixture: &Fixture, method: Method, url: String) -> (StatusCode, HeaderMap, String) {
+    let next = match &mut *fixture.mode.lock() {
+        Mode::Replay(exchanges) => exchanges.pop_front(),
+        Mode::Record(_) => unreachable!(),
+    };
+    let message = match next {
+        Some(exchange) if exchange.method == method.as_str() && exchange.url == url => {
+            return exchange.response()
+        }
+        Some(exchange) => format!(
+            "Expected {} {} but got {} {}",
+            exchange.method, exchange.url, method, url
+        ),
+        None => format!(
+            "Unexpected request {} {} after the fixture ended",
+            method, url
+        ),
+    };
+    fixture.mismatches.lock().push(message.clone());
+    mismatch(message)
+}
+
+async fn record(
+    fixture: &Fixture,
+    method: Method,
+    url: String,
+    mut headers: HeaderMap,
+    body: Bytes,
+) -> (StatusCode, HeaderMap, String) {
+    headers.remove(HOST);
+    let response = fixture
+        .client
+        .request(method.clone(), format!("https://{}", url))
+        .headers(headers)
+        .body(body)
+        .send()
+        .await;
+    let response = match response {
+        Ok(response) => response,
+        Err(err) => {
+            let message = format!("Could not forward {} {}: {:?}", method, url, err);
+            fixture.mismatches.lock().push(message.clone());
+            return mismatch(message);
+        }
+    };
+    let status = response.status().as_u16();
+    let headers = [CONTENT_TYPE, RETRY_AFTER]
+        .iter()
+        .filter_map(|name| {
+            let value = response.headers().get(name)?.to_str().ok()?;
+            Some((name.to_string(), value.to_owned()))
+        })
+        .collect();
+    let text = response.text().await.unwrap_or_default();
+    let body = serde_json::from_str(&text).unwrap_or(serde_json::Value::String(text));
+    let exchange = Exchange {
+        method: method.to_string(),
+        url,
+        status,
+        headers,
+        body,
+    };
+    let served = exchange.response();
+    if let Mode::Record(exchanges) = &mut *fixture.mode.lock() {
+        exchanges.push(exchange);
+    }
+    served
+}
+
+pub struct FixtureServer {
+    path: PathBuf,
+    address: SocketAddr,
+    fixture: Arc<Fixture>,
+}
+
+impl FixtureServer {
+    /// Serves `fixtures/{name}.json` or records it if `RECORD_FIXTURES` is set
+    pub async fn start(name: &str) -> Self {
+        let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+            .join("fixtures")
+            .join(format!("{}.json", name));
+        let mode = if env::var("RECORD_FIXTURES").is_ok() {
+            Mode::Record(vec![])
+        } else {
+            let contents = fs::read_to_string(&path)
+                .unwrap_or_else(|_| panic!("Missing fixture {}", path.display()));
+            let exchanges: Vec<Exchange> = serde_json::from_str(&contents)
+                .unwrap_or_else(|err| panic!("Invalid fixture {}: {}", path.display(), err));
+            Mode::Replay(exchanges.into())
+        };
+        let fixture = Arc::new(Fixture {
+            mode: Mutex::new(mode),
+            mismatches: Mutex::new(vec![]),
+            client: Client::new(),
+        });
+        let handler_fixture = Arc::clone(&fixture);
+        let handler = move |method: Method, uri: Uri, headers: HeaderMap, body: Bytes| {
+            let fixture = Arc::clone(&handler_fixture);
+            async move {
+                let url = requested_url(&uri);
+                let recording = matches!(&*fixture.mode.lock(), Mode::Record(_));
+                if recording {
+                    record(&fixture, method, url, headers, body).await
+                } else {
+                    replay(&fixture, method, url).await
+                }
+            }
+        };
+        let server = axum::Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0)))
+            .serve(handler.into_make_service());
+        let address = server.local_addr();
+        tokio::spawn(server);
+        Self {
+            path,
+            address,
+            fixture,
+        }
+    }
+
+    pub fn upstream(&self) -> Upstream {
+        Upstream::redirect(&format!("http://{}", self.address))
+    }
+
+    pub fn provider_input(&self) -> ProviderInput {
+        // providers add this to their requests
+        env::set_var("USER_AGENT", "Jiu Scraper (fixtures)");
+        ProviderInput {
+            client: Arc::new(Client::new()),
+            upstream: self.upstream(),
+        }
+    }
+
+    /// Fails the test if a request didn't match the fixture or part of the fixture was never
+    /// requested. Recordings are written to disk instead
+    pub fn finish(self) {
+        let mismatches = self.fixture.mismatches.lock().clone();
+        match &*self.fixture.mode.lock() {
+            Mode::Record(exchanges) => {
+                let json = serde_json::to_string_pretty(exchanges).unwrap();
+                fs::write(&self.path, json + "\n").unwrap();
+            }
+            Mode::Replay(remaining) => {
+                let unused = remaining.iter().map(|exchange| {
+                    format!("Never requested {} {}", exchange.method, exchange.url)
+                });
+                let problems = mismatches.iter().cloned().chain(unused).collect::<Vec<_>>();
+                assert!(problems.is_empty(), "{}", problems.join("\n"));
+            }
+        }
+    }
+}
+
+/// Fixtures are served instantly, there's nothing to rate limit
+pub fn unlimited() -> ProviderRateLimiter {
+    ProviderRateLimiter::new(Quota::per_second(nonzero!(1000u32)), None)
+}
+
+pub fn scoped(name: AllProviders, destination: &str) -> ScopedProvider {
+    ScopedProvider {
+        name,
+        destination: destination.to_owned(),
+        official: false,
+    }
+}
+
+pub fn first_scrape() -> ScrapeRequestInput {
+    ScrapeRequestInput {
+        latest_data: Default::default(),
+        default_name: None,
+        last_scrape: None,
+        is_first_scrape: true,
+    }
+}
+
+/// The identifiers of every post a scrape found, in order
+pub fn scraped_posts(scrape: &Scrape) -> Vec<String> {
+    scrape
+        .requests
+        .iter()
+        .filter_map(|request| match &request.step {
+            ScraperStep::Data(data) => Some(data.posts.iter()),
+            ScraperStep::Error(_) => None,
+        })
+        .flatten()
+        .map(|post| post.unique_identifier.clone())
+        .collect()
+}
@@ -1,3 +1,7 @@
+pub mod credentials;
+#[cfg(test)]
+pub(crate) mod fixtures;
 mod providers;
 pub use providers::*;
+pub mod refresh;
 pub mod scraper;
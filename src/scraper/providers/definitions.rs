@@ -0,0 +1,132 @@
+//! What providers that scrape sites described by definition files instead of code have in
+//! common, see [`super::json_api`] and [`super::html`]. Each kind of definition is loaded from
+//! its own directory when Jiu starts, and resources are scraped with `{definition}|{resource}`
+//! destinations.
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+use lazy_static::lazy_static;
+use log::{error, info, warn};
+use regex::Regex;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+
+use crate::scheduler::{parse_quota, ProviderRateLimiter};
+
+use super::{default_quota, ProviderFailure};
+
+pub const DEFINITION_SEPARATOR: &str = "|";
+
+lazy_static! {
+    static ref ENV_VARIABLE: Regex = Regex::new(r"\$\{([A-Za-z0-9_]+)\}").unwrap();
+}
+
+/// Replaces `${VARIABLE}` with the environment variable so secrets don't have to be in the
+/// definition
+pub fn expand_env(value: &str) -> Result<String, String> {
+    let mut missing = None;
+    let expanded = ENV_VARIABLE.replace_all(value, |captures: &regex::Captures| {
+        env::var(&captures[1]).unwrap_or_else(|_| {
+            missing = Some(captures[1].to_owned());
+            String::new()
+        })
+    });
+    match missing {
+        Some(variable) => Err(format!("{} is not set", variable)),
+        None => Ok(expanded.into_owned()),
+    }
+}
+
+pub fn validate_name(name: &str) -> Result<(), String> {
+    if name.is_empty() || name.contains(DEFINITION_SEPARATOR) {
+        return Err(format!("{} is not a valid name", name));
+    }
+    Ok(())
+}
+
+/// How requests to a defined site are made
+pub struct SiteRequests {
+    pub headers: HeaderMap,
+    /// every site is limited on its own on top of the quota of the provider
+    pub limiter: ProviderRateLimiter,
+}
+
+impl SiteRequests {
+    /// `quota` works like `RATE_LIMIT_*`, one request every 3.5 seconds by default
+    pub fn new(headers: &HashMap<String, String>, quota: Option<&str>) -> Result<Self, String> {
+        let mut header_map = HeaderMap::new();
+        for (name, value) in headers {
+            let name = HeaderName::from_bytes(name.as_bytes()).map_err(|err| err.to_string())?;
+            let value =
+                HeaderValue::from_str(&expand_env(value)?).map_err(|err| err.to_string())?;
+            header_map.insert(name, value);
+        }
+        let quota = match quota {
+            Some(quota) => parse_quota(quota).ok_or_else(|| format!("Invalid quota {}", quota))?,
+            None => default_quota(),
+        };
+        Ok(Self {
+            headers: header_map,
+            limiter: ProviderRateLimiter::new(quota, None),
+        })
+    }
+}
+
+/// The name of the definition a destination belongs to and the resource on its site
+pub fn split_destination(destination: &str) -> Result<(&str, &str), ProviderFailure> {
+    destination
+        .split_once(DEFINITION_SEPARATOR)
+        .ok_or(ProviderFailure::Url)
+}
+
+/// Loads every definition in the directory the environment variable points to, leaving out
+/// the ones that aren't valid
+pub fn load_definitions<T>(
+    variable: &str,
+    parse: impl Fn(&str) -> Result<T, String>,
+    name: impl Fn(&T) -> &str,
+) -> HashMap<String, T> {
+    let directory = match env::var(variable) {
+        Ok(directory) => directory,
+        Err(_) => return HashMap::new(),
+    };
+    let directory = Path::new(&directory);
+    let entries = match fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(err) => {
+            error!(
+                "Could not read provider definitions from {}",
+                directory.display()
+            );
+            error!("{:?}", err);
+            return HashMap::new();
+        }
+    };
+    let mut definitions = HashMap::new();
+    for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+        if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+            continue;
+        }
+        let definition = fs::read_to_string(&path)
+            .map_err(|err| err.to_string())
+            .and_then(|json| parse(&json));
+        match definition {
+            Ok(definition) if definitions.contains_key(name(&definition)) => warn!(
+                "Ignoring {} because {} is already defined",
+                path.display(),
+                name(&definition)
+            ),
+            Ok(definition) => {
+                info!("Loaded the {} provider definition", name(&definition));
+                definitions.insert(name(&definition).to_owned(), definition);
+            }
+            Err(err) => warn!(
+                "Ignoring invalid provider definition {}: {}",
+                path.display(),
+                err
+            ),
+        }
+    }
+    definitions
+}
@@ -0,0 +1,616 @@
+//! Sites that only serve static pages, like agency notice boards and fan sites, are described
+//! by definition files in the `HTML_PROVIDER_DEFINITIONS` directory. A definition has the
+//! domains of the site and the CSS selectors posts are picked out of its pages with.
+//! Resources are scraped under `html.page` with `{definition}|{page url}` destinations, see
+//! the README for what a definition looks like.
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::{sync::Arc, time::Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate, NaiveDateTime};
+use governor::{Jitter, Quota};
+use nonzero_ext::nonzero;
+use reqwest::Client;
+use scraper::{ElementRef, Html, Selector};
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    request::{request_default_headers, successful_response_text, Upstream},
+    scheduler::ProviderRateLimiter,
+    scraper::providers::ProviderMediaType,
+};
+
+use super::definitions::{
+    load_definitions, split_destination, validate_name, SiteRequests, DEFINITION_SEPARATOR,
+};
+use super::*;
+
+/// Tried in order when a definition doesn't say how its dates look
+const DATE_TIME_FORMATS: [&str; 4] = [
+    "%Y-%m-%d %H:%M:%S",
+    "%Y-%m-%d %H:%M",
+    "%Y.%m.%d %H:%M:%S",
+    "%Y.%m.%d %H:%M",
+];
+const DATE_FORMATS: [&str; 4] = ["%Y-%m-%d", "%Y.%m.%d", "%Y/%m/%d", "%Y.%m.%d."];
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct CssSelector(Selector);
+
+impl TryFrom<String> for CssSelector {
+    type Error = String;
+    fn try_from(selector: String) -> Result<Self, Self::Error> {
+        let parsed = Selector::parse(&selector)
+            .map_err(|err| format!("Invalid selector {}: {:?}", selector, err))?;
+        Ok(Self(parsed))
+    }
+}
+
+impl CssSelector {
+    fn first<'a>(&self, element: ElementRef<'a>) -> Option<ElementRef<'a>> {
+        element.select(&self.0).next()
+    }
+}
+
+/// A value inside of a post, either the text of an element or one of its attributes
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum Extract {
+    Text(CssSelector),
+    Attribute {
+        selector: CssSelector,
+        attribute: String,
+    },
+}
+
+impl Extract {
+    fn first_string(&self, post: ElementRef) -> Option<String> {
+        match self {
+            Extract::Text(selector) => selector.first(post).and_then(element_text),
+            Extract::Attribute {
+                selector,
+                attribute,
+            } => post
+                .select(&selector.0)
+                .filter_map(|element| element.value().attr(attribute))
+                .map(str::trim)
+                .find(|value| !value.is_empty())
+                .map(str::to_owned),
+        }
+    }
+
+    /// Dates are usually in the `datetime` attribute of a `<time>` when there is one
+    fn date_string(&self, post: ElementRef) -> Option<String> {
+        match self {
+            Extract::Text(selector) => {
+                let element = selector.first(post)?;
+                match element.value().attr("datetime") {
+                    Some(datetime) => Some(datetime.to_owned()),
+                    None => element_text(element),
+                }
+            }
+            Extract::Attribute { .. } => self.first_string(post),
+        }
+    }
+}
+
+/// Where everything is in a page. Everything other than `posts` and `next_page` is relative
+/// to a post
+#[derive(Debug, Clone, Deserialize)]
+pub struct Selectors {
+    posts: CssSelector,
+    /// elements with a `src`, `srcset` or `data-src`
+    images: CssSelector,
+    /// posts are identified by their link, or their first image without one
+    id: Option<Extract>,
+    /// an element with an `href`
+    link: Option<CssSelector>,
+    body: Option<Extract>,
+    date: Option<Extract>,
+    /// `strftime` format of `date`, which is guessed from what it looks like otherwise
+    date_format: Option<String>,
+    account_name: Option<Extract>,
+    /// an element with a `src`
+    account_avatar: Option<CssSelector>,
+    /// a link to the page of older posts
+    next_page: Option<CssSelector>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Definition {
+    pub name: String,
+    /// Pages are only scraped on these domains
+    domains: Vec<String>,
+    /// The query parameter pages are numbered with, if they are. Next page links are followed
+    /// as they are when this isn't set or the link doesn't have it
+    page_param: Option<String>,
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    quota: Option<String>,
+    selectors: Selectors,
+}
+
+/// Text of an element with whitespace collapsed, since pages are indented all over the place
+fn element_text(element: ElementRef) -> Option<String> {
+    let text = element
+        .text()
+        .flat_map(str::split_whitespace)
+        .collect::<Vec<_>>();
+    (!text.is_empty()).then(|| text.join(" "))
+}
+
+/// The biggest candidate of a `srcset`, judged by its width or pixel density descriptor
+fn largest_srcset(srcset: &str) -> Option<&str> {
+    srcset
+        .split(',')
+        .filter_map(|candidate| {
+            let mut parts = candidate.split_whitespace();
+            let url = parts.next()?;
+            let size = parts
+                .next()
+                .and_then(|descriptor| descriptor.strip_suffix(['w', 'x'])?.parse::<f32>().ok())
+                .unwrap_or(1f32);
+            Some((url, size))
+        })
+        .fold(
+            None,
+            |largest: Option<(&str, f32)>, candidate| match largest {
+                Some(largest) if largest.1 >= candidate.1 => Some(largest),
+                _ => Some(candidate),
+            },
+        )
+        .map(|(url, _)| url)
+}
+
+/// The best source of an image, lazy loaded images keep theirs in `data-src`
+fn image_source<'a>(element: ElementRef<'a>) -> Option<&'a str> {
+    let element = element.value();
+    element
+        .attr("srcset")
+        .and_then(largest_srcset)
+        .or_else(|| element.attr("data-src"))
+        .or_else(|| element.attr("src"))
+        .filter(|src| !src.starts_with("data:"))
+}
+
+pub fn parse_date(date: &str, format: Option<&str>) -> Option<NaiveDateTime> {
+    let date = date.trim();
+    if let Some(format) = format {
+        return NaiveDateTime::parse_from_str(date, format)
+            .ok()
+            .or_else(|| {
+                Some(
+                    NaiveDate::parse_from_str(date, format)
+                        .ok()?
+                        .and_hms(0, 0, 0),
+                )
+            });
+    }
+    DateTime::parse_from_rfc3339(date)
+        .or_else(|_| DateTime::parse_from_rfc2822(date))
+        .map(|date| date.naive_utc())
+        .ok()
+        .or_else(|| {
+            DATE_TIME_FORMATS
+                .iter()
+                .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+        })
+        .or_else(|| {
+            DATE_FORMATS
+                .iter()
+                .find_map(|format| NaiveDate::parse_from_str(date, format).ok())
+                .map(|date| date.and_hms(0, 0, 0))
+        })
+}
+
+/// A definition that's ready to be scraped
+pub struct Site {
+    pub definition: Definition,
+    requests: SiteRequests,
+}
+
+impl Site {
+    pub fn from_definition(definition: Definition) -> Result<Self, String> {
+        validate_name(&definition.name)?;
+        if definition.domains.is_empty() {
+            return Err(format!("{} doesn't have any domains", definition.name));
+        }
+        let requests = SiteRequests::new(&definition.headers, definition.quota.as_deref())?;
+        Ok(Self {
+            definition,
+            requests,
+        })
+    }
+
+    fn matches(&self, url: &str) -> bool {
+        let domains = self
+            .definition
+            .domains
+            .iter()
+            .map(String::as_str)
+            .collect::<Vec<_>>();
+        find_matching_domain(&domains, url).is_some()
+    }
+
+    fn post(&self, post: ElementRef, page_url: &Url) -> Option<ProviderPost> {
+        let selectors = &self.definition.selectors;
+        let url = selectors
+            .link
+            .as_ref()
+            .and_then(|link| link.first(post))
+            .and_then(|link| absolute_url(Some(page_url), link.value().attr("href")?));
+        let mut seen = HashSet::new();
+        let images = post
+            .select(&selectors.images.0)
+            .filter_map(image_source)
+            .filter_map(|src| absolute_url(Some(page_url), src))
+            .filter(|src| seen.insert(src.clone()))
+            .map(|src| ProviderMedia {
+                _type: ProviderMediaType::Image,
+                media_url: src.clone(),
+                reference_url: url.clone(),
+                unique_identifier: src,
+                metadata: None,
+            })
+            .collect::<Vec<_>>();
+        let unique_identifier = selectors
+            .id
+            .as_ref()
+            .and_then(|id| id.first_string(post))
+            .or_else(|| url.clone())
+            .or_else(|| Some(images.first()?.unique_identifier.clone()))?;
+        let string = |extract: &Option<Extract>| {
+            extract
+                .as_ref()
+                .and_then(|extract| extract.first_string(post))
+        };
+        Some(ProviderPost {
+            account: ProviderAccount {
+                name: string(&selectors.account_name)
+                    .unwrap_or_else(|| ProviderAccount::default().name),
+                avatar_url: selectors
+                    .account_avatar
+                    .as_ref()
+                    .and_then(|avatar| avatar.first(post))
+                    .and_then(|avatar| absolute_url(Some(page_url), avatar.value().attr("src")?)),
+            },
+            unique_identifier,
+            images,
+            body: string(&selectors.body),
+            url,
+            post_date: selectors
+                .date
+                .as_ref()
+                .and_then(|date| date.date_string(post))
+                .and_then(|date| parse_date(&date, selectors.date_format.as_deref())),
+            metadata: None,
+        })
+    }
+
+    /// Every post on a page that has images in it
+    pub fn posts(&self, html: &Html, page_url: &Url) -> Vec<ProviderPost> {
+        html.select(&self.definition.selectors.posts.0)
+            .filter_map(|post| self.post(post, page_url))
+            .filter(|post| !post.images.is_empty())
+            .collect()
+    }
+
+    /// The page the next page link points to, which has to be on the same site
+    pub fn next_page(&self, html: &Html, page_url: &Url) -> Option<Pagination> {
+        let selector = self.definition.selectors.next_page.as_ref()?;
+        let next = html
+            .select(&selector.0)
+            .find_map(|link| absolute_url(Some(page_url), link.value().attr("href")?))?;
+        if next == page_url.as_str() || !self.matches(&next) {
+            return None;
+        }
+        let page = self.definition.page_param.as_ref().and_then(|param| {
+            let next = Url::parse(&next).ok()?;
+            let (_, page) = next.query_pairs().find(|(key, _)| key == param)?;
+            page.parse::<i32>().ok()
+        });
+        Some(match page {
+            Some(page) => Pagination::NextPage(page),
+            None => Pagination::NextCursor(next),
+        })
+    }
+}
+
+pub fn parse_definition(json: &str) -> Result<Site, String> {
+    let definition = serde_json::from_str::<Definition>(json).map_err(|err| err.to_string())?;
+    Site::from_definition(definition)
+}
+
+pub struct HtmlPage {
+    pub client: Arc<Client>,
+    pub upstream: Upstream,
+    pub rate_limiter: ProviderRateLimiter,
+    pub sites: HashMap<String, Site>,
+}
+
+impl HtmlPage {
+    /// The site a destination belongs to and the url of its page
+    fn site<'a>(&self, destination: &'a str) -> Result<(&Site, &'a str), ProviderFailure> {
+        let (name, url) = split_destination(destination)?;
+        let site = self
+            .sites
+            .get(name)
+            .ok_or_else(|| ProviderFailure::Other(format!("{} is not defined", name)))?;
+        Ok((site, url))
+    }
+
+    /// Sites shouldn't share domains, pages would go to whichever is found first otherwise
+    fn resolve(&self, url: &str) -> Option<String> {
+        let page = Url::parse(url).ok()?;
+        let site = self.sites.values().find(|site| site.matches(url))?;
+        Some(format!(
+            "{}{}{}",
+            site.definition.name, DEFINITION_SEPARATOR, page
+        ))
+    }
+}
+
+#[async_trait]
+impl RateLimitable for HtmlPage {
+    /// Every definition has a quota of its own, this one is shared by all of them
+    fn quota() -> Quota
+    where
+        Self: Sized,
+    {
+        Quota::per_second(nonzero!(2u32))
+    }
+    fn limiter(&self) -> &ProviderRateLimiter {
+        &self.rate_limiter
+    }
+    async fn wait(&self, key: &str) -> () {
+        if let Ok((site, _)) = self.site(key) {
+            site.requests.limiter.wait(key, default_jitter()).await;
+        }
+        // the jitter was already added by the site
+        self.limiter()
+            .wait(key, Jitter::up_to(std::time::Duration::from_secs(0)))
+            .await
+    }
+}
+
+#[async_trait]
+impl Provider for HtmlPage {
+    fn new(input: ProviderInput) -> Self
+    where
+        Self: Sized,
+    {
+        let sites = load_definitions("HTML_PROVIDER_DEFINITIONS", parse_definition, |site| {
+            &site.definition.name
+        });
+        Self {
+            client: Arc::clone(&input.client),
+            upstream: input.upstream,
+            rate_limiter: Self::rate_limiter(AllProviders::HtmlPage),
+            sites,
+        }
+    }
+
+    fn id(&self) -> AllProviders {
+        AllProviders::HtmlPage
+    }
+
+    /// pages decide how many posts they have
+    fn max_page_size(&self) -> PageSize {
+        PageSize(20)
+    }
+
+    fn default_page_size(&self) -> PageSize {
+        PageSize(20)
+    }
+
+    fn from_provider_destination(
+        &self,
+        destination: &str,
+        _page_size: PageSize,
+        pagination: Option<Pagination>,
+    ) -> Result<ScrapeUrl, ProviderFailure> {
+        let (site, page) = self.site(destination)?;
+        let mut url = Url::parse(page).map_err(|_| ProviderFailure::Url)?;
+        match pagination {
+            None => {}
+            Some(Pagination::NextPage(page)) => {
+                let param = site
+                    .definition
+                    .page_param
+                    .as_ref()
+                    .ok_or(ProviderFailure::Url)?;
+                let query = url
+                    .query_pairs()
+                    .filter(|(key, _)| key != param)
+                    .map(|(key, value)| (key.into_owned(), value.into_owned()))
+                    .collect::<Vec<_>>();
+                url.query_pairs_mut()
+                    .clear()
+                    .extend_pairs(query)
+                    .append_pair(param, &page.to_string());
+            }
+            Some(Pagination::NextCursor(next)) => {
+                if !site.matches(&next) {
+                    return Err(ProviderFailure::Url);
+                }
+                url = Url::parse(&next).map_err(|_| ProviderFailure::Url)?;
+            }
+        }
+        Ok(ScrapeUrl(url.to_string()))
+    }
+
+    async fn unfold(&self, state: ProviderState) -> Result<ProviderStep, ProviderFailure> {
+        let (site, _) = self.site(&state.id)?;
+        let page_url = Url::parse(&state.url.0).map_err(|_| ProviderFailure::Url)?;
+        let instant = Instant::now();
+        let response = self
+            .client
+            .get(self.upstream.url(&state.url.0))
+            .headers(request_default_headers())
+            .headers(site.requests.headers.clone())
+            .send()
+            .await?;
+        let response_delay = instant.elapsed();
+        let response_code = response.status();
+        let body = successful_response_text(response).await?;
+        let html = Html::parse_document(&body);
+        let posts = site.posts(&html, &page_url);
+        let next = site.next_page(&html, &page_url);
+        let result = ProviderResult {
+            posts,
+            response_code,
+            response_delay,
+        };
+        Ok(match next {
+            Some(next) => ProviderStep::Next(result, next),
+            None => ProviderStep::End(result),
+        })
+    }
+
+    fn match_domain(&self, url: &str) -> Option<WorkableDomain> {
+        self.resolve(url)
+            .map(|_| WorkableDomain::ToCanonical(IntrospectableResource(url.to_owned())))
+    }
+
+    /// Pages are scraped from the url they were added with so there's nothing to look up
+    async fn introspect_resource(
+        &self,
+        introspectable: &IntrospectableResource,
+    ) -> Result<CanonicalUrlResolution, ProviderFailure> {
+        match self.resolve(&introspectable.0) {
+            Some(destination) => Ok(CanonicalUrlResolution::Success { destination }),
+            None => Err(ProviderFailure::Url),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use reqwest::Client;
+
+    use crate::request::Upstream;
+    use crate::scraper::fixtures::{first_scrape, scoped, scraped_posts, unlimited, FixtureServer};
+    use crate::scraper::scraper::{scrape, ScraperStep};
+    use crate::scraper::{AllProviders, Pagination, Provider, ProviderInput};
+
+    use super::{largest_srcset, parse_date, parse_definition, HtmlPage};
+
+    const NOTICES: &str = r#"{
+        "name": "notices",
+        "domains": ["board.example.com"],
+        "page_param": "page",
+        "selectors": {
+            "posts": "ul.board > li",
+            "images": ".content img",
+            "id": { "selector": "a.subject", "attribute": "data-id" },
+            "link": "a.subject",
+            "body": "a.subject",
+            "date": "time",
+            "account_name": ".writer",
+            "next_page": "a.next"
+        }
+    }"#;
+
+    fn provider(input: ProviderInput) -> HtmlPage {
+        let site = parse_definition(NOTICES).unwrap();
+        HtmlPage {
+            client: input.client,
+            upstream: input.upstream,
+            rate_limiter: unlimited(),
+            sites: HashMap::from([("notices".to_owned(), site)]),
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_next_page_links() {
+        let server = FixtureServer::start("html/board").await;
+        let provider = provider(server.provider_input());
+        let sp = scoped(
+            AllProviders::HtmlPage,
+            "notices|https://board.example.com/notice",
+        );
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        assert_eq!(result.requests.len(), 2);
+        assert_eq!(scraped_posts(&result), ["103", "101", "100"]);
+        let post = match &result.requests[0].step {
+            ScraperStep::Data(data) => &data.posts[0],
+            step => panic!("Expected the first page to be scraped, got {:?}", step),
+        };
+        let images = post
+            .images
+            .iter()
+            .map(|image| image.media_url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            images,
+            [
+                "https://cdn.example.com/103-large.jpg",
+                "https://board.example.com/files/103b.jpg"
+            ]
+        );
+        assert_eq!(
+            post.url.as_deref(),
+            Some("https://board.example.com/notice/103")
+        );
+        assert_eq!(post.body.as_deref(), Some("Concert photos"));
+        assert_eq!(post.account.name, "Admin");
+        assert_eq!(post.post_date.unwrap().to_string(), "2022-02-16 09:00:00");
+    }
+
+    #[test]
+    fn resolves_urls_and_pages() {
+        let provider = provider(ProviderInput {
+            client: Arc::new(Client::new()),
+            upstream: Upstream::default(),
+        });
+        assert_eq!(
+            provider
+                .resolve("https://board.example.com/notice")
+                .unwrap(),
+            "notices|https://board.example.com/notice"
+        );
+        assert!(provider
+            .match_domain("https://example.com/notice")
+            .is_none());
+        let destination = "notices|https://board.example.com/notice?category=2&page=1";
+        let page = provider
+            .from_provider_destination(
+                destination,
+                provider.default_page_size(),
+                Some(Pagination::NextPage(3)),
+            )
+            .unwrap();
+        assert_eq!(page.0, "https://board.example.com/notice?category=2&page=3");
+        let elsewhere = provider.from_provider_destination(
+            destination,
+            provider.default_page_size(),
+            Some(Pagination::NextCursor("https://example.com/".to_owned())),
+        );
+        assert!(elsewhere.is_err());
+        assert!(parse_definition(&NOTICES.replace("ul.board > li", "ul[")).is_err());
+    }
+
+    #[test]
+    fn picks_sources_and_dates() {
+        assert_eq!(
+            largest_srcset("a.jpg 480w, b.jpg 1080w, c.jpg 720w"),
+            Some("b.jpg")
+        );
+        assert_eq!(largest_srcset("a.jpg, b.jpg 2x"), Some("b.jpg"));
+        let parsed = |date: &str, format| parse_date(date, format).unwrap().to_string();
+        assert_eq!(parsed("2022.02.16", None), "2022-02-16 00:00:00");
+        assert_eq!(parsed("2022-02-16 18:30", None), "2022-02-16 18:30:00");
+        assert_eq!(
+            parsed("16/02/2022", Some("%d/%m/%Y")),
+            "2022-02-16 00:00:00"
+        );
+        assert!(parse_date("yesterday", None).is_none());
+    }
+}
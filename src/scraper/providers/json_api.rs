@@ -0,0 +1,549 @@
+//! Sites with a plain paginated JSON API don't need a provider of their own. They're described
+//! by definition files in the `PROVIDER_DEFINITIONS` directory instead, which are loaded when
+//! Jiu starts. Resources of every definition are scraped under `json_api` with
+//! `{definition}|{id}` destinations, see the README for what a definition looks like.
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::{sync::Arc, time::Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime};
+use governor::{Jitter, Quota};
+use jsonpath_lib::Compiled;
+use nonzero_ext::nonzero;
+use regex::Regex;
+use reqwest::Client;
+use serde::Deserialize;
+use serde_json::Value;
+use url::Url;
+
+use crate::{
+    request::{parse_successful_response, request_default_headers, Upstream},
+    scheduler::ProviderRateLimiter,
+    scraper::providers::ProviderMediaType,
+};
+
+use super::definitions::{
+    load_definitions, split_destination, validate_name, SiteRequests, DEFINITION_SEPARATOR,
+};
+use super::*;
+
+/// Unix timestamps above this are in milliseconds, seconds wouldn't get here until the year 5138
+const UNIX_MILLISECONDS_THRESHOLD: i64 = 100_000_000_000;
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct JsonPath(Compiled);
+
+impl TryFrom<String> for JsonPath {
+    type Error = String;
+    fn try_from(path: String) -> Result<Self, Self::Error> {
+        let compiled = Compiled::compile(&path)
+            .map_err(|err| format!("Invalid JSONPath {}: {}", path, err))?;
+        Ok(Self(compiled))
+    }
+}
+
+impl JsonPath {
+    fn select<'a>(&self, value: &'a Value) -> Vec<&'a Value> {
+        self.0.select(value).unwrap_or_default()
+    }
+
+    fn first_string(&self, value: &Value) -> Option<String> {
+        self.select(value).into_iter().find_map(json_string)
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(try_from = "String")]
+pub struct UrlPattern(Regex);
+
+impl TryFrom<String> for UrlPattern {
+    type Error = String;
+    fn try_from(pattern: String) -> Result<Self, Self::Error> {
+        let regex = Regex::new(&pattern).map_err(|err| err.to_string())?;
+        if !regex.capture_names().any(|name| name == Some("id")) {
+            return Err(format!("{} doesn't have an `id` group", pattern));
+        }
+        Ok(Self(regex))
+    }
+}
+
+fn first_page() -> i32 {
+    1
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "style", rename_all = "snake_case")]
+pub enum PaginationStyle {
+    /// Pages are numbered, the last page is the first one without any posts
+    Page {
+        param: String,
+        #[serde(default = "first_page")]
+        start: i32,
+    },
+    /// Every response has the cursor of the next one until there's nothing left
+    Cursor { param: String, next: JsonPath },
+}
+
+#[derive(Debug, Copy, Clone, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DateFormat {
+    Rfc3339,
+    Rfc2822,
+    Unix,
+    UnixMillis,
+}
+
+/// Where everything is in a response. Everything other than `posts` is relative to a post
+#[derive(Debug, Clone, Deserialize)]
+pub struct Selectors {
+    posts: JsonPath,
+    id: JsonPath,
+    images: JsonPath,
+    videos: Option<JsonPath>,
+    url: Option<JsonPath>,
+    body: Option<JsonPath>,
+    date: Option<JsonPath>,
+    /// dates are guessed from what they look like when this isn't set
+    date_format: Option<DateFormat>,
+    account_name: Option<JsonPath>,
+    account_avatar: Option<JsonPath>,
+}
+
+fn default_max_page_size() -> usize {
+    50
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Definition {
+    pub name: String,
+    /// Urls of resources, with their id in a group named `id`
+    url_pattern: UrlPattern,
+    /// The url posts are requested from, with `{id}` in place of the resource's id
+    endpoint: String,
+    pagination: Option<PaginationStyle>,
+    page_size_param: Option<String>,
+    #[serde(default = "default_max_page_size")]
+    max_page_size: usize,
+    /// Sent with every request. `${VARIABLE}` is replaced with the environment variable so
+    /// secrets don't have to be in the definition
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    /// Same format as `RATE_LIMIT_*`, one request every 3.5 seconds by default
+    quota: Option<String>,
+    selectors: Selectors,
+}
+
+/// A definition that's ready to be scraped
+pub struct Site {
+    pub definition: Definition,
+    requests: SiteRequests,
+}
+
+impl Site {
+    pub fn from_definition(definition: Definition) -> Result<Self, String> {
+        validate_name(&definition.name)?;
+        Url::parse(&definition.endpoint.replace("{id}", "id"))
+            .map_err(|err| format!("Invalid endpoint {}: {}", definition.endpoint, err))?;
+        let requests = SiteRequests::new(&definition.headers, definition.quota.as_deref())?;
+        Ok(Self {
+            definition,
+            requests,
+        })
+    }
+
+    fn posts(&self, response: &Value, endpoint: Option<&Url>) -> Vec<ProviderPost> {
+        let selectors = &self.definition.selectors;
+        selectors
+            .posts
+            .select(response)
+            .into_iter()
+            .filter_map(|post| {
+                let url = selectors
+                    .url
+                    .as_ref()
+                    .and_then(|url| url.first_string(post));
+                let media = |selector: Option<&JsonPath>, _type: ProviderMediaType| {
+                    selector
+                        .map(|selector| selector.select(post))
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(json_string)
+                        .filter_map(|media| match endpoint {
+                            Some(endpoint) => endpoint.join(&media).ok().map(String::from),
+                            None => Some(media),
+                        })
+                        .map(|media| ProviderMedia {
+                            _type: _type.clone(),
+                            media_url: media.clone(),
+                            reference_url: url.clone(),
+                            unique_identifier: media,
+                            metadata: None,
+                        })
+                        .collect::<Vec<_>>()
+                };
+                let images = [
+                    media(Some(&selectors.images), ProviderMediaType::Image),
+                    media(selectors.videos.as_ref(), ProviderMediaType::Video),
+                ]
+                .concat();
+                if images.is_empty() {
+                    return None;
+                }
+                let string = |selector: &Option<JsonPath>| {
+                    selector
+                        .as_ref()
+                        .and_then(|selector| selector.first_string(post))
+                };
+                Some(ProviderPost {
+                    account: ProviderAccount {
+                        name: string(&selectors.account_name)
+                            .unwrap_or_else(|| ProviderAccount::default().name),
+                        avatar_url: string(&selectors.account_avatar),
+                    },
+                    unique_identifier: selectors.id.first_string(post)?,
+                    images,
+                    body: string(&selectors.body),
+                    url,
+                    post_date: string(&selectors.date)
+                        .and_then(|date| parse_date(&date, selectors.date_format)),
+                    metadata: None,
+                })
+            })
+            .collect()
+    }
+
+    /// The page after the one that was just scraped, if there is one
+    fn next_page(&self, response: &Value, current: Option<&Pagination>) -> Option<Pagination> {
+        match self.definition.pagination.as_ref()? {
+            PaginationStyle::Page { start, .. } => {
+                let posts = self.definition.selectors.posts.select(response);
+                if posts.is_empty() {
+                    return None;
+                }
+                let page = match current {
+                    Some(Pagination::NextPage(page)) => *page,
+                    _ => *start,
+                };
+                Some(Pagination::NextPage(page + 1))
+            }
+            PaginationStyle::Cursor { next, .. } => {
+                next.first_string(response).map(Pagination::NextCursor)
+            }
+        }
+    }
+}
+
+fn json_string(value: &Value) -> Option<String> {
+    match value {
+        Value::String(string) if !string.is_empty() => Some(string.clone()),
+        Value::Number(number) => Some(number.to_string()),
+        _ => None,
+    }
+}
+
+fn from_unix(timestamp: i64, millis: bool) -> Option<NaiveDateTime> {
+    if millis {
+        NaiveDateTime::from_timestamp_opt(
+            timestamp.div_euclid(1000),
+            (timestamp.rem_euclid(1000) * 1_000_000) as u32,
+        )
+    } else {
+        NaiveDateTime::from_timestamp_opt(timestamp, 0)
+    }
+}
+
+pub fn parse_date(date: &str, format: Option<DateFormat>) -> Option<NaiveDateTime> {
+    let date = date.trim();
+    match format {
+        Some(DateFormat::Rfc3339) => DateTime::parse_from_rfc3339(date)
+            .ok()
+            .map(|d| d.naive_utc()),
+        Some(DateFormat::Rfc2822) => DateTime::parse_from_rfc2822(date)
+            .ok()
+            .map(|d| d.naive_utc()),
+        Some(DateFormat::Unix) => from_unix(date.parse().ok()?, false),
+        Some(DateFormat::UnixMillis) => from_unix(date.parse().ok()?, true),
+        None => match date.parse::<i64>() {
+            Ok(timestamp) => from_unix(timestamp, timestamp > UNIX_MILLISECONDS_THRESHOLD),
+            Err(_) => DateTime::parse_from_rfc3339(date)
+                .or_else(|_| DateTime::parse_from_rfc2822(date))
+                .ok()
+                .map(|date| date.naive_utc()),
+        },
+    }
+}
+
+pub fn parse_definition(json: &str) -> Result<Site, String> {
+    let definition = serde_json::from_str::<Definition>(json).map_err(|err| err.to_string())?;
+    Site::from_definition(definition)
+}
+
+pub struct JsonApiProvider {
+    pub client: Arc<Client>,
+    pub upstream: Upstream,
+    pub rate_limiter: ProviderRateLimiter,
+    pub sites: HashMap<String, Site>,
+}
+
+impl JsonApiProvider {
+    /// The site a destination belongs to and the id of the resource on it
+    fn site<'a>(&self, destination: &'a str) -> Result<(&Site, &'a str), ProviderFailure> {
+        let (name, id) = split_destination(destination)?;
+        let site = self
+            .sites
+            .get(name)
+            .ok_or_else(|| ProviderFailure::Other(format!("{} is not defined", name)))?;
+        Ok((site, id))
+    }
+
+    fn resolve(&self, url: &str) -> Option<String> {
+        self.sites.values().find_map(|site| {
+            let captures = site.definition.url_pattern.0.captures(url)?;
+            Some(format!(
+                "{}{}{}",
+                site.definition.name,
+                DEFINITION_SEPARATOR,
+                captures.name("id")?.as_str()
+            ))
+        })
+    }
+}
+
+#[async_trait]
+impl RateLimitable for JsonApiProvider {
+    /// Every definition has a quota of its own, this one is shared by all of them
+    fn quota() -> Quota
+    where
+        Self: Sized,
+    {
+        Quota::per_second(nonzero!(2u32))
+    }
+    fn limiter(&self) -> &ProviderRateLimiter {
+        &self.rate_limiter
+    }
+    async fn wait(&self, key: &str) -> () {
+        if let Ok((site, _)) = self.site(key) {
+            site.requests.limiter.wait(key, default_jitter()).await;
+        }
+        // the jitter was already added by the site
+        self.limiter()
+            .wait(key, Jitter::up_to(std::time::Duration::from_secs(0)))
+            .await
+    }
+}
+
+#[async_trait]
+impl Provider for JsonApiProvider {
+    fn new(input: ProviderInput) -> Self
+    where
+        Self: Sized,
+    {
+        let sites = load_definitions("PROVIDER_DEFINITIONS", parse_definition, |site| {
+            &site.definition.name
+        });
+        Self {
+            client: Arc::clone(&input.client),
+            upstream: input.upstream,
+            rate_limiter: Self::rate_limiter(AllProviders::JsonApi),
+            sites,
+        }
+    }
+
+    fn id(&self) -> AllProviders {
+        AllProviders::JsonApi
+    }
+
+    /// definitions can lower this with `max_page_size`
+    fn max_page_size(&self) -> PageSize {
+        PageSize(100)
+    }
+
+    fn default_page_size(&self) -> PageSize {
+        PageSize(20)
+    }
+
+    fn from_provider_destination(
+        &self,
+        destination: &str,
+        page_size: PageSize,
+        pagination: Option<Pagination>,
+    ) -> Result<ScrapeUrl, ProviderFailure> {
+        let (site, id) = self.site(destination)?;
+        let definition = &site.definition;
+        let mut url = Url::parse(&definition.endpoint.replace("{id}", id))
+            .map_err(|_| ProviderFailure::Url)?;
+        if let Some(param) = &definition.page_size_param {
+            let page_size = page_size.0.min(definition.max_page_size);
+            url.query_pairs_mut()
+                .append_pair(param, &page_size.to_string());
+        }
+        match (&definition.pagination, pagination) {
+            (Some(PaginationStyle::Page { param, start }), None) => {
+                url.query_pairs_mut().append_pair(param, &start.to_string());
+            }
+            (Some(PaginationStyle::Page { param, .. }), Some(page))
+            | (Some(PaginationStyle::Cursor { param, .. }), Some(page)) => {
+                url.query_pairs_mut().append_pair(param, &page.next_page());
+            }
+            _ => {}
+        }
+        Ok(ScrapeUrl(url.to_string()))
+    }
+
+    async fn unfold(&self, state: ProviderState) -> Result<ProviderStep, ProviderFailure> {
+        let (site, _) = self.site(&state.id)?;
+        let instant = Instant::now();
+        let response = self
+            .client
+            .get(self.upstream.url(&state.url.0))
+            .headers(request_default_headers())
+            .headers(site.requests.headers.clone())
+            .send()
+            .await?;
+        let response_delay = instant.elapsed();
+        let response_code = response.status();
+        let json = parse_successful_response::<Value>(response).await?;
+        let endpoint = Url::parse(&state.url.0).ok();
+        let result = ProviderResult {
+            posts: site.posts(&json, endpoint.as_ref()),
+            response_code,
+            response_delay,
+        };
+        Ok(match site.next_page(&json, state.pagination.as_ref()) {
+            Some(next) => ProviderStep::Next(result, next),
+            None => ProviderStep::End(result),
+        })
+    }
+
+    fn match_domain(&self, url: &str) -> Option<WorkableDomain> {
+        self.resolve(url)
+            .map(|_| WorkableDomain::ToCanonical(IntrospectableResource(url.to_owned())))
+    }
+
+    /// Resources are identified by their url alone so there's nothing to look up
+    async fn introspect_resource(
+        &self,
+        introspectable: &IntrospectableResource,
+    ) -> Result<CanonicalUrlResolution, ProviderFailure> {
+        match self.resolve(&introspectable.0) {
+            Some(destination) => Ok(CanonicalUrlResolution::Success { destination }),
+            None => Err(ProviderFailure::Url),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::env;
+    use std::sync::Arc;
+
+    use reqwest::Client;
+
+    use crate::request::Upstream;
+    use crate::scraper::fixtures::{first_scrape, scoped, scraped_posts, unlimited, FixtureServer};
+    use crate::scraper::scraper::{scrape, ScraperStep};
+    use crate::scraper::{AllProviders, Pagination, Provider, ProviderInput, WorkableDomain};
+
+    use super::{parse_date, parse_definition, DateFormat, JsonApiProvider};
+
+    const GALLERY: &str = r#"{
+        "name": "gallery",
+        "url_pattern": "^https://gallery\\.example\\.com/artists/(?P<id>[0-9]+)",
+        "endpoint": "https://api.gallery.example.com/v2/artists/{id}/photos",
+        "pagination": { "style": "cursor", "param": "after", "next": "$.paging.next" },
+        "page_size_param": "limit",
+        "max_page_size": 2,
+        "headers": { "X-Api-Key": "${JIU_TEST_GALLERY_KEY}" },
+        "quota": "10/1s",
+        "selectors": {
+            "posts": "$.data[*]",
+            "id": "$.id",
+            "images": "$.photos[*].src",
+            "videos": "$.clip",
+            "url": "$.permalink",
+            "date": "$.created_at",
+            "account_name": "$.artist.name"
+        }
+    }"#;
+
+    fn provider(input: ProviderInput) -> JsonApiProvider {
+        env::set_var("JIU_TEST_GALLERY_KEY", "secret");
+        let site = parse_definition(GALLERY).unwrap();
+        JsonApiProvider {
+            client: input.client,
+            upstream: input.upstream,
+            rate_limiter: unlimited(),
+            sites: HashMap::from([("gallery".to_owned(), site)]),
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_cursors_of_defined_sites() {
+        let server = FixtureServer::start("json_api/cursor").await;
+        let provider = provider(server.provider_input());
+        let sp = scoped(AllProviders::JsonApi, "gallery|42");
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        assert_eq!(result.requests.len(), 2);
+        assert_eq!(scraped_posts(&result), ["3", "2", "1"]);
+        let post = match &result.requests[0].step {
+            ScraperStep::Data(data) => &data.posts[0],
+            step => panic!("Expected the first page to be scraped, got {:?}", step),
+        };
+        assert_eq!(post.images.len(), 3);
+        assert_eq!(
+            post.images[1].media_url,
+            "https://api.gallery.example.com/media/3b.jpg"
+        );
+        assert_eq!(post.account.name, "Example");
+        assert_eq!(post.post_date.unwrap().to_string(), "2022-02-16 12:00:00");
+    }
+
+    #[test]
+    fn resolves_urls_and_pages() {
+        let provider = provider(ProviderInput {
+            client: Arc::new(Client::new()),
+            upstream: Upstream::default(),
+        });
+        let url = "https://gallery.example.com/artists/42?tab=photos";
+        assert!(matches!(
+            provider.match_domain(url),
+            Some(WorkableDomain::ToCanonical(_))
+        ));
+        assert!(provider
+            .match_domain("https://gallery.example.com/about")
+            .is_none());
+        assert_eq!(provider.resolve(url).unwrap(), "gallery|42");
+        let next = provider
+            .from_provider_destination(
+                "gallery|42",
+                provider.default_page_size(),
+                Some(Pagination::NextCursor("abc".to_owned())),
+            )
+            .unwrap();
+        assert_eq!(
+            next.0,
+            "https://api.gallery.example.com/v2/artists/42/photos?limit=2&after=abc"
+        );
+        assert!(parse_definition(&GALLERY.replace("?P<id>", "")).is_err());
+    }
+
+    #[test]
+    fn guesses_date_formats() {
+        let parsed = |date: &str, format| parse_date(date, format).unwrap().to_string();
+        assert_eq!(parsed("1644969600", None), "2022-02-16 00:00:00");
+        assert_eq!(parsed("1644969600500", None), "2022-02-16 00:00:00.500");
+        assert_eq!(
+            parsed("2022-02-16T09:00:00+09:00", None),
+            "2022-02-16 00:00:00"
+        );
+        assert_eq!(
+            parsed("1644969600", Some(DateFormat::UnixMillis)),
+            "1970-01-20 00:56:09.600"
+        );
+        assert!(parse_date("yesterday", None).is_none());
+    }
+}
@@ -0,0 +1,385 @@
+//! Accounts on any Mastodon instance, scraped from the public statuses of the account.
+//! Destinations are `{instance}|{account id}` since ids are only unique to an instance.
+use std::{sync::Arc, time::Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use governor::Quota;
+use nonzero_ext::nonzero;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    request::{parse_successful_response, request_default_headers, Upstream},
+    scheduler::ProviderRateLimiter,
+    scraper::providers::ProviderMediaType,
+};
+
+use super::*;
+
+const DESTINATION_SEPARATOR: char = '|';
+
+pub struct MastodonAccount {
+    pub client: Arc<Client>,
+    pub upstream: Upstream,
+    pub rate_limiter: ProviderRateLimiter,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum AttachmentType {
+    Image,
+    Video,
+    Gifv,
+    Audio,
+    Unknown,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct MastodonMediaMetadata {
+    width: i64,
+    height: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct AttachmentMeta {
+    original: Option<MastodonMediaMetadata>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Attachment {
+    id: String,
+    #[serde(rename = "type")]
+    _type: AttachmentType,
+    /// missing while an instance is still downloading media from other instances
+    url: Option<String>,
+    remote_url: Option<String>,
+    meta: Option<AttachmentMeta>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Account {
+    id: String,
+    username: String,
+    display_name: String,
+    avatar: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Status {
+    id: String,
+    created_at: DateTime<Utc>,
+    url: Option<String>,
+    uri: String,
+    /// This is HTML
+    content: String,
+    account: Account,
+    media_attachments: Vec<Attachment>,
+}
+
+impl Status {
+    fn into_post(self) -> Option<ProviderPost> {
+        let url = self.url.unwrap_or(self.uri);
+        let images = self
+            .media_attachments
+            .into_iter()
+            .filter_map(|attachment| {
+                let _type = match attachment._type {
+                    AttachmentType::Image => ProviderMediaType::Image,
+                    AttachmentType::Video | AttachmentType::Gifv => ProviderMediaType::Video,
+                    AttachmentType::Audio | AttachmentType::Unknown => return None,
+                };
+                Some(ProviderMedia {
+                    _type,
+                    media_url: attachment.url.or(attachment.remote_url)?,
+                    reference_url: Some(url.clone()),
+                    unique_identifier: attachment.id,
+                    metadata: attachment
+                        .meta
+                        .and_then(|meta| meta.original)
+                        .and_then(|original| serde_json::to_value(original).ok()),
+                })
+            })
+            .collect::<Vec<_>>();
+        if images.is_empty() {
+            return None;
+        }
+        let account = self.account;
+        Some(ProviderPost {
+            account: ProviderAccount {
+                name: if account.display_name.is_empty() {
+                    account.username
+                } else {
+                    account.display_name
+                },
+                avatar_url: account.avatar,
+            },
+            unique_identifier: self.id,
+            images,
+            body: Some(self.content).filter(|content| !content.is_empty()),
+            url: Some(url),
+            post_date: Some(self.created_at.naive_utc()),
+            metadata: None,
+        })
+    }
+}
+
+/// The instance of a destination and the id of the account on it
+fn split_destination(destination: &str) -> Result<(&str, &str), ProviderFailure> {
+    let (instance, id) = destination
+        .split_once(DESTINATION_SEPARATOR)
+        .ok_or(ProviderFailure::Url)?;
+    // the instance ends up in urls so it can't be anything other than a host
+    let host = Url::parse(&format!("https://{}", instance)).map_err(|_| ProviderFailure::Url)?;
+    if host.host_str() != Some(instance) || id.is_empty() {
+        return Err(ProviderFailure::Url);
+    }
+    Ok((instance, id))
+}
+
+/// The instance and username of a profile url like `https://instance/@user`. Profiles of
+/// accounts from other instances look like `https://instance/@user@home.instance`
+fn profile(url: &str) -> Option<(String, String)> {
+    let parsed = Url::parse(url).ok()?;
+    if parsed.scheme() != "https" {
+        return None;
+    }
+    let segments = parsed
+        .path_segments()?
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    let handle = match segments.as_slice() {
+        [handle] => handle.strip_prefix('@')?,
+        _ => return None,
+    };
+    match handle.split_once('@') {
+        Some((username, instance)) if !username.is_empty() && !instance.is_empty() => {
+            Some((instance.to_owned(), username.to_owned()))
+        }
+        Some(_) => None,
+        None if !handle.is_empty() => Some((parsed.host_str()?.to_owned(), handle.to_owned())),
+        None => None,
+    }
+}
+
+#[async_trait]
+impl RateLimitable for MastodonAccount {
+    /// Accounts are spread out across many instances, every instance is limited on its own
+    fn quota() -> Quota
+    where
+        Self: Sized,
+    {
+        Quota::per_second(nonzero!(2u32))
+    }
+    fn scoped_quota() -> Option<Quota>
+    where
+        Self: Sized,
+    {
+        Some(default_quota())
+    }
+    fn limiter(&self) -> &ProviderRateLimiter {
+        &self.rate_limiter
+    }
+    async fn wait(&self, key: &str) -> () {
+        let instance = key
+            .split_once(DESTINATION_SEPARATOR)
+            .map_or(key, |(instance, _)| instance);
+        self.limiter().wait(instance, default_jitter()).await
+    }
+}
+
+#[async_trait]
+impl Provider for MastodonAccount {
+    fn new(input: ProviderInput) -> Self
+    where
+        Self: Sized,
+    {
+        Self {
+            client: Arc::clone(&input.client),
+            upstream: input.upstream,
+            rate_limiter: Self::rate_limiter(AllProviders::MastodonAccount),
+        }
+    }
+
+    fn id(&self) -> AllProviders {
+        AllProviders::MastodonAccount
+    }
+
+    fn max_page_size(&self) -> PageSize {
+        PageSize(40)
+    }
+
+    fn default_page_size(&self) -> PageSize {
+        PageSize(20)
+    }
+
+    fn from_provider_destination(
+        &self,
+        id: &str,
+        page_size: PageSize,
+        pagination: Option<Pagination>,
+    ) -> Result<ScrapeUrl, ProviderFailure> {
+        let (instance, account) = split_destination(id)?;
+        // boosts are posts of other accounts
+        let mut next_url =
+            UrlBuilder::from_queries(vec![("only_media", "true"), ("exclude_reblogs", "true")]);
+        next_url.page_size("limit", page_size);
+        next_url.pagination("max_id", &pagination);
+        next_url.build_scrape_url(&format!(
+            "https://{}/api/v1/accounts/{}/statuses",
+            instance, account
+        ))
+    }
+
+    async fn unfold(&self, state: ProviderState) -> Result<ProviderStep, ProviderFailure> {
+        let instant = Instant::now();
+        let response = self
+            .client
+            .get(self.upstream.url(&state.url.0))
+            .headers(request_default_headers())
+            .send()
+            .await?;
+        let response_delay = instant.elapsed();
+        let response_code = response.status();
+        let statuses = parse_successful_response::<Vec<Status>>(response).await?;
+        // statuses without anything we can scrape still move the cursor along
+        let next = statuses
+            .last()
+            .map(|status| Pagination::NextCursor(status.id.clone()));
+        let result = ProviderResult {
+            posts: statuses.into_iter().filter_map(Status::into_post).collect(),
+            response_code,
+            response_delay,
+        };
+        Ok(match next {
+            Some(next) => ProviderStep::Next(result, next),
+            None => ProviderStep::End(result),
+        })
+    }
+
+    /// Any site could be an instance, dedicated providers get to look at urls first. Profiles
+    /// link to a feed of the account, so this has to come before `rss.feed`
+    fn generic(&self) -> Option<u8> {
+        Some(0)
+    }
+
+    fn match_domain(&self, url: &str) -> Option<WorkableDomain> {
+        profile(url).map(|_| WorkableDomain::ToCanonical(IntrospectableResource(url.to_owned())))
+    }
+
+    /// Usernames are looked up on the instance the account is from
+    async fn introspect_resource(
+        &self,
+        introspectable: &IntrospectableResource,
+    ) -> Result<CanonicalUrlResolution, ProviderFailure> {
+        let (instance, username) = profile(&introspectable.0).ok_or(ProviderFailure::Url)?;
+        let url = Url::parse_with_params(
+            &format!("https://{}/api/v1/accounts/lookup", instance),
+            &[("acct", &username)],
+        )
+        .map_err(|_| ProviderFailure::Url)?;
+        let response = self
+            .client
+            .get(self.upstream.url(url.as_str()))
+            .headers(request_default_headers())
+            .send()
+            .await?;
+        let account = parse_successful_response::<Account>(response).await?;
+        let destination = format!("{}{}{}", instance, DESTINATION_SEPARATOR, account.id);
+        split_destination(&destination)?;
+        Ok(CanonicalUrlResolution::Success { destination })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scraper::fixtures::{first_scrape, scoped, scraped_posts, unlimited, FixtureServer};
+    use crate::scraper::scraper::{scrape, ScraperStep};
+    use crate::scraper::{
+        AllProviders, CanonicalUrlResolution, IntrospectableResource, Provider, ProviderMediaType,
+    };
+
+    use super::{profile, split_destination, MastodonAccount};
+
+    fn provider(server: &FixtureServer) -> MastodonAccount {
+        let input = server.provider_input();
+        MastodonAccount {
+            client: input.client,
+            upstream: input.upstream,
+            rate_limiter: unlimited(),
+        }
+    }
+
+    #[tokio::test]
+    async fn scrapes_media_attachments() {
+        let server = FixtureServer::start("mastodon/statuses").await;
+        let provider = provider(&server);
+        let sp = scoped(AllProviders::MastodonAccount, "mastodon.example|1090");
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        assert_eq!(result.requests.len(), 2);
+        // the status with only an audio attachment is left out
+        assert_eq!(
+            scraped_posts(&result),
+            ["107812340000000003", "107812340000000001"]
+        );
+        let posts = match &result.requests[0].step {
+            ScraperStep::Data(data) => &data.posts,
+            step => panic!("Expected the first page to be scraped, got {:?}", step),
+        };
+        let image = &posts[0].images[0];
+        assert!(matches!(image._type, ProviderMediaType::Image));
+        assert_eq!(
+            image.metadata,
+            Some(serde_json::json!({ "width": 1080, "height": 1350 }))
+        );
+        assert_eq!(posts[0].account.name, "Example Artist");
+        assert_eq!(
+            posts[0].post_date.unwrap().to_string(),
+            "2022-02-16 12:00:00"
+        );
+        let media = posts[1]
+            .images
+            .iter()
+            .map(|media| media.media_url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            media,
+            [
+                "https://files.mastodon.example/media_attachments/files/2/original.mp4",
+                "https://files.art.example/media/3.mp4"
+            ]
+        );
+        assert!(posts[1]
+            .images
+            .iter()
+            .all(|media| matches!(media._type, ProviderMediaType::Video)));
+    }
+
+    #[tokio::test]
+    async fn looks_up_profiles() {
+        let server = FixtureServer::start("mastodon/lookup").await;
+        let provider = provider(&server);
+        let resolve = |url: &str| IntrospectableResource(url.to_owned());
+        for (url, expected) in [
+            ("https://mastodon.example/@artist", "mastodon.example|1090"),
+            (
+                "https://mastodon.example/@archive@art.example",
+                "art.example|24",
+            ),
+        ] {
+            assert!(provider.match_domain(url).is_some());
+            match provider.introspect_resource(&resolve(url)).await.unwrap() {
+                CanonicalUrlResolution::Success { destination } => {
+                    assert_eq!(destination, expected)
+                }
+                _ => panic!("Could not resolve {}", url),
+            }
+        }
+        server.finish();
+        assert!(profile("https://mastodon.example/@artist/107812340000000003").is_none());
+        assert!(profile("https://mastodon.example/about").is_none());
+        assert!(split_destination("mastodon.example/evil|1090").is_err());
+    }
+}
@@ -1,21 +1,38 @@
 use std::collections::HashMap;
-use std::env;
 use std::fmt::Display;
 use std::iter::FromIterator;
 use std::sync::Arc;
 
 use futures::future::join_all;
+use log::error;
 use reqwest::Client;
 use strum::IntoEnumIterator;
 
+use crate::request::Upstream;
+use crate::roles::{Role, Roles};
+use crate::scraper::credentials::CredentialStore;
+
+pub use html::HtmlPage;
+pub use json_api::*;
+pub use mastodon::*;
 pub use pinterest::*;
+pub use pool::*;
 pub use providers::*;
+pub use reddit::*;
+pub use rss::*;
 pub use twitter::*;
 pub use united_cube::*;
 pub use weverse::*;
 
+pub mod definitions;
+pub mod html;
+pub mod json_api;
+pub mod mastodon;
 pub mod pinterest;
+mod pool;
 mod providers;
+pub mod reddit;
+pub mod rss;
 pub mod twitter;
 mod twitter_types;
 pub mod united_cube;
@@ -40,30 +57,147 @@ pub struct ScopedProvider {
 
 impl Display for ScopedProvider {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_str(&format!("{}:{}", self.name.to_string(), self.destination))
+        f.write_str(&format!("{}:{}", self.name, self.destination))
     }
 }
 
 pub type ProviderMap = HashMap<AllProviders, Box<dyn Provider>>;
 
-pub async fn get_provider_map(client: &Arc<Client>) -> anyhow::Result<ProviderMap> {
+/// Loads the stored credentials of a provider's accounts into memory so they don't have to log in again
+async fn load_credentials(provider: &dyn Provider, store: &CredentialStore) {
+    let pool = match provider.credentials() {
+        Some(pool) => pool,
+        None => return,
+    };
+    for account in pool.accounts() {
+        // credentials given through the environment take precedence
+        if account.credentials.read().is_some() {
+            continue;
+        }
+        match store.load(provider.id(), &account.name).await {
+            Ok(Some(stored)) => *account.credentials.write() = Some(stored),
+            Ok(None) => {}
+            Err(err) => {
+                error!(
+                    "Could not load stored credentials for {} ({})",
+                    provider.id(),
+                    account.name
+                );
+                error!("{:?}", err);
+            }
+        }
+    }
+}
+
+/// Persists credentials that were created while initializing a provider
+async fn save_credentials(provider: &dyn Provider, store: &CredentialStore) {
+    let pool = match provider.credentials() {
+        Some(pool) => pool,
+        None => return,
+    };
+    for account in pool.accounts() {
+        let credentials = match account.credentials.read().clone() {
+            Some(credentials) => credentials,
+            None => continue,
+        };
+        if let Err(err) = store.save(provider.id(), &account.name, &credentials).await {
+            error!(
+                "Could not persist credentials for {} ({})",
+                provider.id(),
+                account.name
+            );
+            error!("{:?}", err);
+        }
+    }
+}
+
+/// The provider a url should be added to. Providers made for a specific site win over the
+/// generic ones, and the order is the same every time no matter how the map is laid out
+pub fn provider_for_url<'a>(
+    providers: &'a ProviderMap,
+    url: &str,
+) -> Option<(&'a dyn Provider, WorkableDomain)> {
+    let mut providers = providers.values().collect::<Vec<_>>();
+    providers.sort_by_key(|p| (p.generic(), p.id().to_string()));
+    providers
+        .into_iter()
+        .find_map(|p| p.match_domain(url).map(|domain| (&**p, domain)))
+}
+
+pub async fn get_provider_map(
+    client: &Arc<Client>,
+    store: Option<&CredentialStore>,
+) -> anyhow::Result<ProviderMap> {
     let handles = AllProviders::iter().map(|provider_type| async move {
         let client = Arc::clone(client);
-        let input = ProviderInput { client };
+        let input = ProviderInput {
+            client,
+            upstream: Upstream::default(),
+        };
         let provider: Box<dyn Provider> = match provider_type {
             AllProviders::PinterestBoardFeed => Box::new(PinterestBoardFeed::new(input)),
             AllProviders::WeverseArtistFeed => Box::new(WeverseArtistFeed::new(input)),
             AllProviders::UnitedCubeArtistFeed => Box::new(UnitedCubeArtistFeed::new(input)),
             AllProviders::TwitterTimeline => Box::new(TwitterTimeline::new(input)),
+            AllProviders::RssFeed => Box::new(RssFeed::new(input)),
+            AllProviders::JsonApi => Box::new(JsonApiProvider::new(input)),
+            AllProviders::HtmlPage => Box::new(HtmlPage::new(input)),
+            AllProviders::RedditSubmissions => Box::new(RedditSubmissions::new(input)),
+            AllProviders::MastodonAccount => Box::new(MastodonAccount::new(input)),
         };
-        // we should only initialize providers if NO_WORKER is not set
-        // this is not typesafe so we should be careful to not try to do
-        // authenticated requests when workers are off
-        if let Err(_) = env::var("NO_WORKER") {
+        // we should only initialize providers if this process scrapes, which workers do and
+        // servers do for scrapes asked for through the API. This is not typesafe so we should
+        // be careful to not try to do authenticated requests from planners
+        let roles = Roles::from_env();
+        if roles.has(Role::Worker) || roles.has(Role::Server) {
+            if let Some(store) = store {
+                load_credentials(&*provider, store).await;
+            }
             provider.initialize().await;
+            if let Some(store) = store {
+                save_credentials(&*provider, store).await;
+            }
         }
         (provider_type, provider)
     });
     let results = join_all(handles).await;
     Ok(HashMap::from_iter(results))
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use reqwest::Client;
+
+    use crate::request::Upstream;
+    use crate::scraper::{AllProviders, MastodonAccount, Provider, ProviderInput, RssFeed};
+
+    use super::{provider_for_url, ProviderMap};
+
+    fn input() -> ProviderInput {
+        ProviderInput {
+            client: Arc::new(Client::new()),
+            upstream: Upstream::default(),
+        }
+    }
+
+    #[test]
+    fn generic_providers_are_matched_in_order() {
+        // maps are laid out differently every time, so a few of them are tried
+        for _ in 0..20 {
+            let mut providers = ProviderMap::new();
+            providers.insert(AllProviders::RssFeed, Box::new(RssFeed::new(input())));
+            providers.insert(
+                AllProviders::MastodonAccount,
+                Box::new(MastodonAccount::new(input())),
+            );
+            let matched = |url: &str| provider_for_url(&providers, url).unwrap().0.id();
+            assert_eq!(
+                matched("https://mastodon.example/@artist"),
+                AllProviders::MastodonAccount
+            );
+            assert_eq!(matched("https://blog.example.com/"), AllProviders::RssFeed);
+        }
+    }
+}
@@ -1,14 +1,13 @@
 use std::{collections::HashMap, sync::Arc, time::Instant};
 
 use async_trait::async_trait;
-use chrono::NaiveDateTime;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
 use crate::{
-    request::{parse_successful_response, request_default_headers},
-    scheduler::UnscopedLimiter,
+    request::{parse_successful_response, request_default_headers, Upstream},
+    scheduler::ProviderRateLimiter,
     scraper::providers::ProviderMediaType,
 };
 
@@ -75,7 +74,8 @@ struct PinterestRequestDict<'a> {
 // #[derive(Clone)]
 pub struct PinterestBoardFeed {
     pub client: Arc<Client>,
-    pub rate_limiter: UnscopedLimiter,
+    pub upstream: Upstream,
+    pub rate_limiter: ProviderRateLimiter,
 }
 
 const PINTEREST_BOARD_SEPARATOR: &str = "|";
@@ -91,10 +91,8 @@ const PROVIDER_NATIVE_PAGE_SIZE: usize = 25;
 
 #[async_trait]
 impl RateLimitable for PinterestBoardFeed {
-    async fn wait(&self, _key: &str) -> () {
-        self.rate_limiter
-            .until_ready_with_jitter(default_jitter())
-            .await;
+    fn limiter(&self) -> &ProviderRateLimiter {
+        &self.rate_limiter
     }
 }
 
@@ -108,7 +106,8 @@ impl Provider for PinterestBoardFeed {
     {
         Self {
             client: Arc::clone(&input.client),
-            rate_limiter: Self::rate_limiter(),
+            upstream: input.upstream,
+            rate_limiter: Self::rate_limiter(AllProviders::PinterestBoardFeed),
         }
     }
     fn id(&self) -> AllProviders {
@@ -153,7 +152,7 @@ impl Provider for PinterestBoardFeed {
         let instant = Instant::now();
         let response = self
             .client
-            .get(&state.url.0)
+            .get(self.upstream.url(&state.url.0))
             .headers(request_default_headers())
             .send()
             .await?;
@@ -211,3 +210,48 @@ impl Provider for PinterestBoardFeed {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::scraper::fixtures::{first_scrape, scoped, scraped_posts, unlimited, FixtureServer};
+    use crate::scraper::scraper::{scrape, ScraperStep};
+    use crate::scraper::{AllProviders, ProviderFailure};
+
+    use super::PinterestBoardFeed;
+
+    const BOARD: &str = "1234|/jiu/dreamcatcher/";
+
+    fn provider(server: &FixtureServer) -> PinterestBoardFeed {
+        let input = server.provider_input();
+        PinterestBoardFeed {
+            client: input.client,
+            upstream: input.upstream,
+            rate_limiter: unlimited(),
+        }
+    }
+
+    #[tokio::test]
+    async fn follows_bookmarks_until_the_last_page() {
+        let server = FixtureServer::start("pinterest/board_feed").await;
+        let provider = provider(&server);
+        let sp = scoped(AllProviders::PinterestBoardFeed, BOARD);
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        assert_eq!(result.requests.len(), 2);
+        assert_eq!(scraped_posts(&result), ["101", "102", "103"]);
+    }
+
+    #[tokio::test]
+    async fn stops_at_a_failed_page() {
+        let server = FixtureServer::start("pinterest/server_error").await;
+        let provider = provider(&server);
+        let sp = scoped(AllProviders::PinterestBoardFeed, BOARD);
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        assert_eq!(scraped_posts(&result), ["101", "102"]);
+        match &result.requests.last().unwrap().step {
+            ScraperStep::Error(ProviderFailure::HttpError(_)) => {}
+            step => panic!("Expected the scrape to end with an error, got {:?}", step),
+        }
+    }
+}
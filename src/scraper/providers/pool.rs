@@ -0,0 +1,252 @@
+use std::env;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use parking_lot::Mutex;
+
+use super::{create_credentials, ProviderCredentials, SharedCredentials};
+
+/// Accounts get quarantined after this many authorization errors in a row
+const MAX_AUTH_FAILURES: u32 = 3;
+
+/// How long a quarantined account is left alone before it gets another chance
+const QUARANTINE_DURATION: Duration = Duration::from_secs(60 * 60);
+
+/// What an account needs to log in, providers like twitter log in anonymously without one
+#[derive(Debug, Clone)]
+pub struct AccountLogin {
+    pub username: String,
+    pub password: String,
+}
+
+#[derive(Debug, Default)]
+struct AccountHealth {
+    last_used: Option<Instant>,
+    auth_failures: u32,
+    quarantined_until: Option<Instant>,
+}
+
+pub struct Account {
+    /// identifies the account in the database, this is the username for accounts that have one
+    pub name: String,
+    pub login: Option<AccountLogin>,
+    pub credentials: SharedCredentials<ProviderCredentials>,
+    health: Mutex<AccountHealth>,
+}
+
+impl Account {
+    pub fn new(name: String, login: Option<AccountLogin>) -> Self {
+        Self {
+            name,
+            login,
+            credentials: create_credentials(),
+            health: Mutex::new(AccountHealth::default()),
+        }
+    }
+
+    fn is_quarantined(&self, now: Instant) -> bool {
+        self.health
+            .lock()
+            .quarantined_until
+            .is_some_and(|until| until > now)
+    }
+
+    fn is_available(&self, now: Instant) -> bool {
+        self.credentials.read().is_some() && !self.is_quarantined(now)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RotationStrategy {
+    RoundRobin,
+    LeastRecentlyUsed,
+}
+
+/// Every account a provider can make requests with. Each scrape checks out a single
+/// account and uses it for all of its requests
+pub struct CredentialPool {
+    accounts: Vec<Arc<Account>>,
+    strategy: RotationStrategy,
+    next: AtomicUsize,
+}
+
+impl CredentialPool {
+    pub fn new(accounts: Vec<Account>, strategy: RotationStrategy) -> Self {
+        Self {
+            accounts: accounts.into_iter().map(Arc::new).collect(),
+            strategy,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// A pool with one account that doesn't need login details
+    pub fn anonymous(name: &str) -> Self {
+        Self::new(
+            vec![Account::new(name.to_owned(), None)],
+            RotationStrategy::RoundRobin,
+        )
+    }
+
+    /// Reads accounts from `{PREFIX}_EMAIL` and `{PREFIX}_PASSWORD` followed by
+    /// `{PREFIX}_EMAIL_2` and `{PREFIX}_PASSWORD_2` and so on. Accounts are rotated
+    /// round-robin unless `{PREFIX}_ACCOUNT_ROTATION` is set to `lru`
+    pub fn from_env(prefix: &str) -> Self {
+        let mut accounts = vec![];
+        for i in 1.. {
+            let suffix = if i == 1 {
+                "".to_owned()
+            } else {
+                format!("_{}", i)
+            };
+            let username = env::var(format!("{}_EMAIL{}", prefix, suffix));
+            let password = env::var(format!("{}_PASSWORD{}", prefix, suffix));
+            match (username, password) {
+                (Ok(username), Ok(password)) => accounts.push(Account::new(
+                    username.clone(),
+                    Some(AccountLogin { username, password }),
+                )),
+                _ => break,
+            }
+        }
+        let strategy = match env::var(format!("{}_ACCOUNT_ROTATION", prefix)).as_deref() {
+            Ok("lru") => RotationStrategy::LeastRecentlyUsed,
+            _ => RotationStrategy::RoundRobin,
+        };
+        if accounts.len() > 1 {
+            info!(
+                "Found {} {} accounts, rotating with {:?}",
+                accounts.len(),
+                prefix,
+                strategy
+            );
+        }
+        Self::new(accounts, strategy)
+    }
+
+    /// Adds an account that was given credentials directly instead of logging in
+    pub fn with_credentials(mut self, name: &str, credentials: ProviderCredentials) -> Self {
+        let account = Account::new(name.to_owned(), None);
+        *account.credentials.write() = Some(credentials);
+        self.accounts.push(Arc::new(account));
+        self
+    }
+
+    pub fn accounts(&self) -> &[Arc<Account>] {
+        &self.accounts
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<Account>> {
+        self.accounts.iter().find(|a| a.name == name).cloned()
+    }
+
+    /// The credentials of an account if it's logged in
+    pub fn credentials(&self, name: &Option<String>) -> Option<ProviderCredentials> {
+        self.get(name.as_deref()?)?.credentials.read().clone()
+    }
+
+    /// Picks the account the next scrape should use, skipping accounts that are
+    /// quarantined or were never logged into
+    pub fn checkout(&self) -> Option<Arc<Account>> {
+        let now = Instant::now();
+        let account = match self.strategy {
+            RotationStrategy::RoundRobin => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed);
+                (0..self.accounts.len())
+                    .map(|i| &self.accounts[(start + i) % self.accounts.len()])
+                    .find(|account| account.is_available(now))
+            }
+            RotationStrategy::LeastRecentlyUsed => self
+                .accounts
+                .iter()
+                .filter(|account| account.is_available(now))
+                .min_by_key(|account| account.health.lock().last_used),
+        }?;
+        account.health.lock().last_used = Some(now);
+        Some(Arc::clone(account))
+    }
+
+    /// Records an authorization error, returning whether the account got quarantined because of it
+    pub fn report_auth_failure(&self, name: &Option<String>) -> bool {
+        let account = match name.as_deref().and_then(|name| self.get(name)) {
+            Some(account) => account,
+            None => return false,
+        };
+        let mut health = account.health.lock();
+        health.auth_failures += 1;
+        if health.auth_failures < MAX_AUTH_FAILURES {
+            return false;
+        }
+        warn!(
+            "Quarantining account {} for {:?} after {} authorization errors",
+            account.name, QUARANTINE_DURATION, health.auth_failures
+        );
+        health.auth_failures = 0;
+        health.quarantined_until = Some(Instant::now() + QUARANTINE_DURATION);
+        true
+    }
+
+    pub fn report_success(&self, name: &Option<String>) {
+        if let Some(account) = name.as_deref().and_then(|name| self.get(name)) {
+            account.health.lock().auth_failures = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Account, CredentialPool, RotationStrategy};
+    use crate::scraper::ProviderCredentials;
+
+    fn pool(strategy: RotationStrategy) -> CredentialPool {
+        let accounts = ["a", "b", "c"]
+            .iter()
+            .map(|name| {
+                let account = Account::new(name.to_string(), None);
+                *account.credentials.write() = Some(ProviderCredentials::default());
+                account
+            })
+            .collect();
+        CredentialPool::new(accounts, strategy)
+    }
+
+    fn checkout(pool: &CredentialPool) -> String {
+        pool.checkout().unwrap().name.clone()
+    }
+
+    #[test]
+    fn round_robin_rotates_through_accounts() {
+        let pool = pool(RotationStrategy::RoundRobin);
+        let names = (0..4).map(|_| checkout(&pool)).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "c", "a"]);
+    }
+
+    #[test]
+    fn least_recently_used_prefers_idle_accounts() {
+        let pool = pool(RotationStrategy::LeastRecentlyUsed);
+        let names = (0..3).map(|_| checkout(&pool)).collect::<Vec<_>>();
+        assert_eq!(names, ["a", "b", "c"]);
+        assert_eq!(checkout(&pool), "a");
+    }
+
+    #[test]
+    fn accounts_are_quarantined_after_repeated_auth_failures() {
+        let pool = pool(RotationStrategy::RoundRobin);
+        let b = Some("b".to_owned());
+        assert!(!pool.report_auth_failure(&b));
+        assert!(!pool.report_auth_failure(&b));
+        assert!(pool.report_auth_failure(&b));
+        let names = (0..4).map(|_| checkout(&pool)).collect::<Vec<_>>();
+        assert!(!names.contains(&"b".to_owned()));
+    }
+
+    #[test]
+    fn accounts_without_credentials_are_skipped() {
+        let pool = CredentialPool::new(
+            vec![Account::new("a".to_owned(), None)],
+            RotationStrategy::RoundRobin,
+        );
+        assert!(pool.checkout().is_none());
+    }
+}
@@ -1,25 +1,25 @@
-use std::convert::Infallible;
 use std::sync::Arc;
 use std::{collections::HashSet, ops::Add, time::Duration};
 
 use async_trait::async_trait;
-use chrono::NaiveDateTime;
-use governor::{Jitter, Quota, RateLimiter};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use governor::{Jitter, Quota};
 use log::{debug, error, info};
 use parking_lot::RwLock;
-use reqwest::{Client, Error, StatusCode};
+use reqwest::{Client, StatusCode};
 use serde;
 use serde::{Deserialize, Serialize};
-use strum_macros::{Display, ToString};
+use strum_macros::Display;
 use strum_macros::{EnumIter, EnumString};
 use thiserror::Error;
 use url::Url;
 
-use crate::request::HttpError;
-use crate::scheduler::UnscopedLimiter;
-use crate::scraper::providers::providers::DerivedProviderResource::Invalid;
+use crate::request::{HttpError, Upstream};
+use crate::scheduler::{
+    budget::configured_budget, configured_quota, ProviderRateLimiter, QuotaScope,
+};
 
-use super::{PageSize, ScrapeUrl};
+use super::{Account, CredentialPool, PageSize, ScrapeUrl};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ProviderMediaType {
@@ -118,6 +118,9 @@ impl From<reqwest::Error> for ProviderFailure {
 #[derive(Debug, Clone)]
 pub struct ProviderState {
     pub login_attempts: u32,
+    pub backoff_attempts: u32,
+    /// the account from the provider's credential pool the scrape is using
+    pub account: Option<String>,
     pub id: String,
     pub default_name: Option<String>,
     pub url: ScrapeUrl,
@@ -153,9 +156,24 @@ pub enum CredentialRefresh {
 pub enum ProviderErrorHandle {
     RefreshToken(ProviderCredentials),
     Login,
+    /// The provider is being rate limited, the request should be retried after
+    /// the given delay or an exponential default if there isn't one
+    Backoff(Option<Duration>),
     Halt,
 }
 
+/// The backoff a rate limited response asks for if it is one
+pub fn rate_limit_backoff(error: &HttpError) -> Option<ProviderErrorHandle> {
+    match error {
+        HttpError::FailStatus(e) | HttpError::UnexpectedBody(e)
+            if e.code == StatusCode::TOO_MANY_REQUESTS =>
+        {
+            Some(ProviderErrorHandle::Backoff(e.retry_after))
+        }
+        _ => None,
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Pagination {
     NextPage(i32),
@@ -171,32 +189,47 @@ impl Pagination {
     }
 }
 
-impl ToString for Pagination {
-    fn to_string(&self) -> String {
-        self.next_page()
+impl std::fmt::Display for Pagination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.next_page())
     }
 }
 
 #[async_trait]
-pub trait RateLimitable {
-    /// The available quota for this provider
+pub trait RateLimitable: Sync {
+    /// The available quota for this provider across every destination
     fn quota() -> Quota
     where
         Self: Sized,
     {
         default_quota()
     }
-    /// The default rate limiter implementation
-    /// This currently only supports global rate limiters
-    /// but may need to be changed to support local ones as well
-    fn rate_limiter() -> UnscopedLimiter
+    /// The quota of every individual key on top of the provider quota.
+    /// Keys are not limited on their own unless this is set
+    fn scoped_quota() -> Option<Quota>
+    where
+        Self: Sized,
+    {
+        None
+    }
+    /// The default rate limiter implementation. Quotas can be overridden with
+    /// environment variables, see [`crate::scheduler::quota_variable`]. Daily budgets
+    /// are only set through [`crate::scheduler::budget::budget_variable`]
+    fn rate_limiter(provider: AllProviders) -> ProviderRateLimiter
     where
         Self: Sized,
     {
-        RateLimiter::direct(Self::quota())
+        ProviderRateLimiter::new(
+            configured_quota(provider, QuotaScope::Global).unwrap_or_else(Self::quota),
+            configured_quota(provider, QuotaScope::Scoped).or_else(Self::scoped_quota),
+        )
+        .with_budget(configured_budget(provider))
     }
+    fn limiter(&self) -> &ProviderRateLimiter;
     /// Wait for next request if token is not available
-    async fn wait(&self, key: &str) -> ();
+    async fn wait(&self, key: &str) -> () {
+        self.limiter().wait(key, default_jitter()).await
+    }
 }
 
 pub fn default_quota() -> Quota {
@@ -204,16 +237,45 @@ pub fn default_quota() -> Quota {
     Quota::with_period(Duration::from_millis(3500u64)).unwrap()
 }
 
-const DEFAULT_WAIT_SECONDS: u64 = if cfg!(debug_assertions) { 2 } else { 8 };
+// fixture tests shouldn't sit around waiting between pages
+const DEFAULT_WAIT_SECONDS: u64 = if cfg!(test) {
+    0
+} else if cfg!(debug_assertions) {
+    2
+} else {
+    8
+};
 
 pub fn default_jitter() -> Jitter {
     Jitter::up_to(Duration::from_secs(DEFAULT_WAIT_SECONDS))
 }
 
-#[derive(Debug, Clone, Default)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct ProviderCredentials {
     pub access_token: String,
     pub refresh_token: String,
+    /// when the access token stops working, if the provider tells us
+    #[serde(default)]
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl ProviderCredentials {
+    /// Sets the expiry from the `expires_in` seconds of an oauth style token response
+    pub fn expiring_in(self, seconds: i64) -> Self {
+        Self {
+            expires_at: Some(Utc::now() + chrono::Duration::seconds(seconds)),
+            ..self
+        }
+    }
+
+    /// Whether the access token expires before `margin` has passed.
+    /// Credentials without a known expiry are never considered expiring
+    pub fn expires_within(&self, margin: Duration) -> bool {
+        let margin =
+            chrono::Duration::from_std(margin).unwrap_or_else(|_| chrono::Duration::max_value());
+        self.expires_at
+            .is_some_and(|expires_at| expires_at - Utc::now() <= margin)
+    }
 }
 
 pub struct BareProviderInput {
@@ -222,33 +284,39 @@ pub struct BareProviderInput {
 
 pub struct ProviderInput {
     pub client: Arc<Client>,
+    pub upstream: Upstream,
 }
 
 pub fn create_credentials<T>() -> Arc<RwLock<Option<T>>> {
     Arc::new(RwLock::new(None))
 }
 
-/// Try to override the shared credentials after logging in one time
-pub async fn attempt_first_login(
-    provider: &dyn Provider,
-    credentials: &SharedCredentials<ProviderCredentials>,
-) {
+/// Try to override the shared credentials of every account in the pool after logging in one time
+pub async fn attempt_first_login(provider: &dyn Provider, pool: &CredentialPool) {
     let id = provider.id().to_string();
-    info!("Attempting login to {}", &id);
-    let login = provider.login().await;
-    let provider_creds = match login {
-        Ok(login) => {
-            info!("Logged in into {}", &id);
-            login
+    for account in pool.accounts() {
+        if account.credentials.read().is_some() {
+            info!("Using stored credentials for {} ({})", &id, account.name);
+            continue;
         }
-        Err(err) => {
-            error!("Could not log into {}, leaving it uninitialized", &id);
-            eprintln!("{:?}", err);
-            return;
-        }
-    };
-    let mut writable = credentials.write();
-    *writable = Some(provider_creds);
+        info!("Attempting login to {} ({})", &id, account.name);
+        let provider_creds = match provider.login(account).await {
+            Ok(login) => {
+                info!("Logged in into {} ({})", &id, account.name);
+                login
+            }
+            Err(err) => {
+                error!(
+                    "Could not log into {} ({}), leaving it uninitialized",
+                    &id, account.name
+                );
+                eprintln!("{:?}", err);
+                continue;
+            }
+        };
+        let mut writable = account.credentials.write();
+        *writable = Some(provider_creds);
+    }
 }
 
 pub enum DerivedProviderResource {
@@ -304,6 +372,13 @@ pub trait Provider: Sync + Send + RateLimitable {
         Duration::from_secs(2)
     }
 
+    /// Providers that can scrape any site instead of one in particular. These are only
+    /// tried after every other provider when matching a url, lowest first since more than
+    /// one of them can take the same url
+    fn generic(&self) -> Option<u8> {
+        None
+    }
+
     /// Match the domain input into a pending action the provider can perform
     fn match_domain(&self, _url: &str) -> Option<WorkableDomain> {
         None
@@ -323,6 +398,7 @@ pub trait Provider: Sync + Send + RateLimitable {
 
     /// Provider destination are any unique identifier a provider can try to resolve into an opaque [ScrapeUrl].
     /// This method is called after every successful scrape to resolve the next page of media
+    #[allow(clippy::wrong_self_convention)]
     fn from_provider_destination(
         &self,
         id: &str,
@@ -334,7 +410,14 @@ pub trait Provider: Sync + Send + RateLimitable {
 
     /// Error handling branch that separates operational errors from authorization
     /// related error codes
-    fn on_error(&self, _http_error: &HttpError) -> anyhow::Result<ProviderErrorHandle> {
+    fn on_error(
+        &self,
+        http_error: &HttpError,
+        _state: &ProviderState,
+    ) -> anyhow::Result<ProviderErrorHandle> {
+        if let Some(backoff) = rate_limit_backoff(http_error) {
+            return Ok(backoff);
+        }
         debug!(
             "{} ran into an unhandled error and is halting",
             self.id().to_string()
@@ -342,40 +425,47 @@ pub trait Provider: Sync + Send + RateLimitable {
         Ok(ProviderErrorHandle::Halt)
     }
 
+    #[allow(clippy::diverging_sub_expression)]
     async fn token_refresh(
         &self,
         _credentials: &ProviderCredentials,
     ) -> anyhow::Result<CredentialRefresh> {
         panic!(
             "{}'s on_error branch tried to refresh credentials but it doesn't implement a token refresh flow",
-            self.id().to_string()
+            self.id()
         )
     }
 
-    async fn login(&self) -> Result<ProviderCredentials, ProviderFailure> {
+    #[allow(clippy::diverging_sub_expression)]
+    async fn login(&self, _account: &Account) -> Result<ProviderCredentials, ProviderFailure> {
         panic!(
             "{} tried to login but it doesn't implement a login flow",
-            self.id().to_string()
+            self.id()
         )
     }
-    fn credentials(&self) -> SharedCredentials<ProviderCredentials> {
-        panic!(
-            "Tried to get credentials for {} which doesn't authorization",
-            self.id().to_string()
-        )
+    /// The accounts of providers that use credentials
+    fn credentials(&self) -> Option<&CredentialPool> {
+        None
     }
-    
+
     fn max_login_attempts(&self) -> u32 {
         3
     }
 
+    /// The number of times a single scrape can back off after getting rate limited
+    fn max_backoff_attempts(&self) -> u32 {
+        3
+    }
+
     /// Whether the URLs generated by this scraper expire after a short amount of duration
     fn ephemeral(&self) -> bool {
         false
     }
 }
 
-#[derive(Display, Debug, Hash, Copy, Clone, Serialize, EnumString, EnumIter, PartialEq, Eq)]
+#[derive(
+    Display, Debug, Hash, Copy, Clone, Serialize, Deserialize, EnumString, EnumIter, PartialEq, Eq,
+)]
 pub enum AllProviders {
     #[strum(serialize = "pinterest.board_feed")]
     PinterestBoardFeed,
@@ -385,6 +475,16 @@ pub enum AllProviders {
     UnitedCubeArtistFeed,
     #[strum(serialize = "twitter.timeline")]
     TwitterTimeline,
+    #[strum(serialize = "rss.feed")]
+    RssFeed,
+    #[strum(serialize = "json_api")]
+    JsonApi,
+    #[strum(serialize = "html.page")]
+    HtmlPage,
+    #[strum(serialize = "reddit.submissions")]
+    RedditSubmissions,
+    #[strum(serialize = "mastodon.account")]
+    MastodonAccount,
 }
 
 pub fn find_matching_domain(domains: &[&str], url: &str) -> Option<WorkableDomain> {
@@ -399,20 +499,18 @@ pub fn find_matching_domain(domains: &[&str], url: &str) -> Option<WorkableDomai
     }
 }
 
-pub struct UrlBuilder {
-    pub params: Vec<(&'static str, String)>,
-}
-
-impl Default for UrlBuilder {
-    fn default() -> Self {
-        Self { params: vec![] }
-    }
+/// Resolves links found in pages and feeds, leaving out anything that isn't http(s)
+pub fn absolute_url(base: Option<&Url>, url: &str) -> Option<String> {
+    let url = match base {
+        Some(base) => base.join(url.trim()).ok()?,
+        None => Url::parse(url.trim()).ok()?,
+    };
+    matches!(url.scheme(), "http" | "https").then(|| url.to_string())
 }
 
-impl ToString for UrlBuilder {
-    fn to_string(&self) -> String {
-        todo!()
-    }
+#[derive(Default)]
+pub struct UrlBuilder {
+    pub params: Vec<(&'static str, String)>,
 }
 
 impl UrlBuilder {
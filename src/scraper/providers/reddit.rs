@@ -0,0 +1,452 @@
+//! Submissions of subreddits and users, scraped from the public `.json` listings. Destinations
+//! are `r/{subreddit}` and `user/{name}`.
+use std::collections::{HashMap, HashSet};
+use std::{sync::Arc, time::Duration, time::Instant};
+
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use governor::Quota;
+use reqwest::Client;
+use serde::Deserialize;
+use url::Url;
+
+use crate::{
+    request::{parse_successful_response, request_default_headers, Upstream},
+    scheduler::ProviderRateLimiter,
+    scraper::providers::ProviderMediaType,
+};
+
+use super::*;
+
+const BASE_URL: &str = "https://www.reddit.com";
+
+const DOMAINS: [&str; 6] = [
+    "reddit.com",
+    "www.reddit.com",
+    "old.reddit.com",
+    "new.reddit.com",
+    "np.reddit.com",
+    "m.reddit.com",
+];
+
+/// Listings of a subreddit that still point to the subreddit itself
+const SUBREDDIT_LISTINGS: [&str; 5] = ["hot", "new", "top", "rising", "controversial"];
+const USER_LISTINGS: [&str; 2] = ["submitted", "posts"];
+
+pub struct RedditSubmissions {
+    pub client: Arc<Client>,
+    pub upstream: Upstream,
+    pub rate_limiter: ProviderRateLimiter,
+}
+
+#[derive(Debug, Deserialize)]
+struct Listing {
+    data: ListingData,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListingData {
+    after: Option<String>,
+    children: Vec<Thing>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Thing {
+    data: Link,
+}
+
+#[derive(Debug, Deserialize)]
+struct GalleryItem {
+    media_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct GalleryData {
+    items: Vec<GalleryItem>,
+}
+
+/// The biggest version of a gallery item
+#[derive(Debug, Deserialize)]
+struct MediaSource {
+    u: Option<String>,
+    gif: Option<String>,
+    mp4: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct MediaMetadata {
+    status: String,
+    s: Option<MediaSource>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Link {
+    id: String,
+    author: String,
+    title: String,
+    permalink: String,
+    created_utc: f64,
+    url: Option<String>,
+    gallery_data: Option<GalleryData>,
+    media_metadata: Option<HashMap<String, MediaMetadata>>,
+    /// the post a crosspost was made from, which is where its media is
+    #[serde(default)]
+    crosspost_parent_list: Vec<Link>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", content = "data")]
+enum About {
+    #[serde(rename = "t5")]
+    Subreddit { display_name: String },
+    #[serde(rename = "t2")]
+    User { name: String },
+}
+
+fn media(_type: ProviderMediaType, url: String, id: &str, post_url: &str) -> ProviderMedia {
+    ProviderMedia {
+        _type,
+        media_url: url,
+        reference_url: Some(post_url.to_owned()),
+        unique_identifier: id.to_owned(),
+        metadata: None,
+    }
+}
+
+/// Images hosted on reddit and imgur that are linked to directly. Imgur albums need the imgur
+/// api so they're left out
+fn direct_link(url: &str, post_url: &str) -> Option<ProviderMedia> {
+    let parsed = Url::parse(url).ok()?;
+    let file = parsed.path().strip_prefix('/')?;
+    if file.is_empty() || file.contains('/') {
+        return None;
+    }
+    let (id, extension) = file.split_once('.').unwrap_or((file, ""));
+    match parsed.domain()? {
+        "i.redd.it" => Some(media(
+            ProviderMediaType::Image,
+            url.to_owned(),
+            id,
+            post_url,
+        )),
+        "i.imgur.com" | "imgur.com" | "m.imgur.com" => Some(match extension {
+            // gifv is a page around an mp4 of the same name
+            "gifv" | "mp4" => media(
+                ProviderMediaType::Video,
+                format!("https://i.imgur.com/{}.mp4", id),
+                id,
+                post_url,
+            ),
+            "" => media(
+                ProviderMediaType::Image,
+                format!("https://i.imgur.com/{}.jpg", id),
+                id,
+                post_url,
+            ),
+            _ => media(
+                ProviderMediaType::Image,
+                format!("https://i.imgur.com/{}", file),
+                id,
+                post_url,
+            ),
+        }),
+        _ => None,
+    }
+}
+
+impl Link {
+    /// Crossposts are the same as the post they were made from, `crosspost_parent_list[0]`.
+    /// Keeping the ids of the original lets media that was already scraped through it, or through
+    /// another crosspost of it, be recognized by later scrapes
+    fn original(&self) -> &Link {
+        self.crosspost_parent_list.first().unwrap_or(self)
+    }
+
+    fn media(&self, post_url: &str) -> Vec<ProviderMedia> {
+        if let (Some(gallery), Some(metadata)) = (&self.gallery_data, &self.media_metadata) {
+            return gallery
+                .items
+                .iter()
+                .filter_map(|item| {
+                    let metadata = metadata.get(&item.media_id)?;
+                    let source = metadata.s.as_ref().filter(|_| metadata.status == "valid")?;
+                    let (_type, url) = match (&source.mp4, &source.gif, &source.u) {
+                        (Some(mp4), _, _) => (ProviderMediaType::Video, mp4),
+                        (None, Some(gif), _) => (ProviderMediaType::Image, gif),
+                        (None, None, Some(image)) => (ProviderMediaType::Image, image),
+                        (None, None, None) => return None,
+                    };
+                    Some(media(_type, url.clone(), &item.media_id, post_url))
+                })
+                .collect();
+        }
+        self.url
+            .as_ref()
+            .and_then(|url| direct_link(url, post_url))
+            .into_iter()
+            .collect()
+    }
+
+    fn into_post(self) -> Option<ProviderPost> {
+        let original = self.original();
+        let url = format!("{}{}", BASE_URL, original.permalink);
+        let images = original.media(&url);
+        if images.is_empty() {
+            return None;
+        }
+        Some(ProviderPost {
+            account: ProviderAccount {
+                name: original.author.clone(),
+                avatar_url: None,
+            },
+            unique_identifier: original.id.clone(),
+            images,
+            body: Some(original.title.clone()),
+            url: Some(url),
+            post_date: NaiveDateTime::from_timestamp_opt(original.created_utc as i64, 0),
+            metadata: None,
+        })
+    }
+}
+
+/// `r/{subreddit}` or `user/{name}` out of the url of a subreddit or a user's profile
+fn destination(url: &str) -> Option<String> {
+    let parsed = Url::parse(url).ok()?;
+    if !DOMAINS.contains(&parsed.domain()?) {
+        return None;
+    }
+    let segments = parsed
+        .path_segments()?
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    match segments.as_slice() {
+        ["r", name] => Some(format!("r/{}", name)),
+        ["r", name, listing] if SUBREDDIT_LISTINGS.contains(listing) => Some(format!("r/{}", name)),
+        ["user" | "u", name] => Some(format!("user/{}", name)),
+        ["user" | "u", name, listing] if USER_LISTINGS.contains(listing) => {
+            Some(format!("user/{}", name))
+        }
+        _ => None,
+    }
+}
+
+#[async_trait]
+impl RateLimitable for RedditSubmissions {
+    /// Reddit only allows around 10 requests a minute without logging in
+    fn quota() -> Quota
+    where
+        Self: Sized,
+    {
+        Quota::with_period(Duration::from_secs(6)).unwrap()
+    }
+    fn limiter(&self) -> &ProviderRateLimiter {
+        &self.rate_limiter
+    }
+}
+
+#[async_trait]
+impl Provider for RedditSubmissions {
+    fn new(input: ProviderInput) -> Self
+    where
+        Self: Sized,
+    {
+        Self {
+            client: Arc::clone(&input.client),
+            upstream: input.upstream,
+            rate_limiter: Self::rate_limiter(AllProviders::RedditSubmissions),
+        }
+    }
+
+    fn id(&self) -> AllProviders {
+        AllProviders::RedditSubmissions
+    }
+
+    fn max_page_size(&self) -> PageSize {
+        PageSize(100)
+    }
+
+    fn default_page_size(&self) -> PageSize {
+        PageSize(25)
+    }
+
+    fn from_provider_destination(
+        &self,
+        id: &str,
+        page_size: PageSize,
+        pagination: Option<Pagination>,
+    ) -> Result<ScrapeUrl, ProviderFailure> {
+        let listing = match id.split_once('/') {
+            Some(("r", _)) => "new",
+            Some(("user", _)) => "submitted",
+            _ => return Err(ProviderFailure::Url),
+        };
+        // urls in the response are html escaped without this
+        let mut next_url = UrlBuilder::from_queries(vec![("raw_json", "1")]);
+        next_url.page_size("limit", page_size);
+        next_url.pagination("after", &pagination);
+        next_url.build_scrape_url(&format!("{}/{}/{}.json", BASE_URL, id, listing))
+    }
+
+    async fn unfold(&self, state: ProviderState) -> Result<ProviderStep, ProviderFailure> {
+        let instant = Instant::now();
+        let response = self
+            .client
+            .get(self.upstream.url(&state.url.0))
+            .headers(request_default_headers())
+            .send()
+            .await?;
+        let response_delay = instant.elapsed();
+        let response_code = response.status();
+        let listing = parse_successful_response::<Listing>(response).await?;
+        let has_posts = !listing.data.children.is_empty();
+        let mut seen = HashSet::new();
+        let posts = listing
+            .data
+            .children
+            .into_iter()
+            .filter_map(|thing| thing.data.into_post())
+            // a crosspost and the post it was made from can be in the same listing
+            .filter(|post| seen.insert(post.unique_identifier.clone()))
+            .collect::<Vec<_>>();
+        let result = ProviderResult {
+            posts,
+            response_code,
+            response_delay,
+        };
+        Ok(match listing.data.after {
+            Some(after) if has_posts => ProviderStep::Next(result, Pagination::NextCursor(after)),
+            _ => ProviderStep::End(result),
+        })
+    }
+
+    fn match_domain(&self, url: &str) -> Option<WorkableDomain> {
+        destination(url)?;
+        find_matching_domain(&DOMAINS, url)
+    }
+
+    /// Names are looked up to make sure they exist and get the way reddit capitalizes them
+    async fn introspect_resource(
+        &self,
+        introspectable: &IntrospectableResource,
+    ) -> Result<CanonicalUrlResolution, ProviderFailure> {
+        let destination = destination(&introspectable.0).ok_or(ProviderFailure::Url)?;
+        let url = format!("{}/{}/about.json", BASE_URL, destination);
+        let response = self
+            .client
+            .get(self.upstream.url(&url))
+            .headers(request_default_headers())
+            .send()
+            .await?;
+        let destination = match parse_successful_response::<About>(response).await? {
+            About::Subreddit { display_name } => format!("r/{}", display_name),
+            About::User { name } => format!("user/{}", name),
+        };
+        Ok(CanonicalUrlResolution::Success { destination })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scraper::fixtures::{first_scrape, scoped, scraped_posts, unlimited, FixtureServer};
+    use crate::scraper::scraper::{scrape, ScraperStep};
+    use crate::scraper::{
+        AllProviders, CanonicalUrlResolution, IntrospectableResource, Provider, ProviderMediaType,
+        ScrapeRequestInput,
+    };
+
+    use super::{destination, RedditSubmissions};
+
+    fn provider(server: &FixtureServer) -> RedditSubmissions {
+        let input = server.provider_input();
+        RedditSubmissions {
+            client: input.client,
+            upstream: input.upstream,
+            rate_limiter: unlimited(),
+        }
+    }
+
+    #[tokio::test]
+    async fn scrapes_galleries_links_and_crossposts() {
+        let server = FixtureServer::start("reddit/subreddit").await;
+        let provider = provider(&server);
+        let sp = scoped(AllProviders::RedditSubmissions, "r/dreamcatcher");
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        assert_eq!(result.requests.len(), 2);
+        // the crosspost is the same post as s0d, and the text post has nothing to scrape
+        assert_eq!(scraped_posts(&result), ["s1a", "s1b", "s0d", "s0f"]);
+        let posts = match &result.requests[0].step {
+            ScraperStep::Data(data) => &data.posts,
+            step => panic!("Expected the first page to be scraped, got {:?}", step),
+        };
+        let media = posts
+            .iter()
+            .flat_map(|post| post.images.iter())
+            .map(|image| (image.unique_identifier.as_str(), image.media_url.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            media,
+            [
+                ("g2", "https://i.redd.it/g2.jpg"),
+                ("g1", "https://i.redd.it/g1.jpg"),
+                ("x7k2", "https://i.redd.it/x7k2.png"),
+                ("AbCdEf1", "https://i.imgur.com/AbCdEf1.mp4"),
+            ]
+        );
+        assert!(matches!(posts[2].images[0]._type, ProviderMediaType::Video));
+        assert_eq!(posts[2].account.name, "original_poster");
+        assert_eq!(
+            posts[2].url.as_deref(),
+            Some("https://www.reddit.com/r/kpics/comments/s0d/rehearsal/")
+        );
+        assert_eq!(
+            posts[0].post_date.unwrap().to_string(),
+            "2022-02-16 12:00:00"
+        );
+    }
+
+    #[tokio::test]
+    async fn crossposts_of_scraped_posts_stop_the_scrape() {
+        let server = FixtureServer::start("reddit/known_crosspost").await;
+        let provider = provider(&server);
+        let sp = scoped(AllProviders::RedditSubmissions, "r/dreamcatcher");
+        // s0d was scraped before, neither it nor the crosspost of it is saved again
+        let input = ScrapeRequestInput {
+            latest_data: std::iter::once("AbCdEf1".to_owned()).collect(),
+            default_name: None,
+            last_scrape: None,
+            is_first_scrape: false,
+        };
+        let result = scrape(&sp, &provider, &input, None).await.unwrap();
+        server.finish();
+        assert_eq!(result.requests.len(), 2);
+        assert_eq!(scraped_posts(&result), ["s1a", "s1b", "s0f"]);
+    }
+
+    #[tokio::test]
+    async fn resolves_subreddits_and_users() {
+        let server = FixtureServer::start("reddit/introspect").await;
+        let provider = provider(&server);
+        let resolve = |url: &str| IntrospectableResource(url.to_owned());
+        for (url, expected) in [
+            (
+                "https://old.reddit.com/r/dreamcatcher/new/",
+                "r/Dreamcatcher",
+            ),
+            ("https://www.reddit.com/u/someone", "user/SomeOne"),
+        ] {
+            assert!(provider.match_domain(url).is_some());
+            match provider.introspect_resource(&resolve(url)).await.unwrap() {
+                CanonicalUrlResolution::Success { destination } => {
+                    assert_eq!(destination, expected)
+                }
+                _ => panic!("Could not resolve {}", url),
+            }
+        }
+        server.finish();
+        assert!(destination("https://www.reddit.com/r/kpics/comments/s0d/rehearsal/").is_none());
+        assert!(provider
+            .match_domain("https://reddit.example.com/r/dreamcatcher")
+            .is_none());
+    }
+}
@@ -0,0 +1,565 @@
+//! A provider for any site that publishes an RSS 2.0 or Atom feed, like agency blogs, Tistory
+//! and news sites. Destinations are the url of the feed itself. Feeds only ever have a single
+//! page, so anything that falls out of a feed between scrapes is missed.
+use std::{sync::Arc, time::Instant};
+
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime};
+use governor::Quota;
+use nonzero_ext::nonzero;
+use reqwest::Client;
+use roxmltree::{Document, Node};
+use url::Url;
+
+use crate::{
+    request::{
+        request_default_headers, successful_response_text, HttpError, ResponseErrorContext,
+        Upstream,
+    },
+    scheduler::ProviderRateLimiter,
+    scraper::providers::ProviderMediaType,
+};
+
+use super::*;
+
+const ATOM_NAMESPACE: &str = "http://www.w3.org/2005/Atom";
+const MEDIA_NAMESPACE: &str = "http://search.yahoo.com/mrss/";
+const CONTENT_NAMESPACE: &str = "http://purl.org/rss/1.0/modules/content/";
+const DUBLIN_CORE_NAMESPACE: &str = "http://purl.org/dc/elements/1.1/";
+
+const FEED_CONTENT_TYPES: [&str; 2] = ["application/rss+xml", "application/atom+xml"];
+
+pub struct RssFeed {
+    pub client: Arc<Client>,
+    pub upstream: Upstream,
+    pub rate_limiter: ProviderRateLimiter,
+}
+
+/// Details of the feed every item falls back to
+struct Channel {
+    title: Option<String>,
+    image: Option<String>,
+}
+
+impl Channel {
+    fn account(&self, author: Option<String>) -> ProviderAccount {
+        ProviderAccount {
+            name: author
+                .or_else(|| self.title.clone())
+                .unwrap_or_else(|| ProviderAccount::default().name),
+            avatar_url: self.image.clone(),
+        }
+    }
+}
+
+fn child<'a, 'input>(
+    node: Node<'a, 'input>,
+    namespace: Option<&str>,
+    name: &str,
+) -> Option<Node<'a, 'input>> {
+    node.children()
+        .find(|child| child.tag_name().namespace() == namespace && child.tag_name().name() == name)
+}
+
+fn children<'a, 'input: 'a>(
+    node: Node<'a, 'input>,
+    namespace: Option<&'a str>,
+    name: &'a str,
+) -> impl Iterator<Item = Node<'a, 'input>> + 'a {
+    node.children().filter(move |child| {
+        child.tag_name().namespace() == namespace && child.tag_name().name() == name
+    })
+}
+
+/// All the text inside of an element, which is split up when it has CDATA sections
+fn text(node: Node) -> Option<String> {
+    let text = node
+        .descendants()
+        .filter_map(|node| if node.is_text() { node.text() } else { None })
+        .collect::<String>();
+    let text = text.trim();
+    (!text.is_empty()).then(|| text.to_owned())
+}
+
+fn child_text(node: Node, namespace: Option<&str>, name: &str) -> Option<String> {
+    child(node, namespace, name).and_then(text)
+}
+
+/// RSS uses RFC 2822 dates and Atom uses RFC 3339, though not every feed sticks to its own
+fn parse_date(date: &str) -> Option<NaiveDateTime> {
+    DateTime::parse_from_rfc2822(date)
+        .or_else(|_| DateTime::parse_from_rfc3339(date))
+        .ok()
+        .map(|date| date.naive_utc())
+}
+
+/// Figures out if a media element is an image or a video, leaving out things like podcasts
+fn media_type(mime: Option<&str>, medium: Option<&str>, url: &str) -> Option<ProviderMediaType> {
+    match (medium, mime) {
+        (Some("image"), _) => Some(ProviderMediaType::Image),
+        (Some("video"), _) => Some(ProviderMediaType::Video),
+        (Some(_), _) => None,
+        (None, Some(mime)) if mime.starts_with("image/") => Some(ProviderMediaType::Image),
+        (None, Some(mime)) if mime.starts_with("video/") => Some(ProviderMediaType::Video),
+        (None, Some(_)) => None,
+        (None, None) => {
+            let path = url.split(['?', '#']).next().unwrap_or(url);
+            let video = [".mp4", ".webm", ".mov", ".m4v"]
+                .iter()
+                .any(|extension| path.to_lowercase().ends_with(extension));
+            Some(if video {
+                ProviderMediaType::Video
+            } else {
+                ProviderMediaType::Image
+            })
+        }
+    }
+}
+
+/// The sources of every `<img>` in a post's html
+pub fn inline_images(html: &str) -> Vec<String> {
+    let selector = scraper::Selector::parse("img[src]").unwrap();
+    scraper::Html::parse_fragment(html)
+        .select(&selector)
+        .filter_map(|img| img.value().attr("src"))
+        .filter(|src| !src.starts_with("data:"))
+        .map(str::to_owned)
+        .collect()
+}
+
+/// `media:content` elements, which can also be grouped inside of a `media:group`
+fn media_rss(item: Node) -> Vec<(String, Option<String>, Option<String>)> {
+    item.descendants()
+        .filter(|node| {
+            node.tag_name().namespace() == Some(MEDIA_NAMESPACE)
+                && node.tag_name().name() == "content"
+        })
+        .filter_map(|content| {
+            Some((
+                content.attribute("url")?.to_owned(),
+                content.attribute("type").map(str::to_owned),
+                content.attribute("medium").map(str::to_owned),
+            ))
+        })
+        .collect()
+}
+
+struct Item {
+    id: Option<String>,
+    link: Option<String>,
+    title: Option<String>,
+    author: Option<String>,
+    date: Option<NaiveDateTime>,
+    /// url, mime type and medium of every media element
+    media: Vec<(String, Option<String>, Option<String>)>,
+    html: Vec<String>,
+}
+
+impl Item {
+    fn into_post(self, channel: &Channel, feed_url: Option<&Url>) -> Option<ProviderPost> {
+        let link = self.link.and_then(|link| absolute_url(feed_url, &link));
+        let base = link
+            .as_deref()
+            .and_then(|link| Url::parse(link).ok())
+            .or_else(|| feed_url.cloned());
+        let inline = self
+            .html
+            .iter()
+            .flat_map(|html| inline_images(html))
+            .map(|src| (src, None, Some("image".to_owned())));
+        let mut images: Vec<ProviderMedia> = vec![];
+        for (url, mime, medium) in self.media.into_iter().chain(inline) {
+            let url = match absolute_url(base.as_ref(), &url) {
+                Some(url) => url,
+                None => continue,
+            };
+            // enclosures are usually repeated in the body
+            if images.iter().any(|image| image.media_url == url) {
+                continue;
+            }
+            if let Some(_type) = media_type(mime.as_deref(), medium.as_deref(), &url) {
+                images.push(ProviderMedia {
+                    _type,
+                    unique_identifier: url.clone(),
+                    media_url: url,
+                    reference_url: link.clone(),
+                    metadata: None,
+                });
+            }
+        }
+        if images.is_empty() {
+            return None;
+        }
+        Some(ProviderPost {
+            account: channel.account(self.author),
+            unique_identifier: self.id.or_else(|| link.clone())?,
+            images,
+            body: self.title,
+            url: link,
+            post_date: self.date,
+            metadata: None,
+        })
+    }
+}
+
+fn rss_item(item: Node) -> Item {
+    let enclosures = children(item, None, "enclosure").filter_map(|enclosure| {
+        Some((
+            enclosure.attribute("url")?.to_owned(),
+            enclosure.attribute("type").map(str::to_owned),
+            None,
+        ))
+    });
+    let media = enclosures.chain(media_rss(item)).collect();
+    let html = [
+        child_text(item, Some(CONTENT_NAMESPACE), "encoded"),
+        child_text(item, None, "description"),
+    ];
+    Item {
+        id: child_text(item, None, "guid"),
+        link: child_text(item, None, "link"),
+        title: child_text(item, None, "title"),
+        author: child_text(item, Some(DUBLIN_CORE_NAMESPACE), "creator")
+            .or_else(|| child_text(item, None, "author")),
+        date: child_text(item, None, "pubDate")
+            .or_else(|| child_text(item, Some(DUBLIN_CORE_NAMESPACE), "date"))
+            .and_then(|date| parse_date(&date)),
+        media,
+        html: html.iter().flatten().cloned().collect(),
+    }
+}
+
+fn parse_rss(rss: Node, feed_url: Option<&Url>) -> Vec<ProviderPost> {
+    let channel_node = match child(rss, None, "channel") {
+        Some(channel) => channel,
+        None => return vec![],
+    };
+    let channel = Channel {
+        title: child_text(channel_node, None, "title"),
+        image: child(channel_node, None, "image")
+            .and_then(|image| child_text(image, None, "url"))
+            .and_then(|image| absolute_url(feed_url, &image)),
+    };
+    children(channel_node, None, "item")
+        .filter_map(|item| rss_item(item).into_post(&channel, feed_url))
+        .collect()
+}
+
+/// The html of an Atom text construct, xhtml content is kept as markup
+fn atom_html(node: Node, source: &str) -> Option<String> {
+    match node.attribute("type") {
+        Some("xhtml") => Some(source[node.range()].to_owned()),
+        _ => text(node),
+    }
+}
+
+fn atom_entry(entry: Node, source: &str) -> Item {
+    let atom = Some(ATOM_NAMESPACE);
+    let links = children(entry, atom, "link").collect::<Vec<_>>();
+    let link = links
+        .iter()
+        .find(|link| matches!(link.attribute("rel"), None | Some("alternate")))
+        .and_then(|link| link.attribute("href"))
+        .map(str::to_owned);
+    let enclosures = links
+        .iter()
+        .filter(|link| link.attribute("rel") == Some("enclosure"))
+        .filter_map(|link| {
+            Some((
+                link.attribute("href")?.to_owned(),
+                link.attribute("type").map(str::to_owned),
+                None,
+            ))
+        });
+    let media = enclosures.chain(media_rss(entry)).collect();
+    let html = [
+        child(entry, atom, "content").and_then(|content| atom_html(content, source)),
+        child(entry, atom, "summary").and_then(|summary| atom_html(summary, source)),
+    ];
+    Item {
+        id: child_text(entry, atom, "id"),
+        link,
+        title: child_text(entry, atom, "title"),
+        author: child(entry, atom, "author").and_then(|author| child_text(author, atom, "name")),
+        date: child_text(entry, atom, "published")
+            .or_else(|| child_text(entry, atom, "updated"))
+            .and_then(|date| parse_date(&date)),
+        media,
+        html: html.iter().flatten().cloned().collect(),
+    }
+}
+
+fn parse_atom(feed: Node, source: &str, feed_url: Option<&Url>) -> Vec<ProviderPost> {
+    let atom = Some(ATOM_NAMESPACE);
+    let channel = Channel {
+        title: child_text(feed, atom, "title"),
+        image: child_text(feed, atom, "icon")
+            .or_else(|| child_text(feed, atom, "logo"))
+            .and_then(|image| absolute_url(feed_url, &image)),
+    };
+    children(feed, atom, "entry")
+        .filter_map(|entry| atom_entry(entry, source).into_post(&channel, feed_url))
+        .collect()
+}
+
+/// Every item of an RSS or Atom feed that has images or videos in it
+pub fn parse_feed(source: &str, feed_url: Option<&Url>) -> Result<Vec<ProviderPost>, String> {
+    let document = Document::parse(source).map_err(|err| err.to_string())?;
+    let root = document.root_element();
+    match (root.tag_name().namespace(), root.tag_name().name()) {
+        (None, "rss") => Ok(parse_rss(root, feed_url)),
+        (Some(ATOM_NAMESPACE), "feed") => Ok(parse_atom(root, source, feed_url)),
+        (_, name) => Err(format!("{} is not an RSS or Atom feed", name)),
+    }
+}
+
+/// Finds the feed a page links to with `<link rel="alternate">`
+pub fn discover_feed(html: &str, page_url: &Url) -> Option<String> {
+    let selector = scraper::Selector::parse("link[rel~=alternate][href]").unwrap();
+    scraper::Html::parse_document(html)
+        .select(&selector)
+        .find(|link| {
+            link.value()
+                .attr("type")
+                .is_some_and(|_type| FEED_CONTENT_TYPES.contains(&_type.trim()))
+        })
+        .and_then(|link| absolute_url(Some(page_url), link.value().attr("href")?))
+}
+
+#[async_trait]
+impl RateLimitable for RssFeed {
+    /// Feeds are spread out across many sites, so the quota is mostly there to limit each feed
+    fn quota() -> Quota
+    where
+        Self: Sized,
+    {
+        Quota::per_second(nonzero!(2u32))
+    }
+    fn scoped_quota() -> Option<Quota>
+    where
+        Self: Sized,
+    {
+        Some(default_quota())
+    }
+    fn limiter(&self) -> &ProviderRateLimiter {
+        &self.rate_limiter
+    }
+}
+
+#[async_trait]
+impl Provider for RssFeed {
+    fn new(input: ProviderInput) -> Self
+    where
+        Self: Sized,
+    {
+        Self {
+            client: Arc::clone(&input.client),
+            upstream: input.upstream,
+            rate_limiter: Self::rate_limiter(AllProviders::RssFeed),
+        }
+    }
+
+    fn id(&self) -> AllProviders {
+        AllProviders::RssFeed
+    }
+
+    /// feeds decide how many items they have
+    fn max_page_size(&self) -> PageSize {
+        PageSize(20)
+    }
+
+    fn default_page_size(&self) -> PageSize {
+        PageSize(20)
+    }
+
+    fn from_provider_destination(
+        &self,
+        id: &str,
+        _page_size: PageSize,
+        _pagination: Option<Pagination>,
+    ) -> Result<ScrapeUrl, ProviderFailure> {
+        let url = Url::parse(id).map_err(|_| ProviderFailure::Url)?;
+        Ok(ScrapeUrl(url.to_string()))
+    }
+
+    async fn unfold(&self, state: ProviderState) -> Result<ProviderStep, ProviderFailure> {
+        let instant = Instant::now();
+        let response = self
+            .client
+            .get(self.upstream.url(&state.url.0))
+            .headers(request_default_headers())
+            .send()
+            .await?;
+        let response_delay = instant.elapsed();
+        let response_code = response.status();
+        let body = successful_response_text(response).await?;
+        let feed_url = Url::parse(&state.url.0).ok();
+        let posts = match parse_feed(&body, feed_url.as_ref()) {
+            Ok(posts) => posts,
+            Err(message) => {
+                return Err(HttpError::UnexpectedBody(ResponseErrorContext {
+                    body,
+                    code: response_code,
+                    message: Some(message),
+                    retry_after: None,
+                })
+                .into())
+            }
+        };
+        Ok(ProviderStep::End(ProviderResult {
+            posts,
+            response_code,
+            response_delay,
+        }))
+    }
+
+    /// Most sites have a feed, anything more specific gets to look at urls first
+    fn generic(&self) -> Option<u8> {
+        Some(1)
+    }
+
+    /// Any page could have a feed
+    fn match_domain(&self, url: &str) -> Option<WorkableDomain> {
+        let parsed = Url::parse(url).ok()?;
+        matches!(parsed.scheme(), "http" | "https")
+            .then(|| WorkableDomain::ToCanonical(IntrospectableResource(url.to_owned())))
+    }
+
+    /// Feed urls are used as they are, pages are resolved into the feed they link to
+    async fn introspect_resource(
+        &self,
+        introspectable: &IntrospectableResource,
+    ) -> Result<CanonicalUrlResolution, ProviderFailure> {
+        let url = Url::parse(&introspectable.0).map_err(|_| ProviderFailure::Url)?;
+        let response = self
+            .client
+            .get(self.upstream.url(url.as_str()))
+            .headers(request_default_headers())
+            .send()
+            .await?;
+        let body = successful_response_text(response).await?;
+        if parse_feed(&body, Some(&url)).is_ok() {
+            return Ok(CanonicalUrlResolution::Success {
+                destination: url.to_string(),
+            });
+        }
+        match discover_feed(&body, &url) {
+            Some(destination) => Ok(CanonicalUrlResolution::Success { destination }),
+            None => Err(ProviderFailure::Url),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use url::Url;
+
+    use crate::scraper::fixtures::{first_scrape, scoped, scraped_posts, unlimited, FixtureServer};
+    use crate::scraper::scraper::scrape;
+    use crate::scraper::{
+        AllProviders, CanonicalUrlResolution, IntrospectableResource, Provider, ProviderMediaType,
+    };
+
+    use super::{parse_feed, RssFeed};
+
+    const FEED: &str = "https://blog.example.com/rss";
+
+    fn provider(server: &FixtureServer) -> RssFeed {
+        let input = server.provider_input();
+        RssFeed {
+            client: input.client,
+            upstream: input.upstream,
+            rate_limiter: unlimited(),
+        }
+    }
+
+    #[tokio::test]
+    async fn scrapes_images_out_of_rss_items() {
+        let server = FixtureServer::start("rss/feed").await;
+        let provider = provider(&server);
+        let sp = scoped(AllProviders::RssFeed, FEED);
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        // the item without any images is left out
+        assert_eq!(scraped_posts(&result), ["post-2", "post-1"]);
+        let posts = &result.requests[0];
+        let posts = match &posts.step {
+            crate::scraper::scraper::ScraperStep::Data(data) => &data.posts,
+            step => panic!("Expected the feed to be scraped, got {:?}", step),
+        };
+        let media = posts[0]
+            .images
+            .iter()
+            .map(|image| image.media_url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            media,
+            [
+                "https://blog.example.com/files/teaser.mp4",
+                "https://cdn.example.com/photo-2a.jpg",
+                "https://blog.example.com/files/photo-2b.jpg",
+            ]
+        );
+        assert!(matches!(posts[0].images[0]._type, ProviderMediaType::Video));
+        assert_eq!(posts[0].account.name, "Example Entertainment");
+        assert_eq!(
+            posts[0].post_date.unwrap().to_string(),
+            "2022-02-14 09:30:00"
+        );
+        assert_eq!(posts[1].account.name, "Manager");
+    }
+
+    #[test]
+    fn reads_atom_entries() {
+        let feed = r#"<?xml version="1.0" encoding="utf-8"?>
+            <feed xmlns="http://www.w3.org/2005/Atom" xmlns:media="http://search.yahoo.com/mrss/">
+                <title>Fansite</title>
+                <icon>/icon.png</icon>
+                <entry>
+                    <id>tag:fansite.example.com,2022:1</id>
+                    <title>Concert photos</title>
+                    <link rel="alternate" href="https://fansite.example.com/posts/1"/>
+                    <published>2022-02-15T12:00:00+09:00</published>
+                    <media:group><media:content url="/photos/1.jpg" medium="image"/></media:group>
+                    <content type="xhtml"><div xmlns="http://www.w3.org/1999/xhtml"><img src="2.png"/></div></content>
+                </entry>
+            </feed>"#;
+        let url = Url::parse("https://fansite.example.com/feed.atom").unwrap();
+        let posts = parse_feed(feed, Some(&url)).unwrap();
+        assert_eq!(posts.len(), 1);
+        let post = &posts[0];
+        assert_eq!(post.unique_identifier, "tag:fansite.example.com,2022:1");
+        assert_eq!(post.post_date.unwrap().to_string(), "2022-02-15 03:00:00");
+        assert_eq!(
+            post.account.avatar_url.as_deref(),
+            Some("https://fansite.example.com/icon.png")
+        );
+        let media = post
+            .images
+            .iter()
+            .map(|image| image.media_url.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            media,
+            [
+                "https://fansite.example.com/photos/1.jpg",
+                "https://fansite.example.com/posts/2.png"
+            ]
+        );
+        assert!(parse_feed("<html></html>", None).is_err());
+    }
+
+    #[tokio::test]
+    async fn finds_feeds_linked_from_pages() {
+        let server = FixtureServer::start("rss/introspect").await;
+        let provider = provider(&server);
+        let resolve = |url: &str| IntrospectableResource(url.to_owned());
+        for url in ["https://blog.example.com/", "https://blog.example.com/rss"] {
+            match provider.introspect_resource(&resolve(url)).await.unwrap() {
+                CanonicalUrlResolution::Success { destination } => assert_eq!(destination, FEED),
+                _ => panic!("Could not find the feed of {}", url),
+            }
+        }
+        server.finish();
+    }
+}
@@ -1,22 +1,19 @@
-use std::borrow::Borrow;
 use std::env;
 use std::iter::FromIterator;
 use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::time::Instant;
 
-use anyhow::Error;
 use async_trait::async_trait;
-use chrono::NaiveDateTime;
 use chrono::{DateTime, FixedOffset, ParseResult};
 use governor::Quota;
-use log::{debug, error, info, trace, warn};
+use log::{debug, error, info, warn};
 use regex::Regex;
 use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
 use reqwest::Client;
 use url::Url;
 
-use crate::request::{parse_successful_response, HttpError};
-use crate::scheduler::UnscopedLimiter;
+use crate::request::{parse_successful_response, HttpError, Upstream};
+use crate::scheduler::ProviderRateLimiter;
 use crate::scraper::providers::twitter_types::{
     Entries, GuestTokenFetchResponse, Twitter, TwitterImageMetadata, TwitterPostMetadata,
     TwitterUserLookupResponse, Type,
@@ -41,10 +38,11 @@ fn parse_twitter_date(date_str: &str) -> ParseResult<DateTime<FixedOffset>> {
 }
 
 pub struct TwitterTimeline {
-    pub guest_token: SharedCredentials<ProviderCredentials>,
+    pub guest_token: CredentialPool,
     pub bearer_token: Option<String>,
     pub client: Arc<Client>,
-    pub rate_limiter: UnscopedLimiter,
+    pub upstream: Upstream,
+    pub rate_limiter: ProviderRateLimiter,
 }
 
 const BASE_URL: &str = "https://twitter.com/";
@@ -61,10 +59,8 @@ impl RateLimitable for TwitterTimeline {
     {
         default_quota()
     }
-    async fn wait(&self, _key: &str) -> () {
-        self.rate_limiter
-            .until_ready_with_jitter(default_jitter())
-            .await
+    fn limiter(&self) -> &ProviderRateLimiter {
+        &self.rate_limiter
     }
 }
 
@@ -78,10 +74,11 @@ impl Provider for TwitterTimeline {
         Self: Sized,
     {
         Self {
-            guest_token: create_credentials(),
+            guest_token: CredentialPool::anonymous("guest"),
             bearer_token: env::var("TWITTER_BEARER_TOKEN").ok(),
             client: Arc::clone(&input.client),
-            rate_limiter: Self::rate_limiter(),
+            upstream: input.upstream,
+            rate_limiter: Self::rate_limiter(AllProviders::TwitterTimeline),
         }
     }
 
@@ -143,7 +140,7 @@ impl Provider for TwitterTimeline {
     }
 
     async fn unfold(&self, state: ProviderState) -> Result<ProviderStep, ProviderFailure> {
-        let credentials = self.guest_token.read().clone();
+        let credentials = self.guest_token.credentials(&state.account);
         let token = match credentials {
             Some(token) => token,
             None => return Ok(ProviderStep::NotInitialized),
@@ -161,7 +158,7 @@ impl Provider for TwitterTimeline {
 
         let response = self
             .client
-            .get(state.url.0)
+            .get(self.upstream.url(&state.url.0))
             .headers(HeaderMap::from_iter([
                 (
                     HeaderName::from_static("user-agent"),
@@ -301,7 +298,7 @@ impl Provider for TwitterTimeline {
             Some(token) => token,
         };
         let input = match Url::parse(&introspectable.0) {
-            Err(e) => return Err(ProviderFailure::Url),
+            Err(_) => return Err(ProviderFailure::Url),
             Ok(e) => e,
         };
         let username = input.path().trim_start_matches("/");
@@ -309,7 +306,7 @@ impl Provider for TwitterTimeline {
         let endpoint = format!("https://api.twitter.com/2/users/by/username/{}", username);
         let result = self
             .client
-            .get(endpoint)
+            .get(self.upstream.url(&endpoint))
             .header("Authorization", format!("Bearer {}", bearer))
             .send()
             .await?
@@ -320,14 +317,14 @@ impl Provider for TwitterTimeline {
         })
     }
 
-    async fn login(&self) -> Result<ProviderCredentials, ProviderFailure> {
+    async fn login(&self, _account: &Account) -> Result<ProviderCredentials, ProviderFailure> {
         let headers = HeaderMap::from_iter([(
             HeaderName::from_static("user-agent"),
             HeaderValue::from_static(USER_AGENT),
         )]);
         let login = self
             .client
-            .get(BASE_URL)
+            .get(self.upstream.url(BASE_URL))
             .headers(headers.clone())
             .send()
             .await?;
@@ -343,6 +340,7 @@ impl Provider for TwitterTimeline {
                 Ok(ProviderCredentials {
                     access_token: capture.as_str().to_owned(),
                     refresh_token: "".to_owned(),
+                    expires_at: None,
                 })
             }
             None => {
@@ -361,7 +359,10 @@ impl Provider for TwitterTimeline {
                 );
                 let result = self
                     .client
-                    .post("https://api.twitter.com/1.1/guest/activate.json")
+                    .post(
+                        self.upstream
+                            .url("https://api.twitter.com/1.1/guest/activate.json"),
+                    )
                     .headers(request_headers)
                     .send()
                     .await?
@@ -370,13 +371,21 @@ impl Provider for TwitterTimeline {
                 let creds = ProviderCredentials {
                     access_token: result.guest_token,
                     refresh_token: "".to_owned(),
+                    expires_at: None,
                 };
                 Ok(creds)
             }
         }
     }
 
-    fn on_error(&self, error: &HttpError) -> anyhow::Result<ProviderErrorHandle> {
+    fn on_error(
+        &self,
+        error: &HttpError,
+        _state: &ProviderState,
+    ) -> anyhow::Result<ProviderErrorHandle> {
+        if let Some(backoff) = rate_limit_backoff(error) {
+            return Ok(backoff);
+        }
         match error {
             HttpError::FailStatus(e) | HttpError::UnexpectedBody(e) => {
                 if e.code == 403 {
@@ -393,7 +402,58 @@ impl Provider for TwitterTimeline {
             }
         }
     }
-    fn credentials(&self) -> SharedCredentials<ProviderCredentials> {
-        self.guest_token.clone()
+    fn credentials(&self) -> Option<&CredentialPool> {
+        Some(&self.guest_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::scraper::fixtures::{first_scrape, scoped, scraped_posts, unlimited, FixtureServer};
+    use crate::scraper::scraper::scrape;
+    use crate::scraper::{AllProviders, CredentialPool, Provider, ProviderCredentials};
+
+    use super::TwitterTimeline;
+
+    const USER_ID: &str = "12345";
+
+    fn provider(server: &FixtureServer) -> TwitterTimeline {
+        let input = server.provider_input();
+        TwitterTimeline {
+            guest_token: CredentialPool::anonymous("guest"),
+            bearer_token: None,
+            client: input.client,
+            upstream: input.upstream,
+            rate_limiter: unlimited(),
+        }
+    }
+
+    #[tokio::test]
+    async fn scrapes_the_timeline_with_a_guest_token() {
+        let server = FixtureServer::start("twitter/timeline").await;
+        let provider = provider(&server);
+        provider.initialize().await;
+        let sp = scoped(AllProviders::TwitterTimeline, USER_ID);
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        assert_eq!(result.requests.len(), 2);
+        assert_eq!(scraped_posts(&result), ["1001", "1002", "1003"]);
+    }
+
+    #[tokio::test]
+    async fn fetches_a_new_guest_token_after_it_expires() {
+        let server = FixtureServer::start("twitter/guest_token_expired").await;
+        let provider = provider(&server);
+        *provider.guest_token.accounts()[0].credentials.write() = Some(ProviderCredentials {
+            access_token: "expired".to_owned(),
+            refresh_token: "".to_owned(),
+            expires_at: None,
+        });
+        let sp = scoped(AllProviders::TwitterTimeline, USER_ID);
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        assert_eq!(scraped_posts(&result), ["1003"]);
+        let credentials = provider.guest_token.credentials(&result.account).unwrap();
+        assert_eq!(credentials.access_token, "1400000000000000001");
     }
 }
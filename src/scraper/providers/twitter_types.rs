@@ -1,3 +1,6 @@
+// most of these types are generated and only partially read
+#![allow(dead_code)]
+
 // Example code that deserializes and serializes the model.
 // extern crate serde;
 // #[macro_use]
@@ -1,14 +1,14 @@
-use std::{env, path::Path, sync::Arc, time::Instant};
+use std::{path::Path, sync::Arc, time::Instant};
 
 use async_trait::async_trait;
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 use log::error;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    request::{parse_successful_response, request_default_headers, HttpError},
-    scheduler::UnscopedLimiter,
+    request::{parse_successful_response, request_default_headers, HttpError, Upstream},
+    scheduler::ProviderRateLimiter,
     scraper::ProviderCredentials,
 };
 
@@ -16,16 +16,15 @@ use super::*;
 
 pub struct UnitedCubeArtistFeed {
     pub client: Arc<Client>,
-    pub credentials: SharedCredentials<ProviderCredentials>,
-    pub rate_limiter: UnscopedLimiter,
+    pub upstream: Upstream,
+    pub credentials: CredentialPool,
+    pub rate_limiter: ProviderRateLimiter,
 }
 
 #[async_trait]
 impl RateLimitable for UnitedCubeArtistFeed {
-    async fn wait(&self, _key: &str) -> () {
-        self.rate_limiter
-            .until_ready_with_jitter(default_jitter())
-            .await
+    fn limiter(&self) -> &ProviderRateLimiter {
+        &self.rate_limiter
     }
 }
 
@@ -65,6 +64,7 @@ struct LoginResponse {
 }
 
 /// Posts are divided between images and videos
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Clone)]
 #[serde(tag = "type_code", content = "data")]
 enum MediaData {
@@ -84,6 +84,7 @@ struct Post {
     media: Vec<MediaData>,
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize, Clone)]
 struct Page {
     has_next: bool,
@@ -125,8 +126,9 @@ impl Provider for UnitedCubeArtistFeed {
     {
         Self {
             client: input.client,
-            credentials: create_credentials(),
-            rate_limiter: Self::rate_limiter(),
+            upstream: input.upstream,
+            credentials: CredentialPool::from_env("UNITED_CUBE"),
+            rate_limiter: Self::rate_limiter(AllProviders::UnitedCubeArtistFeed),
         }
     }
 
@@ -148,11 +150,25 @@ impl Provider for UnitedCubeArtistFeed {
         PageSize(20)
     }
 
-    fn on_error(&self, http_error: &HttpError) -> anyhow::Result<ProviderErrorHandle> {
+    fn on_error(
+        &self,
+        http_error: &HttpError,
+        state: &ProviderState,
+    ) -> anyhow::Result<ProviderErrorHandle> {
+        if let Some(backoff) = rate_limit_backoff(http_error) {
+            return Ok(backoff);
+        }
         let err = match http_error {
             HttpError::ReqwestError(_err) => return Ok(ProviderErrorHandle::Halt),
             HttpError::FailStatus(err) | HttpError::UnexpectedBody(err) => err,
         };
+        if err.code == 401 || err.code == 403 {
+            return Ok(if self.credentials.report_auth_failure(&state.account) {
+                ProviderErrorHandle::Halt
+            } else {
+                ProviderErrorHandle::Login
+            });
+        }
 
         let body = match serde_json::from_str::<GenericError>(&err.body) {
             Err(err) => {
@@ -163,8 +179,10 @@ impl Provider for UnitedCubeArtistFeed {
             Ok(body) => body,
         };
         Ok(if body.message == "Token Expired" && err.code == 400 {
-            let cred = self.credentials.read().clone();
-            ProviderErrorHandle::RefreshToken((cred).unwrap())
+            match self.credentials.credentials(&state.account) {
+                Some(cred) => ProviderErrorHandle::RefreshToken(cred),
+                None => ProviderErrorHandle::Login,
+            }
         } else {
             // I don't think there is any other response you can get if your token is expired
             // so we can probably assume that something else has gone wrong
@@ -191,7 +209,7 @@ impl Provider for UnitedCubeArtistFeed {
     }
 
     async fn unfold(&self, state: ProviderState) -> Result<ProviderStep, ProviderFailure> {
-        let creds = self.credentials.read().clone();
+        let creds = self.credentials.credentials(&state.account);
         let credentials = match creds {
             Some(c) => c,
             None => return Ok(ProviderStep::NotInitialized),
@@ -202,7 +220,7 @@ impl Provider for UnitedCubeArtistFeed {
 
         let response = self
             .client
-            .get(&state.url.0)
+            .get(self.upstream.url(&state.url.0))
             .headers(request_default_headers())
             .header("Authorization", &format!("Bearer {}", token))
             .send()
@@ -301,20 +319,22 @@ impl Provider for UnitedCubeArtistFeed {
         }
     }
 
-    fn credentials(&self) -> SharedCredentials<ProviderCredentials> {
-        self.credentials.clone()
+    fn credentials(&self) -> Option<&CredentialPool> {
+        Some(&self.credentials)
     }
 
-    async fn login(&self) -> Result<ProviderCredentials, ProviderFailure> {
+    async fn login(&self, account: &Account) -> Result<ProviderCredentials, ProviderFailure> {
+        let login = account.login.as_ref().ok_or_else(|| {
+            ProviderFailure::Other(format!("{} has no united_cube login details", account.name))
+        })?;
         let response = self
             .client
-            .post("https://united-cube.com/v1/auth/login")
+            .post(self.upstream.url("https://united-cube.com/v1/auth/login"))
             .json(&LoginInput {
                 refresh_token: None,
                 path: "https://www.united-cube.com/signin".to_owned(),
-                id: env::var("UNITED_CUBE_EMAIL")
-                    .expect("Tried to login to united_cube without credentials"),
-                pw: env::var("UNITED_CUBE_PASSWORD").unwrap(),
+                id: login.username.clone(),
+                pw: login.password.clone(),
                 remember_me: false,
             })
             .send()
@@ -324,6 +344,7 @@ impl Provider for UnitedCubeArtistFeed {
         Ok(ProviderCredentials {
             access_token: response.token,
             refresh_token: response.refresh_token,
+            expires_at: None,
         })
     }
     async fn token_refresh(
@@ -333,7 +354,7 @@ impl Provider for UnitedCubeArtistFeed {
         let refresh_token = credentials.refresh_token.clone();
         let response = self
             .client
-            .post("https://united-cube.com/v1/auth/refresh")
+            .post(self.upstream.url("https://united-cube.com/v1/auth/refresh"))
             .json(&RefreshInput {
                 refresh_token: refresh_token.clone(),
             })
@@ -344,6 +365,75 @@ impl Provider for UnitedCubeArtistFeed {
         Ok(CredentialRefresh::Result(ProviderCredentials {
             access_token: response.token,
             refresh_token,
+            expires_at: None,
         }))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::scraper::fixtures::{first_scrape, scoped, scraped_posts, unlimited, FixtureServer};
+    use crate::scraper::scraper::{scrape, ScraperStep};
+    use crate::scraper::{
+        Account, AccountLogin, AllProviders, CredentialPool, Provider, ProviderCredentials,
+        RotationStrategy,
+    };
+
+    use super::UnitedCubeArtistFeed;
+
+    const BOARD: &str = "club|board";
+
+    fn provider(
+        server: &FixtureServer,
+        credentials: Option<ProviderCredentials>,
+    ) -> UnitedCubeArtistFeed {
+        let input = server.provider_input();
+        let account = Account::new(
+            "jiu@example.com".to_owned(),
+            Some(AccountLogin {
+                username: "jiu@example.com".to_owned(),
+                password: "hunter2".to_owned(),
+            }),
+        );
+        *account.credentials.write() = credentials;
+        UnitedCubeArtistFeed {
+            client: input.client,
+            upstream: input.upstream,
+            credentials: CredentialPool::new(vec![account], RotationStrategy::RoundRobin),
+            rate_limiter: unlimited(),
+        }
+    }
+
+    #[tokio::test]
+    async fn refreshes_expired_tokens_and_paginates() {
+        let server = FixtureServer::start("united_cube/token_expired").await;
+        let provider = provider(
+            &server,
+            Some(ProviderCredentials {
+                access_token: "stale".to_owned(),
+                refresh_token: "refresh".to_owned(),
+                expires_at: None,
+            }),
+        );
+        let sp = scoped(AllProviders::UnitedCubeArtistFeed, BOARD);
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        assert_eq!(scraped_posts(&result), ["post-1", "post-2", "post-3"]);
+        let credentials = provider.credentials.credentials(&result.account).unwrap();
+        assert_eq!(credentials.access_token, "fresh");
+        assert_eq!(credentials.refresh_token, "refresh");
+    }
+
+    #[tokio::test]
+    async fn logs_in_and_halts_on_unknown_errors() {
+        let server = FixtureServer::start("united_cube/login_then_error").await;
+        let provider = provider(&server, None);
+        provider.initialize().await;
+        let sp = scoped(AllProviders::UnitedCubeArtistFeed, BOARD);
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        assert_eq!(result.account.as_deref(), Some("jiu@example.com"));
+        assert_eq!(result.requests.len(), 1);
+        assert!(matches!(result.requests[0].step, ScraperStep::Error(_)));
+    }
+}
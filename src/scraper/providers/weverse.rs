@@ -1,9 +1,8 @@
-use std::error::Error;
 use std::{env, iter::FromIterator, sync::Arc, time::Instant};
 
 use async_trait::async_trait;
 use bimap::{BiHashMap, BiMap};
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Utc};
 use governor::Quota;
 use lazy_static::lazy_static;
 use log::info;
@@ -15,17 +14,22 @@ use serde::{Deserialize, Serialize};
 use sha1::Sha1;
 
 use crate::{
-    request::{parse_successful_response, request_default_headers, HttpError},
-    scheduler::UnscopedLimiter,
+    request::{parse_successful_response, request_default_headers, HttpError, Upstream},
+    scheduler::ProviderRateLimiter,
     scraper::{providers::ProviderMediaType, ProviderMedia, ProviderResult},
 };
 
 use super::*;
 
+const TOKEN_URL: &str = "https://accountapi.weverse.io/api/v1/oauth/token";
+
 /// https://gist.github.com/Xetera/aa59e84f3959a37c16a3309b5d9ab5a0
-async fn get_public_key(client: &Client) -> Result<RSAPublicKey, ProviderFailure> {
+async fn get_public_key(
+    client: &Client,
+    upstream: &Upstream,
+) -> Result<RSAPublicKey, ProviderFailure> {
     let login_page = client
-        .post("https://account.weverse.io/login/auth?client_id=weverse-test&hl=en")
+        .post(upstream.url("https://account.weverse.io/login/auth?client_id=weverse-test&hl=en"))
         .send()
         .await?
         .text()
@@ -45,7 +49,12 @@ async fn get_public_key(client: &Client) -> Result<RSAPublicKey, ProviderFailure
         ))?
         .as_str();
     let js_bundle_url = format!("https://account.weverse.io/{}", js_name);
-    let js_bundle = client.get(&js_bundle_url).send().await?.text().await?;
+    let js_bundle = client
+        .get(upstream.url(&js_bundle_url))
+        .send()
+        .await?
+        .text()
+        .await?;
     let rsa_captures =
         Regex::new(r"(-----BEGIN RSA PUBLIC KEY-----(.|\n)+----END RSA PUBLIC KEY-----)")
             .unwrap()
@@ -84,27 +93,14 @@ fn encrypted_password(
     Ok(base64::encode(encrypted))
 }
 
-#[derive(Serialize)]
-struct WeverseLoginRequest {
-    grant_type: String,
-    client_id: String,
-    username: String,
-    password: String,
-}
-
-#[derive(Debug, Deserialize)]
-pub struct WeverseLoginResponse {
-    refresh_token: String,
-    access_token: String,
-}
-
 async fn get_access_token(
     email: String,
     encrypted_password: String,
     client: &Client,
+    upstream: &Upstream,
 ) -> Result<WeverseAuthorizeResponse, ProviderFailure> {
     Ok(client
-        .post("https://accountapi.weverse.io/api/v1/oauth/token")
+        .post(upstream.url(TOKEN_URL))
         .json(&WeverseAuthorizeInput::Login {
             grant_type: "password".to_owned(),
             client_id: "weverse-test".to_owned(),
@@ -119,36 +115,46 @@ async fn get_access_token(
 
 pub async fn fetch_weverse_auth_token(
     client: &Client,
-) -> Result<Option<ProviderCredentials>, ProviderFailure> {
-    match (
-        env::var("WEVERSE_ACCESS_TOKEN"),
-        env::var("WEVERSE_EMAIL"),
-        env::var("WEVERSE_PASSWORD"),
-    ) {
-        (Ok(access_token), _, _) => {
+    upstream: &Upstream,
+    login: &AccountLogin,
+) -> Result<ProviderCredentials, ProviderFailure> {
+    let public_key = get_public_key(client, upstream).await?;
+    let encrypted = encrypted_password(login.password.clone(), public_key)?;
+    let token = get_access_token(login.username.clone(), encrypted, client, upstream).await?;
+    Ok(ProviderCredentials {
+        access_token: token.access_token,
+        refresh_token: token.refresh_token,
+        expires_at: None,
+    }
+    .expiring_in(token.expires_in.into()))
+}
+
+/// Accounts that log in with `WEVERSE_EMAIL` and `WEVERSE_PASSWORD` (see [`CredentialPool::from_env`])
+/// as well as an existing token from `WEVERSE_ACCESS_TOKEN`
+fn weverse_accounts() -> CredentialPool {
+    let pool = CredentialPool::from_env("WEVERSE");
+    match env::var("WEVERSE_ACCESS_TOKEN") {
+        Ok(access_token) => {
             info!("An existing weverse token was found");
-            Ok(Some(ProviderCredentials {
-                access_token,
-                refresh_token: "".to_owned(),
-            }))
+            pool.with_credentials(
+                "access_token",
+                ProviderCredentials {
+                    access_token,
+                    refresh_token: "".to_owned(),
+                    expires_at: None,
+                },
+            )
         }
-        (_, Ok(email), Ok(password)) => {
-            info!("Detected weverse credentials, attempting to login...");
-            let public_key = get_public_key(client).await?;
-            let encrypted = encrypted_password(password, public_key)?;
-            let token = get_access_token(email, encrypted, client).await?;
-            Ok(Some(ProviderCredentials {
-                access_token: token.access_token,
-                refresh_token: token.refresh_token,
-            }))
-        }
-        _ => {
-            info!("Weverse credentials missing, not initializing Weverse module");
-            Ok(None)
+        Err(_) => {
+            if pool.accounts().is_empty() {
+                info!("Weverse credentials missing, not initializing Weverse module");
+            }
+            pool
         }
     }
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Clone, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct WeversePhoto {
@@ -208,6 +214,7 @@ enum WeverseAuthorizeInput {
     },
 }
 
+#[allow(dead_code)]
 #[derive(Debug, Deserialize)]
 struct WeverseAuthorizeResponse {
     access_token: String,
@@ -216,9 +223,6 @@ struct WeverseAuthorizeResponse {
     refresh_token: String,
 }
 
-#[derive(Debug, Serialize)]
-struct WeverseTokenRefreshInput {}
-
 #[derive(Debug, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct WeversePage {
@@ -230,8 +234,9 @@ pub struct WeversePage {
 // #[derive(Clone)]
 pub struct WeverseArtistFeed {
     pub client: Arc<Client>,
-    pub credentials: SharedCredentials<ProviderCredentials>,
-    pub rate_limiter: UnscopedLimiter,
+    pub upstream: Upstream,
+    pub credentials: CredentialPool,
+    pub rate_limiter: ProviderRateLimiter,
 }
 
 lazy_static! {
@@ -242,7 +247,7 @@ lazy_static! {
 fn url_from_post(artist_id: u32, post_id: u64, photo_id: u64) -> String {
     let artist_name = ARTIST_MAPPINGS
         .get_by_left(&artist_id)
-        .expect(&format!("Weverse ID {} is not a valid mapping", artist_id));
+        .unwrap_or_else(|| panic!("Weverse ID {} is not a valid mapping", artist_id));
     format!(
         "https://weverse.io/{}/artist/{}?photoId={}",
         artist_name, post_id, photo_id
@@ -261,10 +266,8 @@ impl RateLimitable for WeverseArtistFeed {
     {
         default_quota()
     }
-    async fn wait(&self, _key: &str) -> () {
-        self.rate_limiter
-            .until_ready_with_jitter(default_jitter())
-            .await
+    fn limiter(&self) -> &ProviderRateLimiter {
+        &self.rate_limiter
     }
 }
 
@@ -275,9 +278,10 @@ impl Provider for WeverseArtistFeed {
         Self: Sized,
     {
         Self {
-            credentials: create_credentials(),
+            credentials: weverse_accounts(),
             client: Arc::clone(&input.client),
-            rate_limiter: Self::rate_limiter(),
+            upstream: input.upstream,
+            rate_limiter: Self::rate_limiter(AllProviders::WeverseArtistFeed),
         }
     }
     fn id(&self) -> AllProviders {
@@ -336,7 +340,7 @@ impl Provider for WeverseArtistFeed {
     }
 
     async fn unfold(&self, state: ProviderState) -> Result<ProviderStep, ProviderFailure> {
-        let credentials = self.credentials.read().clone();
+        let credentials = self.credentials.credentials(&state.account);
         // let token = "".to_owned();
         let token = match credentials {
             Some(token) => token.access_token,
@@ -346,7 +350,7 @@ impl Provider for WeverseArtistFeed {
         let instant = Instant::now();
         let response = self
             .client
-            .get(&state.url.0)
+            .get(self.upstream.url(&state.url.0))
             .headers(request_default_headers())
             .header("Authorization", format!("Bearer {}", token))
             .send()
@@ -367,7 +371,7 @@ impl Provider for WeverseArtistFeed {
                 let post_created_at = post.created_at;
                 let photos = post.photos.unwrap_or_default();
                 let page_url = photos
-                    .get(0)
+                    .first()
                     .map(|photo| url_from_post(community_id, post_id, photo.id));
                 ProviderPost {
                     account: ProviderAccount {
@@ -421,19 +425,26 @@ impl Provider for WeverseArtistFeed {
         Ok(ProviderStep::End(result))
     }
 
-    fn on_error(&self, http_error: &HttpError) -> anyhow::Result<ProviderErrorHandle> {
+    fn on_error(
+        &self,
+        http_error: &HttpError,
+        state: &ProviderState,
+    ) -> anyhow::Result<ProviderErrorHandle> {
+        if let Some(backoff) = rate_limit_backoff(http_error) {
+            return Ok(backoff);
+        }
         match http_error {
             HttpError::FailStatus(err) | HttpError::UnexpectedBody(err) => {
                 // :) I don't actually know if weverse returns a 401 on expired tokens
                 // but I can't test because their tokens last for 6 ENTIRE months!!!!
                 if err.code == 401 || err.code == 403 {
-                    let handle = self
-                        .credentials
-                        .clone()
-                        .try_read()
-                        .map_or(ProviderErrorHandle::Login, |creds| {
-                            ProviderErrorHandle::RefreshToken(creds.clone().unwrap())
-                        });
+                    if self.credentials.report_auth_failure(&state.account) {
+                        return Ok(ProviderErrorHandle::Halt);
+                    }
+                    let handle = self.credentials.credentials(&state.account).map_or(
+                        ProviderErrorHandle::Login,
+                        ProviderErrorHandle::RefreshToken,
+                    );
                     return Ok(handle);
                 }
                 Ok(ProviderErrorHandle::Halt)
@@ -452,7 +463,7 @@ impl Provider for WeverseArtistFeed {
         };
         let out = self
             .client
-            .post("https://accountapi.weverse.io/api/v1/oauth/token")
+            .post(self.upstream.url(TOKEN_URL))
             .json(&input)
             .send()
             .await?
@@ -461,16 +472,93 @@ impl Provider for WeverseArtistFeed {
         let credentials_result = ProviderCredentials {
             access_token: out.access_token,
             refresh_token: out.refresh_token,
-        };
+            expires_at: None,
+        }
+        .expiring_in(out.expires_in.into());
         Ok(CredentialRefresh::Result(credentials_result))
     }
-    async fn login(&self) -> Result<ProviderCredentials, ProviderFailure> {
-        let credentials = fetch_weverse_auth_token(&self.client)
-            .await?
-            .expect("Tried to authorize weverse module but the login credentials were not found");
-        Ok(credentials)
+    async fn login(&self, account: &Account) -> Result<ProviderCredentials, ProviderFailure> {
+        let login = account.login.as_ref().ok_or_else(|| {
+            ProviderFailure::Other(format!("{} has no weverse login details", account.name))
+        })?;
+        info!("Logging into weverse as {}", account.name);
+        fetch_weverse_auth_token(&self.client, &self.upstream, login).await
+    }
+    fn credentials(&self) -> Option<&CredentialPool> {
+        Some(&self.credentials)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use crate::scraper::fixtures::{first_scrape, scoped, scraped_posts, unlimited, FixtureServer};
+    use crate::scraper::scraper::scrape;
+    use crate::scraper::{
+        Account, AccountLogin, AllProviders, CredentialPool, Provider, ProviderCredentials,
+        RotationStrategy,
+    };
+
+    use super::WeverseArtistFeed;
+
+    const COMMUNITY: &str = "14";
+
+    fn provider(
+        server: &FixtureServer,
+        credentials: Option<ProviderCredentials>,
+    ) -> WeverseArtistFeed {
+        let input = server.provider_input();
+        let account = Account::new(
+            "jiu@example.com".to_owned(),
+            Some(AccountLogin {
+                username: "jiu@example.com".to_owned(),
+                password: "hunter2".to_owned(),
+            }),
+        );
+        *account.credentials.write() = credentials;
+        WeverseArtistFeed {
+            client: input.client,
+            upstream: input.upstream,
+            credentials: CredentialPool::new(vec![account], RotationStrategy::RoundRobin),
+            rate_limiter: unlimited(),
+        }
+    }
+
+    #[tokio::test]
+    async fn logs_in_with_an_encrypted_password() {
+        let server = FixtureServer::start("weverse/login").await;
+        let provider = provider(&server, None);
+        provider.initialize().await;
+        let sp = scoped(AllProviders::WeverseArtistFeed, COMMUNITY);
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        assert_eq!(scraped_posts(&result), ["5001"]);
+        let credentials = provider.credentials.credentials(&result.account).unwrap();
+        assert_eq!(credentials.access_token, "access");
+        // the token response expires in a day
+        let expires_at = credentials.expires_at.unwrap();
+        assert!(expires_at > Utc::now() + Duration::hours(23));
     }
-    fn credentials(&self) -> SharedCredentials<ProviderCredentials> {
-        self.credentials.clone()
+
+    #[tokio::test]
+    async fn refreshes_rejected_tokens_and_paginates() {
+        let server = FixtureServer::start("weverse/token_refresh").await;
+        let provider = provider(
+            &server,
+            Some(ProviderCredentials {
+                access_token: "stale".to_owned(),
+                refresh_token: "refresh".to_owned(),
+                expires_at: None,
+            }),
+        );
+        let sp = scoped(AllProviders::WeverseArtistFeed, COMMUNITY);
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        server.finish();
+        assert_eq!(result.requests.len(), 2);
+        assert_eq!(scraped_posts(&result), ["5003", "5002", "5001"]);
+        let credentials = provider.credentials.credentials(&result.account).unwrap();
+        assert_eq!(credentials.access_token, "fresh");
+        assert_eq!(credentials.refresh_token, "fresh-refresh");
     }
 }
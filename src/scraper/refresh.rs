@@ -0,0 +1,133 @@
+//! Refreshes provider tokens shortly before they expire so scrapes don't have to
+//! run into an authorization error first
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+
+use log::{debug, error, info, warn};
+
+use crate::scraper::{
+    credentials::CredentialStore, scraper::store_account_credentials, Account, CredentialRefresh,
+    Provider, ProviderMap,
+};
+
+/// How often accounts are checked for tokens that are about to expire
+const REFRESH_POLL_INTERVAL_SECONDS: u64 = 60;
+
+const DEFAULT_REFRESH_MARGIN_SECONDS: u64 = 10 * 60;
+
+/// Tokens are refreshed once they expire within this margin. Can be overridden
+/// with the `TOKEN_REFRESH_MARGIN_SECONDS` environment variable
+pub fn refresh_margin() -> Duration {
+    let seconds = env::var("TOKEN_REFRESH_MARGIN_SECONDS")
+        .ok()
+        .and_then(|seconds| seconds.parse::<u64>().ok())
+        .unwrap_or(DEFAULT_REFRESH_MARGIN_SECONDS);
+    Duration::from_secs(seconds)
+}
+
+/// Accounts of a provider whose tokens expire within the margin
+fn expiring_accounts(provider: &dyn Provider, margin: Duration) -> Vec<Arc<Account>> {
+    let pool = match provider.credentials() {
+        Some(pool) => pool,
+        None => return vec![],
+    };
+    pool.accounts()
+        .iter()
+        .filter(|account| {
+            account
+                .credentials
+                .read()
+                .as_ref()
+                .is_some_and(|credentials| credentials.expires_within(margin))
+        })
+        .cloned()
+        .collect()
+}
+
+async fn refresh_account(
+    provider: &dyn Provider,
+    store: Option<&CredentialStore>,
+    account: &Account,
+) -> anyhow::Result<()> {
+    let credentials = match account.credentials.read().clone() {
+        Some(credentials) => credentials,
+        None => return Ok(()),
+    };
+    debug!("Refreshing {} token for {}", provider.id(), account.name);
+    let refreshed = match provider.token_refresh(&credentials).await? {
+        CredentialRefresh::Result(refreshed) => refreshed,
+        CredentialRefresh::TryLogin => provider.login(account).await?,
+        CredentialRefresh::Halt => {
+            warn!(
+                "{} refused to refresh the token for {}, waiting for it to expire",
+                provider.id(),
+                account.name
+            );
+            return Ok(());
+        }
+    };
+    store_account_credentials(provider, store, account, refreshed).await;
+    info!("Refreshed {} token for {}", provider.id(), account.name);
+    Ok(())
+}
+
+async fn refresh_expiring_tokens(provider_map: &ProviderMap, store: Option<&CredentialStore>) {
+    let margin = refresh_margin();
+    for provider in provider_map.values() {
+        for account in expiring_accounts(&**provider, margin) {
+            if let Err(err) = refresh_account(&**provider, store, &account).await {
+                error!(
+                    "Could not refresh the {} token for {}",
+                    provider.id(),
+                    account.name
+                );
+                error!("{:?}", err);
+            }
+        }
+    }
+}
+
+/// Periodically refreshes the tokens of every provider account before they expire.
+/// Tokens that fail to refresh here still go through the usual `on_error` flow
+pub async fn token_refresh_loop(
+    provider_map: Arc<ProviderMap>,
+    store: Option<Arc<CredentialStore>>,
+) {
+    loop {
+        refresh_expiring_tokens(&provider_map, store.as_deref()).await;
+        tokio::time::sleep(Duration::from_secs(REFRESH_POLL_INTERVAL_SECONDS)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use crate::scraper::ProviderCredentials;
+
+    fn credentials() -> ProviderCredentials {
+        ProviderCredentials {
+            access_token: "token".to_owned(),
+            refresh_token: "refresh".to_owned(),
+            expires_at: None,
+        }
+    }
+
+    #[test]
+    fn tokens_expiring_within_the_margin_are_refreshed() {
+        let margin = Duration::from_secs(10 * 60);
+        assert!(credentials().expiring_in(60).expires_within(margin));
+        assert!(credentials().expiring_in(-60).expires_within(margin));
+        assert!(!credentials().expiring_in(60 * 60).expires_within(margin));
+    }
+
+    #[test]
+    fn tokens_without_an_expiry_are_left_alone() {
+        assert!(!credentials().expires_within(Duration::from_secs(u64::MAX)));
+        let expiring = credentials().expiring_in(30);
+        assert!(expiring.expires_at.unwrap() > Utc::now());
+    }
+}
@@ -1,12 +1,15 @@
-use std::time::Instant;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use async_recursion::async_recursion;
 use chrono::{NaiveDateTime, Utc};
 use futures::StreamExt;
-use log::{debug, error, info, trace};
-use crate::api::v1::ProviderStat;
+use log::{debug, error, info, trace, warn};
 
+use crate::db::{latest_media_ids_from_provider, Database};
+use crate::models::PendingProvider;
 use crate::scraper::{
+    credentials::CredentialStore,
     providers::{CredentialRefresh, ProviderErrorHandle},
     ProviderPost,
 };
@@ -14,18 +17,20 @@ use crate::scraper::scraper::ScraperErrorHandleDecision::{Continue, MaxLoginAtte
 
 use super::{
     providers::{Provider, ProviderFailure, ProviderState, ProviderStep, ScrapeRequestInput},
-    ProviderCredentials, ProviderResult, ScopedProvider,
+    Account, ProviderCredentials, ProviderResult, ScopedProvider,
 };
 
 #[derive(Debug)]
 pub struct Scrape<'a> {
     pub provider: &'a ScopedProvider,
+    /// the account from the provider's credential pool that served the requests
+    pub account: Option<String>,
     pub requests: Vec<ScrapeRequest>,
 }
 
 impl Scrape<'_> {
     pub fn discovered_new_images(&self) -> bool {
-        let step = match self.requests.get(0) {
+        let step = match self.requests.first() {
             None => return false,
             Some(req) => &req.step,
         };
@@ -61,10 +66,78 @@ enum ScraperErrorHandleDecision {
     MaxLoginAttempts(u32),
 }
 
-fn write_provider_credentials(provider: &dyn Provider, credentials: ProviderCredentials) {
-    let creds = provider.credentials();
-    let mut credential_ref = creds.write();
-    *credential_ref = Some(credentials);
+async fn write_provider_credentials(
+    provider: &dyn Provider,
+    store: Option<&CredentialStore>,
+    account: &Option<String>,
+    credentials: ProviderCredentials,
+) {
+    if let Some(account) = account_of(provider, account) {
+        store_account_credentials(provider, store, &account, credentials).await;
+    }
+}
+
+/// Hands new credentials to an account, persisting them if there is a store
+pub(crate) async fn store_account_credentials(
+    provider: &dyn Provider,
+    store: Option<&CredentialStore>,
+    account: &Account,
+    credentials: ProviderCredentials,
+) {
+    if let Some(store) = store {
+        // losing the stored copy only means having to log in again after a restart
+        if let Err(err) = store.save(provider.id(), &account.name, &credentials).await {
+            error!("Could not persist credentials for {}", provider.id());
+            error!("{:?}", err);
+        }
+    }
+    *account.credentials.write() = Some(credentials);
+}
+
+fn account_of(provider: &dyn Provider, account: &Option<String>) -> Option<Arc<Account>> {
+    provider.credentials()?.get(account.as_deref()?)
+}
+
+async fn login(
+    provider: &dyn Provider,
+    account: &Option<String>,
+) -> Result<ProviderCredentials, ProviderFailure> {
+    match account_of(provider, account) {
+        Some(account) => provider.login(&account).await,
+        None => Err(ProviderFailure::Other(format!(
+            "{} has no account to log in with",
+            provider.id()
+        ))),
+    }
+}
+
+/// Backoff used when a rate limited response doesn't say how long to wait
+const DEFAULT_BACKOFF_SECONDS: u64 = 5;
+
+/// Rate limits that last longer than this aren't worth waiting for in the middle of a scrape
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+fn backoff_delay(attempts: u32) -> Duration {
+    Duration::from_secs(DEFAULT_BACKOFF_SECONDS * 2u64.pow(attempts.min(10)))
+}
+
+fn out_of_budget(provider: &dyn Provider) -> ProviderFailure {
+    ProviderFailure::Other(format!(
+        "{} is out of its daily request budget",
+        provider.id()
+    ))
+}
+
+/// Counts another request towards the provider's budget and waits for its rate limit. Every
+/// request of a scrape goes through this, the first page, the next ones and the ones tried again
+/// after a rate limit or a login. Returns false if the budget ran out
+async fn ready_for_request(sp: &ScopedProvider, provider: &dyn Provider) -> bool {
+    if !provider.limiter().spend_budget().await {
+        return false;
+    }
+    trace!("Waiting for provider rate limit...");
+    provider.wait(&sp.destination).await;
+    true
 }
 
 fn should_continue_requests(state: &ProviderState, provider: &dyn Provider) -> ScraperErrorHandleDecision {
@@ -73,7 +146,7 @@ fn should_continue_requests(state: &ProviderState, provider: &dyn Provider) -> S
         error!("Failed to login to {} after {} attempts. Giving up.", provider.id().to_string(), max_attempts);
         return MaxLoginAttempts(max_attempts);
     }
-    return Continue;
+    Continue
 }
 
 #[async_recursion]
@@ -82,6 +155,7 @@ async fn request_page<'a>(
     provider: &dyn Provider,
     state: ProviderState,
     input: &ScrapeRequestInput,
+    store: Option<&'a CredentialStore>,
 ) -> (InternalScraperStep, Option<ProviderState>) {
     let iteration = state.iteration;
     let error_step = |error| {
@@ -89,26 +163,57 @@ async fn request_page<'a>(
         (InternalScraperStep::Error(error), None)
     };
     let give_up = (InternalScraperStep::Exit, None);
-    let write_credentials_and_continue = |creds: ProviderCredentials| {
-        write_provider_credentials(provider, creds);
+    let current_state = &state;
+    let write_credentials_and_continue = |creds: ProviderCredentials| async move {
+        write_provider_credentials(provider, store, &current_state.account, creds).await;
+        if !ready_for_request(sp, provider).await {
+            return error_step(out_of_budget(provider));
+        }
         let new_state = ProviderState {
-            login_attempts: state.login_attempts + 1,
-            ..state.clone()
+            login_attempts: current_state.login_attempts + 1,
+            ..current_state.clone()
         };
-        request_page(sp, provider, new_state, input)
+        request_page(sp, provider, new_state, input, store).await
     };
-    match provider.unfold(state.to_owned()).await {
+    let step = provider.unfold(state.to_owned()).await;
+    if let (Ok(_), Some(pool)) = (&step, provider.credentials()) {
+        pool.report_success(&state.account);
+    }
+    match step {
         // we have to indicate an error to the consumer and stop iteration on the next cycle
         Err(error) => match &error {
-            ProviderFailure::HttpError(http_error) => match provider.on_error(http_error) {
+            ProviderFailure::HttpError(http_error) => match provider.on_error(http_error, &state) {
                 Ok(ProviderErrorHandle::Halt) => error_step(error),
+                Ok(ProviderErrorHandle::Backoff(retry_after)) => {
+                    let max_attempts = provider.max_backoff_attempts();
+                    if state.backoff_attempts >= max_attempts {
+                        error!("{} is still rate limited after backing off {} times. Giving up", sp, max_attempts);
+                        return error_step(error);
+                    }
+                    let delay = retry_after.unwrap_or_else(|| backoff_delay(state.backoff_attempts));
+                    if delay > MAX_BACKOFF {
+                        error!("{} is rate limited for {:?}. Giving up", sp, delay);
+                        return error_step(error);
+                    }
+                    warn!("{} is being rate limited, retrying in {:?}", sp, delay);
+                    provider.limiter().slow_down();
+                    tokio::time::sleep(delay).await;
+                    if !ready_for_request(sp, provider).await {
+                        return error_step(out_of_budget(provider));
+                    }
+                    let new_state = ProviderState {
+                        backoff_attempts: state.backoff_attempts + 1,
+                        ..state.clone()
+                    };
+                    request_page(sp, provider, new_state, input, store).await
+                }
                 Ok(ProviderErrorHandle::Login) => {
                     if let MaxLoginAttempts(count) = should_continue_requests(&state, provider) {
                         error!("Too many login attempts ({}) for {}. Giving up", count, provider.id().to_string());
                         return give_up;
                     }
                     debug!("Triggering login flow for {}", provider.id().to_string());
-                    match provider.login().await {
+                    match login(provider, &state.account).await {
                         Ok(credentials) => write_credentials_and_continue(credentials).await,
                         Err(error) => error_step(error),
                     }
@@ -128,7 +233,7 @@ async fn request_page<'a>(
                         }
                         Ok(CredentialRefresh::TryLogin) => {
                             debug!("Triggering login flow for {}", provider.id().to_string());
-                            match provider.login().await {
+                            match login(provider, &state.account).await {
                                 Ok(credentials) => {
                                     write_credentials_and_continue(credentials).await
                                 }
@@ -188,10 +293,46 @@ async fn request_page<'a>(
     }
 }
 
+/// What a scrape of a resource needs to know about its previous ones
+pub async fn scrape_input(
+    db: &Database,
+    pending: &PendingProvider,
+) -> anyhow::Result<ScrapeRequestInput> {
+    let sp = &pending.provider;
+    let latest_data = latest_media_ids_from_provider(db, sp).await?;
+    // there must be at least ONE data found if the scrape isn't the first ever one
+    let is_first_scrape = latest_data.is_empty();
+    if is_first_scrape {
+        trace!(
+            "Scraping {}: {} for the first time ever",
+            sp.name.to_string(),
+            sp.destination
+        )
+    }
+    Ok(ScrapeRequestInput {
+        latest_data,
+        is_first_scrape,
+        default_name: pending.default_name.clone(),
+        last_scrape: pending.last_scrape,
+    })
+}
+
 pub async fn scrape<'a>(
     sp: &'a ScopedProvider,
     provider: &dyn Provider,
     input: &ScrapeRequestInput,
+    store: Option<&CredentialStore>,
+) -> Result<Scrape<'a>, ProviderFailure> {
+    scrape_with_progress(sp, provider, input, store, &|_| {}).await
+}
+
+/// Scrapes a resource, handing every request to `on_request` as soon as it's done
+pub async fn scrape_with_progress<'a>(
+    sp: &'a ScopedProvider,
+    provider: &dyn Provider,
+    input: &ScrapeRequestInput,
+    store: Option<&CredentialStore>,
+    on_request: &(dyn Fn(&ScrapeRequest) + Send + Sync),
 ) -> Result<Scrape<'a>, ProviderFailure> {
     let initial_iteration = 0;
     let page_size = if input.is_first_scrape {
@@ -201,9 +342,19 @@ pub async fn scrape<'a>(
     };
     let id = sp.destination.clone();
     let url = provider.from_provider_destination(&id, page_size.to_owned(), None)?;
+    // workers run many scrapes at once, so even single page scrapes wait for the rate limit
+    if !ready_for_request(sp, provider).await {
+        return Err(out_of_budget(provider));
+    }
 
+    let account = provider
+        .credentials()
+        .and_then(|pool| pool.checkout())
+        .map(|account| account.name.clone());
     let seed = ProviderState {
         login_attempts: 0,
+        backoff_attempts: 0,
+        account: account.clone(),
         id: id.clone(),
         default_name: input.default_name.clone(),
         url,
@@ -214,7 +365,7 @@ pub async fn scrape<'a>(
     let mut steps = futures::stream::unfold(Some(seed), |maybe_state| async {
         let state = maybe_state?;
         info!("Scraping URL: {:?}", state.url.0);
-        Some(request_page(sp, provider, state, input).await)
+        Some(request_page(sp, provider, state, input, store).await)
     })
         .boxed();
 
@@ -226,15 +377,16 @@ pub async fn scrape<'a>(
         match step {
             InternalScraperStep::Exit => break,
             InternalScraperStep::Error(error) => {
-                scrape_requests.push(ScrapeRequest {
+                let request = ScrapeRequest {
                     date,
                     step: ScraperStep::Error(error),
-                });
+                };
+                on_request(&request);
+                scrape_requests.push(request);
                 // no reason to continue scraping after an error
                 break;
             }
             InternalScraperStep::Data(page) => {
-                let total_found_images = page.posts.iter().flat_map(|p| &p.images).count();
                 let mut posts: Vec<ProviderPost> = vec![];
                 for post in page.posts {
                     // it SHOULDN'T be possible for us to have seen a post and only
@@ -257,10 +409,12 @@ pub async fn scrape<'a>(
                 let new_image_count = posts.iter().map(|p| &p.images).len();
                 info!("Found {} new images in {}", posts.len(), sp);
 
-                scrape_requests.push(ScrapeRequest {
+                let request = ScrapeRequest {
                     date,
                     step: ScraperStep::Data(ProviderResult { posts, ..page }),
-                });
+                };
+                on_request(&request);
+                scrape_requests.push(request);
 
                 if new_image_count == 0 {
                     info!(
@@ -280,8 +434,14 @@ pub async fn scrape<'a>(
                     );
                     break;
                 }
-                trace!("Waiting for provider rate limit...");
-                provider.wait(&sp.destination).await;
+                if !ready_for_request(sp, provider).await {
+                    info!(
+                        "[{}] stopped paginating because {} is out of its daily request budget",
+                        sp,
+                        provider.id()
+                    );
+                    break;
+                }
             }
         }
     }
@@ -295,6 +455,56 @@ pub async fn scrape<'a>(
     );
     Ok(Scrape {
         provider: sp,
+        account,
         requests: scrape_requests,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::db::test_database;
+    use crate::scraper::fixtures::{first_scrape, scoped, unlimited, FixtureServer};
+    use crate::scraper::{AllProviders, MastodonAccount, ProviderFailure};
+
+    use super::{scrape, ScraperStep};
+
+    #[tokio::test]
+    async fn retries_spend_the_request_budget() {
+        // budgets are counted in the database
+        let db = match test_database().await {
+            Some(db) => Arc::new(db),
+            None => return,
+        };
+        let used = sqlx::query!(
+            "SELECT requests FROM provider_request_usage
+            WHERE provider_name = $1 AND day = (NOW() AT TIME ZONE 'UTC')::date",
+            AllProviders::MastodonAccount.to_string()
+        )
+        .fetch_optional(&*db)
+        .await
+        .unwrap()
+        .map_or(0, |usage| usage.requests);
+        let server = FixtureServer::start("mastodon/rate_limited").await;
+        let input = server.provider_input();
+        let provider = MastodonAccount {
+            client: input.client,
+            upstream: input.upstream,
+            // room for a single request on top of what was already used today
+            rate_limiter: unlimited().with_budget(Some(used as u32 + 1)),
+        };
+        provider
+            .rate_limiter
+            .share(Arc::clone(&db), AllProviders::MastodonAccount);
+        let sp = scoped(AllProviders::MastodonAccount, "mastodon.example|1090");
+        let result = scrape(&sp, &provider, &first_scrape(), None).await.unwrap();
+        // the only request the budget allows for is the one that got rate limited
+        server.finish();
+        assert_eq!(result.requests.len(), 1);
+        assert!(matches!(
+            result.requests[0].step,
+            ScraperStep::Error(ProviderFailure::Other(_))
+        ));
+    }
+}
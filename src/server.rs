@@ -1,27 +1,26 @@
-use std::convert::Infallible;
 use std::net::SocketAddr;
-use std::ops::Sub;
 use std::sync::Arc;
 
-use axum::body::{Bytes, Full};
 use axum::extract::Extension;
-use axum::http::Response;
-use axum::response::IntoResponse;
 use axum::routing::{get, post};
 use axum::{AddExtensionLayer, Json, Router};
-use chrono::{Duration, NaiveDate, NaiveDateTime, Utc};
-use log::{debug, error, info};
+use chrono::{Duration, NaiveDateTime, Utc};
+use log::{info, warn};
 use num_traits::ToPrimitive;
-use reqwest::StatusCode;
 use serde::Serialize;
-use serde_json::json;
 use sqlx::types::BigDecimal;
 
 use crate::api::v1::providers::v1_add_provider;
+use crate::api::v1::scrape::v1_scrape_provider;
+use crate::api::v1::webhooks::{
+    v1_dead_letters, v1_replay_dead_letter, v1_rotate_webhook_secret,
+};
 use crate::api::v1::{v1_provider_stats, v1_scheduled_scrapes, v1_scrape_history};
 use crate::api::{AppError, Context};
-use crate::db::{latest_requests, Database};
+use crate::db::Database;
+use crate::scraper::credentials::CredentialStore;
 use crate::scraper::ProviderMap;
+use crate::shutdown::Shutdown;
 
 struct ScheduledProvider {
     id: i32,
@@ -105,22 +104,44 @@ async fn scheduled_scrapes(
     Ok(Json(out))
 }
 
-pub async fn run_server(db: Arc<Database>, provider_map: Arc<ProviderMap>, port: u16) {
+#[allow(deprecated)]
+pub async fn run_server(
+    db: Arc<Database>,
+    provider_map: Arc<ProviderMap>,
+    credentials: Option<Arc<CredentialStore>>,
+    port: u16,
+    shutdown: Shutdown,
+) {
     info!("Starting server");
     let ctx = Arc::new(Context {
         db: Arc::clone(&db),
         providers: provider_map,
+        credentials,
     });
     let router = Router::new()
         .route("/schedule", get(scheduled_scrapes))
         .route("/v1/schedule", get(v1_scheduled_scrapes))
         .route("/v1/history", get(v1_scrape_history))
         .route("/v1/provider", post(v1_add_provider))
+        .route("/v1/provider/:id/scrape", post(v1_scrape_provider))
         .route("/v1/stats", get(v1_provider_stats))
+        .route("/v1/webhooks/dead_letters", get(v1_dead_letters))
+        .route(
+            "/v1/webhooks/dead_letters/:id/replay",
+            post(v1_replay_dead_letter),
+        )
+        .route(
+            "/v1/webhooks/:id/rotate_secret",
+            post(v1_rotate_webhook_secret),
+        )
         .layer(AddExtensionLayer::new(ctx));
     let addr = SocketAddr::from(([0, 0, 0, 0], port));
-    axum::Server::bind(&addr)
+    // open requests like streamed scrapes are left to finish until the deadline
+    let server = axum::Server::bind(&addr)
         .serve(router.into_make_service())
-        .await
-        .unwrap();
+        .with_graceful_shutdown(shutdown.triggered());
+    tokio::select! {
+        result = server => result.unwrap(),
+        _ = shutdown.deadline() => warn!("Closing requests that didn't finish in time"),
+    }
 }
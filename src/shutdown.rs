@@ -0,0 +1,128 @@
+//! Lets a process finish what it's in the middle of before it exits. Once SIGTERM or ctrl-c
+//! is received nothing new is picked up, and running scrapes and open requests get
+//! `SHUTDOWN_TIMEOUT_SECONDS` to wrap up before they're cut off.
+use std::env;
+use std::time::Duration;
+
+use futures::future;
+use log::{error, info, warn};
+use tokio::sync::watch;
+use tokio::time::Instant;
+
+const DEFAULT_SHUTDOWN_TIMEOUT_SECONDS: u64 = 30;
+
+/// How long running work is given to finish after a shutdown is asked for
+pub fn shutdown_timeout() -> Duration {
+    let seconds = match env::var("SHUTDOWN_TIMEOUT_SECONDS") {
+        Ok(value) => value.trim().parse::<u64>().unwrap_or_else(|_| {
+            warn!("Ignoring invalid SHUTDOWN_TIMEOUT_SECONDS {}", value);
+            DEFAULT_SHUTDOWN_TIMEOUT_SECONDS
+        }),
+        Err(_) => DEFAULT_SHUTDOWN_TIMEOUT_SECONDS,
+    };
+    Duration::from_secs(seconds)
+}
+
+#[cfg(unix)]
+async fn terminated() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = sigterm.recv() => Ok(()),
+        result = tokio::signal::ctrl_c() => result,
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminated() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    /// when the shutdown was asked for
+    signal: watch::Receiver<Option<Instant>>,
+    timeout: Duration,
+}
+
+impl Shutdown {
+    fn channel(timeout: Duration) -> (watch::Sender<Option<Instant>>, Self) {
+        let (tx, signal) = watch::channel(None);
+        (tx, Self { signal, timeout })
+    }
+
+    /// Starts listening for SIGTERM and ctrl-c
+    pub fn listen() -> Self {
+        let (tx, shutdown) = Self::channel(shutdown_timeout());
+        tokio::spawn(async move {
+            if let Err(err) = terminated().await {
+                error!("Could not listen for shutdown signals");
+                error!("{:?}", err);
+                // dropping the sender would look like a shutdown to everyone waiting on it
+                future::pending::<()>().await;
+            }
+            info!("Received a shutdown signal");
+            drop(tx.send(Some(Instant::now())));
+        });
+        shutdown
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        self.signal.borrow().is_some()
+    }
+
+    /// Resolves once a shutdown was asked for, with the time it was asked for at
+    async fn triggered_at(&self) -> Option<Instant> {
+        let mut signal = self.signal.clone();
+        loop {
+            if let Some(at) = *signal.borrow() {
+                return Some(at);
+            }
+            if signal.changed().await.is_err() {
+                return None;
+            }
+        }
+    }
+
+    /// Resolves once a shutdown was asked for
+    pub async fn triggered(&self) {
+        self.triggered_at().await;
+    }
+
+    /// Resolves once the running work has run out of time to finish. The time is counted from
+    /// the shutdown signal, so everything waiting on it is cut off at the same time
+    pub async fn deadline(&self) {
+        match self.triggered_at().await {
+            Some(at) => tokio::time::sleep_until(at + self.timeout).await,
+            None => tokio::time::sleep(self.timeout).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::time::Instant;
+
+    use super::Shutdown;
+
+    #[tokio::test]
+    async fn shutdowns_reach_every_clone() {
+        let (tx, shutdown) = Shutdown::channel(Duration::from_millis(10));
+        let clone = shutdown.clone();
+        assert!(!clone.is_triggered());
+        let waiting = tokio::spawn(async move { clone.deadline().await });
+        tx.send(Some(Instant::now())).unwrap();
+        shutdown.triggered().await;
+        assert!(shutdown.is_triggered());
+        tokio::time::timeout(Duration::from_secs(1), waiting)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}